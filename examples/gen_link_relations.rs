@@ -0,0 +1,99 @@
+/*
+Copyright 2024 Glyn Normington
+
+This file is part of webfinger-rs.
+
+webfinger-rs is free software: you can redistribute it and/or modify it under the terms
+of the GNU General Public License as published by the Free Software Foundation, either
+version 3 of the License, or (at your option) any later version.
+
+webfinger-rs is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with webfinger-rs.
+If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Regenerate src/link_relations.rs from a local copy of the IANA Link Relations
+// registry (https://www.iana.org/assignments/link-relations/link-relations.xml):
+//
+//     cargo run --example gen_link_relations -- link-relations.xml > src/link_relations.rs
+
+use std::env;
+use std::fs;
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+
+fn main() {
+    let path = env::args()
+        .nth(1)
+        .expect("Usage: gen_link_relations <path of link-relations.xml>");
+    let xml = fs::read_to_string(&path).expect("Failed to read registry");
+    print!("{}", generate(&xml));
+}
+
+// Write the source of src/link_relations.rs for the registry. The source is
+// checked against the registry by a test in src/rel.rs.
+pub(crate) fn generate(xml: &str) -> String {
+    let mut relations = parse(xml);
+    relations.sort_by_key(|(value, _)| value.to_ascii_lowercase());
+    relations.dedup_by_key(|(value, _)| value.to_ascii_lowercase());
+
+    let mut source = String::new();
+    source.push_str("// Generated by `cargo run --example gen_link_relations` from the IANA Link\n");
+    source.push_str("// Relations registry. Do not edit.\n");
+    source.push('\n');
+    source.push_str("// Registered link relation types and their descriptions, sorted by relation\n");
+    source.push_str("// type in lower case.\n");
+    source.push_str("pub(crate) const LINK_RELATIONS: &[(&str, &str)] = &[\n");
+    for (value, description) in relations {
+        source.push_str(&format!("    ({value:?}, {description:?}),\n"));
+    }
+    source.push_str("];\n");
+    source
+}
+
+// Extract the value and description of each record of the registry.
+fn parse(xml: &str) -> Vec<(String, String)> {
+    let mut reader = Reader::from_str(xml);
+    let mut relations = Vec::new();
+    let mut path: Vec<String> = Vec::new();
+    let mut value = String::new();
+    let mut description = String::new();
+
+    loop {
+        match reader.read_event().expect("Failed to parse registry") {
+            Event::Start(e) => {
+                let name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+                if name == "record" {
+                    value.clear();
+                    description.clear();
+                }
+                path.push(name);
+            }
+            Event::End(_) => {
+                let name = path.pop();
+                if name.as_deref() == Some("record") && !value.is_empty() {
+                    relations.push((value.trim().to_string(), normalize_space(&description)));
+                }
+            }
+            Event::Text(t) if path.iter().any(|p| p == "record") => {
+                let text = t.unescape().expect("Failed to unescape text");
+                match path.last().map(String::as_str) {
+                    Some("value") => value.push_str(&text),
+                    Some(_) if path.iter().any(|p| p == "description") => description.push_str(&text),
+                    _ => {}
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    relations
+}
+
+fn normalize_space(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
@@ -0,0 +1,187 @@
+/*
+Copyright 2024 Glyn Normington
+
+This file is part of webfinger-rs.
+
+webfinger-rs is free software: you can redistribute it and/or modify it under the terms
+of the GNU General Public License as published by the Free Software Foundation, either
+version 3 of the License, or (at your option) any later version.
+
+webfinger-rs is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with webfinger-rs.
+If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Authentication of WebFinger clients, so that JRDs and links which are not
+// public can be served to the principals allowed to see them. A client presents
+// either a bearer token or, where TLS is terminated by a reverse proxy, a client
+// certificate which the proxy identifies in a request header.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use hyper::header::{HeaderMap, HeaderName, AUTHORIZATION};
+
+use crate::jrdmap::Format;
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Credentials {
+    // Bearer token presented in the Authorization header.
+    #[serde(default)]
+    token: Option<String>,
+
+    // Value of the client certificate header set by the reverse proxy, such as
+    // the certificate's fingerprint or subject.
+    #[serde(default)]
+    client_certificate: Option<String>,
+}
+
+/* Principals maps the name of each principal to its credentials. */
+#[derive(Clone, Default)]
+pub struct Principals(BTreeMap<String, Credentials>);
+
+// Credentials are omitted so that they do not appear in logs.
+impl fmt::Debug for Principals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+impl Principals {
+    // Parse a principals file, which maps each principal's name to its "token",
+    // "client_certificate", or both.
+    pub fn from_str(s: &str, format: Format) -> Result<Principals, String> {
+        let principals: BTreeMap<String, Credentials> = match format {
+            Format::Json => serde_json::from_str(s).map_err(|e| e.to_string())?,
+            Format::Yaml => serde_yaml::from_str(s).map_err(|e| e.to_string())?,
+            Format::Toml => toml::from_str(s).map_err(|e| e.to_string())?,
+        };
+        for (name, credentials) in &principals {
+            if credentials.token.is_none() && credentials.client_certificate.is_none() {
+                return Err(format!("principal {name:?} has no token or client certificate"));
+            }
+        }
+        Ok(Principals(principals))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Determine the principal which made a request: None for an anonymous request,
+    // or an error if the request carries credentials which are not recognised.
+    pub(crate) fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        client_certificate_header: Option<&HeaderName>,
+    ) -> Result<Option<String>, String> {
+        if let Some(authorization) = headers.get(AUTHORIZATION) {
+            let token = authorization
+                .to_str()
+                .ok()
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .ok_or("the Authorization header does not contain a bearer token")?;
+            return self
+                .find(|credentials| credentials.token.as_deref(), token)
+                .map(Some)
+                .ok_or_else(|| "the bearer token is not recognised".to_string());
+        }
+        if let Some(certificate) = client_certificate_header.and_then(|name| headers.get(name)) {
+            let certificate = certificate
+                .to_str()
+                .map_err(|_| "the client certificate header is not valid")?;
+            return self
+                .find(|credentials| credentials.client_certificate.as_deref(), certificate.trim())
+                .map(Some)
+                .ok_or_else(|| "the client certificate is not recognised".to_string());
+        }
+        Ok(None)
+    }
+
+    // Find the principal with a given credential. Every principal is checked, in
+    // constant time, so that the time taken does not reveal a partial match.
+    fn find<F>(&self, credential: F, presented: &str) -> Option<String>
+    where
+        F: Fn(&Credentials) -> Option<&str>,
+    {
+        let mut found = None;
+        for (name, credentials) in &self.0 {
+            if credential(credentials)
+                .is_some_and(|expected| constant_time_eq(expected.as_bytes(), presented.as_bytes()))
+            {
+                found = Some(name.clone());
+            }
+        }
+        found
+    }
+}
+
+// Compare two byte strings in time independent of where they first differ.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    const PRINCIPALS: &str = r#"
+        {
+            "partner": {"token": "s3cret"},
+            "intranet": {"client_certificate": "SHA256:ab:cd"}
+        }"#;
+
+    fn principals() -> Principals {
+        Principals::from_str(PRINCIPALS, Format::Json).unwrap()
+    }
+
+    fn headers(name: HeaderName, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn test_authenticate_anonymous() {
+        assert_eq!(principals().authenticate(&HeaderMap::new(), None), Ok(None));
+    }
+
+    #[test]
+    fn test_authenticate_bearer_token() {
+        let principals = principals();
+        assert_eq!(
+            principals.authenticate(&headers(AUTHORIZATION, "Bearer s3cret"), None),
+            Ok(Some("partner".to_string()))
+        );
+        assert!(principals.authenticate(&headers(AUTHORIZATION, "Bearer guess"), None).is_err());
+        assert!(principals.authenticate(&headers(AUTHORIZATION, "Basic czNjcmV0"), None).is_err());
+    }
+
+    #[test]
+    fn test_authenticate_client_certificate() {
+        let principals = principals();
+        let header = HeaderName::from_static("x-client-cert");
+        assert_eq!(
+            principals.authenticate(&headers(header.clone(), "SHA256:ab:cd"), Some(&header)),
+            Ok(Some("intranet".to_string()))
+        );
+        assert!(principals.authenticate(&headers(header.clone(), "SHA256:ef"), Some(&header)).is_err());
+        // The header is ignored unless it has been configured.
+        assert_eq!(principals.authenticate(&headers(header, "SHA256:ab:cd"), None), Ok(None));
+    }
+
+    #[test]
+    fn test_from_str_rejects_principal_without_credentials() {
+        assert!(Principals::from_str("partner = {}", Format::Toml).is_err());
+    }
+
+    #[test]
+    fn test_debug_omits_credentials() {
+        assert_eq!(format!("{:?}", principals()), r#"{"intranet", "partner"}"#);
+    }
+}
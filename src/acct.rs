@@ -0,0 +1,272 @@
+/*
+Copyright 2024 Glyn Normington
+
+This file is part of webfinger-rs.
+
+webfinger-rs is free software: you can redistribute it and/or modify it under the terms
+of the GNU General Public License as published by the Free Software Foundation, either
+version 3 of the License, or (at your option) any later version.
+
+webfinger-rs is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with webfinger-rs.
+If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// The "acct" URI scheme of RFC 7565:
+//
+//     acctURI  = "acct" ":" userpart "@" host
+//     userpart = unreserved / sub-delims
+//                0*( unreserved / pct-encoded / sub-delims )
+//
+// where host is as defined in RFC 3986.
+
+use std::fmt;
+use std::str::FromStr;
+
+/* An AcctUri is a parsed "acct" URI. The userpart is held percent-decoded and
+the host is normalized to lower case. */
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AcctUri {
+    userpart: String,
+    host: String,
+}
+
+impl AcctUri {
+    // Parse and validate an "acct" URI.
+    pub fn parse(s: &str) -> Result<AcctUri, String> {
+        let (scheme, rest) = s.split_once(':').ok_or("missing scheme")?;
+        if !is_acct(scheme) {
+            return Err(format!("scheme {scheme:?} is not \"acct\""));
+        }
+
+        // The userpart cannot contain an unencoded "@", so the host follows the first one.
+        let (userpart, host) = rest.split_once('@').ok_or("missing \"@\" and host")?;
+        Ok(AcctUri {
+            userpart: parse_userpart(userpart)?,
+            host: parse_host(host)?,
+        })
+    }
+
+    // The userpart, percent-decoded.
+    pub fn userpart(&self) -> &str {
+        &self.userpart
+    }
+
+    // The host, in lower case.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+}
+
+// Determine whether a URI has the "acct" scheme (which is case-insensitive), and
+// so should be parsed as an AcctUri.
+pub fn has_acct_scheme(uri: &str) -> bool {
+    uri.split_once(':').is_some_and(|(scheme, _)| is_acct(scheme))
+}
+
+// Interpret a resource without a scheme, such as alice@example.com or the
+// fediverse handle @alice@example.com, as an acct URI.
+pub fn infer_acct(resource: &str) -> Option<AcctUri> {
+    let handle = resource.strip_prefix('@').unwrap_or(resource);
+    if handle.contains(':') {
+        return None;
+    }
+    AcctUri::parse(&format!("acct:{handle}")).ok()
+}
+
+fn is_acct(scheme: &str) -> bool {
+    scheme.eq_ignore_ascii_case("acct")
+}
+
+impl FromStr for AcctUri {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AcctUri, String> {
+        AcctUri::parse(s)
+    }
+}
+
+// Format the URI in normal form, percent-encoding only those characters of the
+// userpart which must be encoded.
+impl fmt::Display for AcctUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "acct:")?;
+        for c in self.userpart.chars() {
+            if c.is_ascii() && is_userpart_char(c as u8) {
+                write!(f, "{c}")?;
+            } else {
+                for b in c.encode_utf8(&mut [0; 4]).bytes() {
+                    write!(f, "%{b:02X}")?;
+                }
+            }
+        }
+        write!(f, "@{}", self.host)
+    }
+}
+
+fn is_unreserved(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~')
+}
+
+fn is_sub_delim(b: u8) -> bool {
+    matches!(
+        b,
+        b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+' | b',' | b';' | b'='
+    )
+}
+
+fn is_userpart_char(b: u8) -> bool {
+    is_unreserved(b) || is_sub_delim(b)
+}
+
+// Validate and percent-decode a userpart.
+fn parse_userpart(userpart: &str) -> Result<String, String> {
+    let bytes = userpart.as_bytes();
+    match bytes.first() {
+        None => return Err("empty userpart".to_string()),
+        Some(&b) if !is_userpart_char(b) => {
+            return Err(format!(
+                "userpart {userpart:?} must begin with an unreserved character or sub-delimiter"
+            ))
+        }
+        _ => {}
+    }
+
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = userpart
+                    .get(i + 1..i + 3)
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                    .ok_or_else(|| format!("invalid percent-encoding in userpart {userpart:?}"))?;
+                decoded.push(hex);
+                i += 3;
+            }
+            b if is_userpart_char(b) => {
+                decoded.push(b);
+                i += 1;
+            }
+            b => {
+                return Err(format!(
+                    "userpart {userpart:?} contains {:?}, which must be percent-encoded",
+                    b as char
+                ))
+            }
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| format!("userpart {userpart:?} is not valid UTF-8"))
+}
+
+// Validate a host and normalize it to lower case. The host is either an IP literal
+// in square brackets or a registered name (which also covers IPv4 addresses).
+fn parse_host(host: &str) -> Result<String, String> {
+    if host.is_empty() {
+        return Err("empty host".to_string());
+    }
+    let valid = if let Some(literal) = host.strip_prefix('[') {
+        literal.strip_suffix(']').is_some_and(|literal| {
+            !literal.is_empty()
+                && literal
+                    .bytes()
+                    .all(|b| b.is_ascii_hexdigit() || b == b':' || b == b'.' || is_userpart_char(b))
+        })
+    } else {
+        let bytes = host.as_bytes();
+        let mut i = 0;
+        let mut valid = true;
+        while i < bytes.len() && valid {
+            if bytes[i] == b'%' {
+                valid = host
+                    .get(i + 1..i + 3)
+                    .is_some_and(|h| h.bytes().all(|b| b.is_ascii_hexdigit()));
+                i += 3;
+            } else {
+                valid = is_userpart_char(bytes[i]);
+                i += 1;
+            }
+        }
+        valid
+    };
+    if !valid {
+        return Err(format!("host {host:?} is not valid"));
+    }
+    Ok(host.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let acct = AcctUri::parse("acct:alice@example.com").unwrap();
+        assert_eq!(acct.userpart(), "alice");
+        assert_eq!(acct.host(), "example.com");
+        assert_eq!(acct.to_string(), "acct:alice@example.com");
+    }
+
+    #[test]
+    fn test_parse_normalizes() {
+        let acct: AcctUri = "ACCT:bob%40home%2esite@Example.COM".parse().unwrap();
+        assert_eq!(acct.userpart(), "bob@home.site");
+        assert_eq!(acct.host(), "example.com");
+        assert_eq!(acct.to_string(), "acct:bob%40home.site@example.com");
+    }
+
+    #[test]
+    fn test_parse_ip_literal() {
+        let acct = AcctUri::parse("acct:carol@[2001:DB8::1]").unwrap();
+        assert_eq!(acct.host(), "[2001:db8::1]");
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed() {
+        for s in [
+            "acct:",
+            "acct:alice",
+            "acct:@example.com",
+            "acct:alice@",
+            "acct:a@b@c",
+            "acct:%61lice@example.com",
+            "acct:al ice@example.com",
+            "acct:alice%4@example.com",
+            "acct:alice%FF@example.com",
+            "acct:alice@example.com:8080",
+            "acct:alice@example.com/path",
+            "acct:alice@[2001:db8::1",
+            "mailto:alice@example.com",
+            "alice@example.com",
+        ] {
+            assert!(AcctUri::parse(s).is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn test_infer_acct() {
+        assert_eq!(
+            infer_acct("alice@example.com").unwrap().to_string(),
+            "acct:alice@example.com"
+        );
+        assert_eq!(
+            infer_acct("@alice@Example.com").unwrap().to_string(),
+            "acct:alice@example.com"
+        );
+        assert!(infer_acct("alice").is_none());
+        assert!(infer_acct("@@example.com").is_none());
+        assert!(infer_acct("acct:alice@example.com").is_none());
+        assert!(infer_acct("alice@example.com:8080").is_none());
+    }
+
+    #[test]
+    fn test_has_acct_scheme() {
+        assert!(has_acct_scheme("acct:alice@example.com"));
+        assert!(has_acct_scheme("Acct:"));
+        assert!(!has_acct_scheme("https://example.com/acct:alice"));
+        assert!(!has_acct_scheme("alice@example.com"));
+    }
+}
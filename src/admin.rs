@@ -0,0 +1,559 @@
+/*
+Copyright 2024 Glyn Normington
+
+This file is part of webfinger-rs.
+
+webfinger-rs is free software: you can redistribute it and/or modify it under the terms
+of the GNU General Public License as published by the Free Software Foundation, either
+version 3 of the License, or (at your option) any later version.
+
+webfinger-rs is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with webfinger-rs.
+If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// The admin API allows the entries of the JRD map to be managed while the server
+// is running. It is served on a separate listener and every request must carry
+// the configured bearer token. Each change is validated with the same rules as
+// when the map is loaded and is persisted to the map file before it is served.
+// The map file is written as JSON, so only JSON maps can be managed: rewriting a
+// YAML or TOML map would discard its comments.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::{Path, Request, State},
+    http::StatusCode,
+    middleware::{self, Next},
+    response::Response,
+    routing::get,
+    Json, Router,
+};
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use serde_json::Value;
+use tokio::sync::Mutex;
+
+use crate::access::constant_time_eq;
+use crate::jrdmap::{self, Jrd, JrdMap};
+use crate::store::{JrdStore, JsonMapStore};
+
+#[derive(Clone)]
+pub struct AdminState {
+    store: Arc<JsonMapStore>,
+
+    // File path the map is persisted to after each change.
+    jrd_map_path: PathBuf,
+
+    token: Arc<String>,
+
+    // Serialises changes so that concurrent updates cannot overwrite each other.
+    update_lock: Arc<Mutex<()>>,
+}
+
+pub fn create_admin_router(
+    store: Arc<JsonMapStore>,
+    jrd_map_path: PathBuf,
+    token: String,
+) -> Router {
+    let state = AdminState {
+        store,
+        jrd_map_path,
+        token: Arc::new(token),
+        update_lock: Arc::new(Mutex::new(())),
+    };
+
+    Router::new()
+        .route("/entries", get(list))
+        .route(
+            "/entries/:resource",
+            get(get_entry)
+                .post(create)
+                .put(replace)
+                .patch(patch)
+                .delete(delete),
+        )
+        .layer(middleware::from_fn_with_state(state.clone(), authenticate))
+        .with_state(state)
+}
+
+async fn authenticate(State(state): State<AdminState>, request: Request, next: Next) -> Response {
+    let authorized = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| constant_time_eq(token.as_bytes(), state.token.as_bytes()));
+
+    if authorized {
+        next.run(request).await
+    } else {
+        Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(WWW_AUTHENTICATE, "Bearer")
+            .body(Body::empty())
+            .unwrap()
+    }
+}
+
+async fn list(State(state): State<AdminState>) -> Response {
+    let resources = state.store.list().await.unwrap().unwrap_or_default();
+    json_response(StatusCode::OK, serde_json::to_string(&resources).unwrap())
+}
+
+async fn get_entry(State(state): State<AdminState>, Path(resource): Path<String>) -> Response {
+    match state.store.lookup(&resource).await.unwrap() {
+        Some(jrd) => json_response(StatusCode::OK, jrdmap::to_json(&jrd)),
+        None => not_found(),
+    }
+}
+
+async fn create(
+    State(state): State<AdminState>,
+    Path(resource): Path<String>,
+    Json(jrd): Json<Jrd>,
+) -> Response {
+    update(&state, |jm| {
+        if jm.contains_key(&resource) {
+            return text_response(
+                StatusCode::CONFLICT,
+                format!("resource {resource:?} already exists"),
+            );
+        }
+        if let Err(e) = jrdmap::validate_entry(&resource, &jrd) {
+            return invalid(e);
+        }
+        jm.insert(resource.clone(), jrd.clone());
+        json_response(StatusCode::CREATED, jrdmap::to_json(&jrd))
+    })
+    .await
+}
+
+async fn replace(
+    State(state): State<AdminState>,
+    Path(resource): Path<String>,
+    Json(jrd): Json<Jrd>,
+) -> Response {
+    update(&state, |jm| {
+        if !jm.contains_key(&resource) {
+            return not_found();
+        }
+        if let Err(e) = jrdmap::validate_entry(&resource, &jrd) {
+            return invalid(e);
+        }
+        jm.insert(resource.clone(), jrd.clone());
+        json_response(StatusCode::OK, jrdmap::to_json(&jrd))
+    })
+    .await
+}
+
+// Apply a JSON merge patch (RFC 7396) to an entry.
+async fn patch(
+    State(state): State<AdminState>,
+    Path(resource): Path<String>,
+    Json(merge_patch): Json<Value>,
+) -> Response {
+    update(&state, |jm| {
+        let Some(jrd) = jm.get(&resource) else {
+            return not_found();
+        };
+        let mut value = serde_json::to_value(jrd).unwrap();
+        apply_merge_patch(&mut value, &merge_patch);
+        let jrd: Jrd = match serde_json::from_value(value) {
+            Ok(jrd) => jrd,
+            Err(e) => return invalid(e.to_string()),
+        };
+        if let Err(e) = jrdmap::validate_entry(&resource, &jrd) {
+            return invalid(e);
+        }
+        jm.insert(resource.clone(), jrd.clone());
+        json_response(StatusCode::OK, jrdmap::to_json(&jrd))
+    })
+    .await
+}
+
+async fn delete(State(state): State<AdminState>, Path(resource): Path<String>) -> Response {
+    update(&state, |jm| match jm.remove(&resource) {
+        Some(_) => Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap(),
+        None => not_found(),
+    })
+    .await
+}
+
+// Apply a change to a copy of the map, persist the result, and only then make it
+// visible to the WebFinger handler. If the change is rejected (indicated by a
+// response status other than success) or cannot be persisted, the served map is
+// left untouched.
+async fn update<F>(state: &AdminState, change: F) -> Response
+where
+    F: FnOnce(&mut JrdMap) -> Response,
+{
+    let _guard = state.update_lock.lock().await;
+
+    let mut jm = state.store.snapshot();
+    let response = change(&mut jm);
+    if !response.status().is_success() {
+        return response;
+    }
+
+    if let Err(e) = persist(&jm, &state.jrd_map_path) {
+        return text_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to persist JRD map: {e}"),
+        );
+    }
+
+    state.store.replace(jm);
+    response
+}
+
+// Write the map to a temporary file alongside the map file and then rename it over
+// the map file, so that the map file is never left partially written.
+fn persist(jm: &JrdMap, path: &PathBuf) -> io::Result<()> {
+    let mut tmp = path.clone().into_os_string();
+    tmp.push(".tmp");
+    fs::write(&tmp, jrdmap::to_json_pretty(jm))?;
+    fs::rename(&tmp, path)
+}
+
+fn apply_merge_patch(target: &mut Value, merge_patch: &Value) {
+    let Value::Object(patch_members) = merge_patch else {
+        *target = merge_patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(serde_json::Map::new());
+    }
+    let target_members = target.as_object_mut().unwrap();
+    for (name, value) in patch_members {
+        if value.is_null() {
+            target_members.remove(name);
+        } else {
+            apply_merge_patch(
+                target_members.entry(name.clone()).or_insert(Value::Null),
+                value,
+            );
+        }
+    }
+}
+
+fn invalid(message: String) -> Response {
+    text_response(StatusCode::UNPROCESSABLE_ENTITY, message)
+}
+
+fn not_found() -> Response {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn json_response(status: StatusCode, body: String) -> Response {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn text_response(status: StatusCode, body: String) -> Response {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+    use http_body_util::BodyExt;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    const TOKEN: &str = "s3cr3t";
+
+    fn setup() -> (Arc<JsonMapStore>, tempfile::TempDir, PathBuf, Router) {
+        let jm = jrdmap::from_json(
+            &r#"
+            {
+                "acct:alice@example.com":{
+                    "subject": "acct:alice@example.com",
+                    "links": [
+                        {
+                            "rel": "self",
+                            "href": "https://example.com/users/alice"
+                        }
+                    ]
+                }
+            }"#
+            .to_string(),
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("jrdmap.json");
+        fs::write(&path, jrdmap::to_json_pretty(&jm)).unwrap();
+        let store = Arc::new(JsonMapStore::new(jm));
+        let router = create_admin_router(store.clone(), path.clone(), TOKEN.to_string());
+        (store, dir, path, router)
+    }
+
+    fn request(method: &str, uri: &str, body: Option<Value>) -> Request<Body> {
+        let builder = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(AUTHORIZATION, format!("Bearer {TOKEN}"));
+        match body {
+            Some(body) => builder
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+            None => builder.body(Body::empty()).unwrap(),
+        }
+    }
+
+    async fn body_json(response: Response) -> Value {
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn persisted(path: &PathBuf) -> Value {
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn missing_token() {
+        let (_, _dir, _, router) = setup();
+
+        let response = router
+            .oneshot(Request::builder().uri("/entries").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers().get(WWW_AUTHENTICATE).unwrap(), "Bearer");
+    }
+
+    #[tokio::test]
+    async fn wrong_token() {
+        let (_, _dir, _, router) = setup();
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/entries")
+                    .header(AUTHORIZATION, "Bearer wrong")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn list_entries() {
+        let (_, _dir, _, router) = setup();
+
+        let response = router.oneshot(request("GET", "/entries", None)).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_json(response).await, json!(["acct:alice@example.com"]));
+    }
+
+    #[tokio::test]
+    async fn get_existing_entry() {
+        let (_, _dir, _, router) = setup();
+
+        let response = router
+            .oneshot(request("GET", "/entries/acct:alice@example.com", None))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            body_json(response).await,
+            json!({
+                "subject": "acct:alice@example.com",
+                "links": [{"rel": "self", "href": "https://example.com/users/alice"}]
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn get_missing_entry() {
+        let (_, _dir, _, router) = setup();
+
+        let response = router
+            .oneshot(request("GET", "/entries/acct:bob@example.com", None))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn create_entry() {
+        let (store, _dir, path, router) = setup();
+        let bob = json!({"subject": "acct:bob@example.com"});
+
+        let response = router
+            .oneshot(request("POST", "/entries/acct:bob@example.com", Some(bob.clone())))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(store.snapshot().contains_key("acct:bob@example.com"));
+        assert_eq!(persisted(&path)["acct:bob@example.com"], bob);
+    }
+
+    #[tokio::test]
+    async fn create_existing_entry() {
+        let (_, _dir, _, router) = setup();
+
+        let response = router
+            .oneshot(request(
+                "POST",
+                "/entries/acct:alice@example.com",
+                Some(json!({"subject": "acct:alice@example.com"})),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn create_invalid_entry() {
+        let (store, _dir, path, router) = setup();
+
+        let response = router
+            .oneshot(request(
+                "POST",
+                "/entries/acct:bob@example.com",
+                Some(json!({"subject": "bob@example.com"})),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(!store.snapshot().contains_key("acct:bob@example.com"));
+        assert!(persisted(&path).get("acct:bob@example.com").is_none());
+    }
+
+    #[tokio::test]
+    async fn create_entry_not_in_normal_form() {
+        let (store, _dir, path, router) = setup();
+
+        let response = router
+            .oneshot(request(
+                "POST",
+                "/entries/acct:bob@Example.com",
+                Some(json!({"subject": "acct:bob@example.com"})),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(!store.snapshot().contains_key("acct:bob@Example.com"));
+        assert!(persisted(&path).get("acct:bob@Example.com").is_none());
+    }
+
+    #[tokio::test]
+    async fn replace_entry() {
+        let (store, _dir, path, router) = setup();
+        let alice = json!({"subject": "acct:alice@example.com", "aliases": ["https://example.com/alice"]});
+
+        let response = router
+            .oneshot(request("PUT", "/entries/acct:alice@example.com", Some(alice.clone())))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(store.snapshot()["acct:alice@example.com"].links.is_none());
+        assert_eq!(persisted(&path)["acct:alice@example.com"], alice);
+    }
+
+    #[tokio::test]
+    async fn replace_missing_entry() {
+        let (_, _dir, _, router) = setup();
+
+        let response = router
+            .oneshot(request(
+                "PUT",
+                "/entries/acct:bob@example.com",
+                Some(json!({"subject": "acct:bob@example.com"})),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn patch_entry() {
+        let (_, _dir, path, router) = setup();
+
+        let response = router
+            .oneshot(request(
+                "PATCH",
+                "/entries/acct:alice@example.com",
+                Some(json!({"aliases": ["https://example.com/alice"], "links": null})),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let expected = json!({"subject": "acct:alice@example.com", "aliases": ["https://example.com/alice"]});
+        assert_eq!(body_json(response).await, expected);
+        assert_eq!(persisted(&path)["acct:alice@example.com"], expected);
+    }
+
+    #[tokio::test]
+    async fn patch_entry_invalid_result() {
+        let (_, _dir, _, router) = setup();
+
+        let response = router
+            .oneshot(request(
+                "PATCH",
+                "/entries/acct:alice@example.com",
+                Some(json!({"subject": null})),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn delete_entry() {
+        let (store, _dir, path, router) = setup();
+
+        let response = router
+            .oneshot(request("DELETE", "/entries/acct:alice@example.com", None))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(store.snapshot().is_empty());
+        assert_eq!(persisted(&path), json!({}));
+    }
+
+    #[test]
+    fn test_merge_patch() {
+        let mut target = json!({"a": "b", "c": {"d": "e", "f": "g"}});
+        apply_merge_patch(&mut target, &json!({"a": "z", "c": {"f": null}}));
+        assert_eq!(target, json!({"a": "z", "c": {"d": "e"}}));
+    }
+}
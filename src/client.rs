@@ -0,0 +1,1163 @@
+/*
+Copyright 2024 Glyn Normington
+
+This file is part of webfinger-rs.
+
+webfinger-rs is free software: you can redistribute it and/or modify it under the terms
+of the GNU General Public License as published by the Free Software Foundation, either
+version 3 of the License, or (at your option) any later version.
+
+webfinger-rs is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with webfinger-rs.
+If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// A WebFinger client, used by the `query` subcommand to look up resources on
+// other servers. Responses are cached according to their Cache-Control and ETag
+// headers, and requests are sent via a pluggable transport.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::header::{
+    HeaderMap, HeaderValue, ACCEPT, CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH, LOCATION,
+};
+use reqwest::{redirect, StatusCode, Url};
+
+use crate::acct::{self, AcctUri};
+use crate::jrdmap::{self, Jrd};
+use crate::httpsig::{self, SignatureScheme};
+use crate::jws::{self, JwkSet};
+
+#[derive(Debug)]
+pub struct ClientError(pub String);
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+pub struct QueryOptions {
+    // Link relation types to request. All links are requested if this is empty.
+    pub rels: Vec<String>,
+
+    // Host (and optional port) to query instead of the one derived from the resource.
+    pub host: Option<String>,
+
+    // Use HTTP rather than HTTPS. This is insecure and intended only for testing.
+    pub insecure_http: bool,
+
+    // Maximum number of redirects to follow.
+    pub max_redirects: usize,
+}
+
+/* The result of a WebFinger query. */
+#[derive(Clone, Debug)]
+pub struct QueryResponse {
+    pub jrd: Jrd,
+
+    // Problems with the response which did not prevent the JRD from being used,
+    // such as a generic JSON content type.
+    pub warnings: Vec<String>,
+}
+
+/* Options controlling verification of a signed JRD. */
+#[derive(Default)]
+pub struct VerifyOptions {
+    // Public keys with which to verify the signature. If None, the JWK set
+    // published by the host queried is used.
+    pub jwk_set: Option<JwkSet>,
+
+    // How the response is expected to be signed.
+    pub scheme: SignatureScheme,
+
+    // Request the JRD wrapped in a JWS rather than signed in a response header.
+    pub wrapped: bool,
+}
+
+// The signature of a response.
+enum Signed {
+    // A JWS of the body with the payload detached.
+    Detached(String),
+
+    // A JWS wrapping the JRD.
+    Wrapped,
+
+    // An HTTP message signature, with the values of the headers it covers.
+    Message {
+        signature_input: String,
+        signature: String,
+        content_digest: String,
+        content_type: String,
+    },
+}
+
+// Characters to percent-encode in query parameter values: everything except the
+// unreserved characters of RFC 3986.
+const QUERY_VALUE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+// Determine the host to query for a resource. For an "acct" URI (RFC 7565), or
+// any other URI without an authority, such as a "mailto" URI, this is the part
+// after the last "@". Otherwise it is the host and port of the URI's authority.
+pub fn resource_host(resource: &str) -> Result<String, ClientError> {
+    let malformed = || ClientError(format!("Cannot determine host of resource {resource:?}"));
+
+    if !jrdmap::valid_uri(resource) {
+        return Err(ClientError(format!("Resource {resource:?} is not a valid URI")));
+    }
+    if acct::has_acct_scheme(resource) {
+        let acct = AcctUri::parse(resource)
+            .map_err(|e| ClientError(format!("Resource {resource:?} is not a valid acct URI: {e}")))?;
+        return Ok(acct.host().to_string());
+    }
+    let (_, rest) = resource.split_once(':').ok_or_else(malformed)?;
+
+    let host = match rest.strip_prefix("//") {
+        Some(hierarchical) => {
+            let authority = hierarchical
+                .split(['/', '?', '#'])
+                .next()
+                .unwrap_or_default();
+            authority.rsplit('@').next().unwrap_or_default()
+        }
+        None => {
+            let (_, host) = rest.rsplit_once('@').ok_or_else(malformed)?;
+            host.split(['/', '?', '#']).next().unwrap_or_default()
+        }
+    };
+
+    if host.is_empty() {
+        return Err(malformed());
+    }
+    Ok(host.to_ascii_lowercase())
+}
+
+// Build the URL of a WebFinger query as described in section 4.1 of RFC 7033.
+pub fn webfinger_url(scheme: &str, host: &str, resource: &str, rels: &[String]) -> String {
+    let mut url = format!(
+        "{scheme}://{host}/.well-known/webfinger?resource={}",
+        utf8_percent_encode(resource, QUERY_VALUE)
+    );
+    for rel in rels {
+        url.push_str("&rel=");
+        url.push_str(&utf8_percent_encode(rel, QUERY_VALUE).to_string());
+    }
+    url
+}
+
+// The response to an HTTP request sent by a transport.
+pub struct TransportResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+/* A Transport sends HTTP GET requests on behalf of a Client. It must not follow
+redirects: the client follows them itself so that it can enforce HTTPS and the
+redirect limit whichever transport is used. */
+#[async_trait]
+pub trait Transport: Send + Sync {
+    async fn get(&self, url: &str, headers: HeaderMap) -> Result<TransportResponse, ClientError>;
+}
+
+/* A ReqwestTransport sends requests over the network using reqwest. */
+pub struct ReqwestTransport {
+    client: reqwest::Client,
+}
+
+impl ReqwestTransport {
+    pub fn new() -> Result<ReqwestTransport, ClientError> {
+        let client = reqwest::Client::builder()
+            .redirect(redirect::Policy::none())
+            .build()
+            .map_err(|e| ClientError(format!("Failed to create HTTP client: {e}")))?;
+        Ok(ReqwestTransport { client })
+    }
+}
+
+#[async_trait]
+impl Transport for ReqwestTransport {
+    async fn get(&self, url: &str, headers: HeaderMap) -> Result<TransportResponse, ClientError> {
+        let response = self
+            .client
+            .get(url)
+            .headers(headers)
+            .send()
+            .await
+            .map_err(|e| ClientError(format!("Request to {url} failed: {e}")))?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response
+            .text()
+            .await
+            .map_err(|e| ClientError(format!("Failed to read response: {e}")))?;
+        Ok(TransportResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+struct CacheEntry {
+    jrd: Jrd,
+
+    // Warnings about the response the entry was created from.
+    warnings: Vec<String>,
+
+    // Entity tag for revalidating the entry once it is stale.
+    etag: Option<HeaderValue>,
+
+    // The entry may be used without revalidation until this time.
+    fresh_until: Instant,
+}
+
+impl CacheEntry {
+    fn response(&self) -> QueryResponse {
+        QueryResponse {
+            jrd: self.jrd.clone(),
+            warnings: self.warnings.clone(),
+        }
+    }
+}
+
+// Maximum number of JRDs a client caches.
+const MAX_CACHED: usize = 1000;
+
+/* A Client performs WebFinger queries and caches the resulting JRDs. */
+pub struct Client {
+    transport: Arc<dyn Transport>,
+    cache: Mutex<HashMap<String, CacheEntry>>,
+    max_cached: usize,
+}
+
+impl Client {
+    // Create a client which sends requests over the network.
+    pub fn new() -> Result<Client, ClientError> {
+        Ok(Client::with_transport(Arc::new(ReqwestTransport::new()?)))
+    }
+
+    pub fn with_transport(transport: Arc<dyn Transport>) -> Client {
+        Client {
+            transport,
+            cache: Mutex::new(HashMap::new()),
+            max_cached: MAX_CACHED,
+        }
+    }
+
+    // Query the WebFinger server for a resource and return the resulting JRD,
+    // together with any warnings about the response.
+    pub async fn query(&self, resource: &str, options: &QueryOptions) -> Result<QueryResponse, ClientError> {
+        let host = match &options.host {
+            Some(host) => host.clone(),
+            None => resource_host(resource)?,
+        };
+        let scheme = if options.insecure_http { "http" } else { "https" };
+        let url = webfinger_url(scheme, &host, resource, &options.rels);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("application/jrd+json"));
+        if let Some(entry) = self.cache.lock().unwrap().get(&url) {
+            if entry.fresh_until > Instant::now() {
+                return Ok(entry.response());
+            }
+            if let Some(etag) = &entry.etag {
+                headers.insert(IF_NONE_MATCH, etag.clone());
+            }
+        }
+
+        let (final_url, response) = self.get_following_redirects(&url, headers, options).await?;
+
+        if response.status == StatusCode::NOT_MODIFIED {
+            let mut cache = self.cache.lock().unwrap();
+            if let Some(entry) = cache.get_mut(&url) {
+                let cached = entry.response();
+                match freshness(&response.headers) {
+                    Some(fresh_for) => {
+                        entry.fresh_until = Instant::now() + fresh_for;
+                        if let Some(etag) = response.headers.get(ETAG) {
+                            entry.etag = Some(etag.clone());
+                        }
+                    }
+                    None => {
+                        cache.remove(&url);
+                    }
+                }
+                return Ok(cached);
+            }
+        }
+        if !response.status.is_success() {
+            return Err(ClientError(format!("{final_url} returned {}", response.status)));
+        }
+
+        let warnings = check_content_type(&response.headers)?.into_iter().collect::<Vec<_>>();
+        let jrd = parse_jrd(resource, &response.body)?;
+
+        let etag = response.headers.get(ETAG).cloned();
+        let mut cache = self.cache.lock().unwrap();
+        match freshness(&response.headers) {
+            // A response which is never fresh is only worth caching if it can be revalidated.
+            Some(fresh_for) if !fresh_for.is_zero() || etag.is_some() => {
+                if !cache.contains_key(&url) && cache.len() >= self.max_cached {
+                    evict(&mut cache, self.max_cached - 1);
+                }
+                cache.insert(
+                    url,
+                    CacheEntry {
+                        jrd: jrd.clone(),
+                        warnings: warnings.clone(),
+                        etag,
+                        fresh_until: Instant::now() + fresh_for,
+                    },
+                );
+            }
+            _ => {
+                cache.remove(&url);
+            }
+        }
+        Ok(QueryResponse { jrd, warnings })
+    }
+
+    // Query the WebFinger server for a resource, verify the signature of the
+    // resulting JRD, and return the JRD, with any warnings about the response,
+    // and the ID of the key which signed it. Signed responses are not cached.
+    pub async fn verify(
+        &self,
+        resource: &str,
+        options: &QueryOptions,
+        verify_options: &VerifyOptions,
+    ) -> Result<(QueryResponse, String), ClientError> {
+        let host = match &options.host {
+            Some(host) => host.clone(),
+            None => resource_host(resource)?,
+        };
+        let scheme = if options.insecure_http { "http" } else { "https" };
+        let url = webfinger_url(scheme, &host, resource, &options.rels);
+
+        if verify_options.wrapped && verify_options.scheme != SignatureScheme::Jws {
+            return Err(ClientError("Only JWS signatures can wrap a JRD".to_string()));
+        }
+        let mut headers = HeaderMap::new();
+        let media_type = if verify_options.wrapped {
+            jws::JOSE_JSON
+        } else {
+            "application/jrd+json"
+        };
+        headers.insert(ACCEPT, HeaderValue::from_static(media_type));
+        let (final_url, response) = self.get_following_redirects(&url, headers, options).await?;
+        if !response.status.is_success() {
+            return Err(ClientError(format!("{final_url} returned {}", response.status)));
+        }
+
+        let header = |name: &str| {
+            response
+                .headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+                .ok_or_else(|| ClientError(format!("Response from {final_url} is not signed")))
+        };
+        let mut warnings = Vec::new();
+        let signed = match verify_options.scheme {
+            SignatureScheme::Jws if verify_options.wrapped => Signed::Wrapped,
+            SignatureScheme::Jws => {
+                warnings.extend(check_content_type(&response.headers)?);
+                Signed::Detached(header(jws::SIGNATURE_HEADER)?)
+            }
+            SignatureScheme::HttpMessage => {
+                warnings.extend(check_content_type(&response.headers)?);
+                Signed::Message {
+                    signature_input: header(httpsig::SIGNATURE_INPUT)?,
+                    signature: header(httpsig::SIGNATURE)?,
+                    content_digest: header(httpsig::CONTENT_DIGEST)?,
+                    content_type: header(CONTENT_TYPE.as_str())?,
+                }
+            }
+        };
+
+        // The keys are fetched from the host queried, rather than from any host it
+        // redirected to.
+        let jwk_set = match &verify_options.jwk_set {
+            Some(jwk_set) => jwk_set.clone(),
+            None => self.fetch_jwk_set(&format!("{scheme}://{host}{}", jws::JWKS_PATH), options).await?,
+        };
+        let failed = |e| ClientError(format!("Signature verification failed: {e}"));
+        let (kid, body) = match signed {
+            Signed::Detached(signature) => {
+                let kid = jws::verify_detached(&signature, response.body.as_bytes(), &jwk_set).map_err(failed)?;
+                (kid, response.body)
+            }
+            Signed::Wrapped => {
+                let (kid, payload) = jws::verify_wrapped(&response.body, &jwk_set).map_err(failed)?;
+                let body = String::from_utf8(payload)
+                    .map_err(|e| ClientError(format!("Response is not a valid JRD: {e}")))?;
+                (kid, body)
+            }
+            Signed::Message {
+                signature_input,
+                signature,
+                content_digest,
+                content_type,
+            } => {
+                httpsig::verify_content_digest(&content_digest, response.body.as_bytes()).map_err(failed)?;
+                // The signature covers the target of the request which was answered.
+                let final_url = Url::parse(&final_url)
+                    .map_err(|e| ClientError(format!("Invalid URL {final_url:?}: {e}")))?;
+                let request_target = match final_url.query() {
+                    Some(query) => format!("{}?{query}", final_url.path()),
+                    None => final_url.path().to_string(),
+                };
+                let components = httpsig::Components {
+                    status: response.status.as_u16(),
+                    content_type: &content_type,
+                    content_digest: &content_digest,
+                    request_target: &request_target,
+                };
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                let kid =
+                    httpsig::verify(&components, &signature_input, &signature, &jwk_set, now).map_err(failed)?;
+                (kid, response.body)
+            }
+        };
+        let jrd = parse_jrd(resource, &body)?;
+        Ok((QueryResponse { jrd, warnings }, kid))
+    }
+
+    async fn fetch_jwk_set(&self, url: &str, options: &QueryOptions) -> Result<JwkSet, ClientError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static(jws::JWK_SET_JSON));
+        let (final_url, response) = self.get_following_redirects(url, headers, options).await?;
+        if !response.status.is_success() {
+            return Err(ClientError(format!("{final_url} returned {}", response.status)));
+        }
+        response
+            .body
+            .parse()
+            .map_err(|e| ClientError(format!("Response from {final_url} is not a valid JWK set: {e}")))
+    }
+
+    // Send a request, following up to the maximum number of redirects, and return
+    // the final URL and response. Redirects to non-HTTPS URLs are refused unless
+    // insecure HTTP is enabled.
+    async fn get_following_redirects(
+        &self,
+        url: &str,
+        headers: HeaderMap,
+        options: &QueryOptions,
+    ) -> Result<(String, TransportResponse), ClientError> {
+        let mut url = url.to_string();
+        let mut redirects = 0;
+        loop {
+            let response = self.transport.get(&url, headers.clone()).await?;
+            if !response.status.is_redirection() || response.status == StatusCode::NOT_MODIFIED {
+                return Ok((url, response));
+            }
+
+            let location = response
+                .headers
+                .get(LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .ok_or_else(|| ClientError(format!("{url} returned {} without a location", response.status)))?;
+            let next = Url::parse(&url)
+                .and_then(|base| base.join(location))
+                .map_err(|e| ClientError(format!("Invalid redirect location {location:?}: {e}")))?;
+
+            redirects += 1;
+            if redirects > options.max_redirects {
+                return Err(ClientError(format!(
+                    "Request to {url} failed: more than {} redirects",
+                    options.max_redirects
+                )));
+            }
+            if next.scheme() != "https" && !options.insecure_http {
+                return Err(ClientError(format!(
+                    "Request to {url} failed: redirect to non-HTTPS URL {next}"
+                )));
+            }
+            url = next.to_string();
+        }
+    }
+}
+
+// Reduce the number of cache entries to at most a given number. Stale entries
+// which cannot be revalidated are removed first, followed by the entries which
+// become stale soonest.
+fn evict(cache: &mut HashMap<String, CacheEntry>, max_entries: usize) {
+    let now = Instant::now();
+    cache.retain(|_, entry| entry.fresh_until > now || entry.etag.is_some());
+    if cache.len() <= max_entries {
+        return;
+    }
+    let mut by_freshness: Vec<(Instant, String)> = cache
+        .iter()
+        .map(|(url, entry)| (entry.fresh_until, url.clone()))
+        .collect();
+    by_freshness.sort();
+    for (_, url) in by_freshness.into_iter().take(cache.len() - max_entries) {
+        cache.remove(&url);
+    }
+}
+
+// Query the WebFinger server for a resource, without caching, and return the
+// resulting JRD, together with any warnings about the response.
+pub async fn query(resource: &str, options: &QueryOptions) -> Result<QueryResponse, ClientError> {
+    Client::new()?.query(resource, options).await
+}
+
+// Parse and validate the JRD of a resource.
+fn parse_jrd(resource: &str, body: &str) -> Result<Jrd, ClientError> {
+    let jrd = jrdmap::jrd_from_str(body, jrdmap::Format::Json)
+        .map_err(|e| ClientError(format!("Response is not a valid JRD: {e}")))?;
+    jrdmap::validate_jrd(resource, &jrd).map_err(|e| ClientError(format!("Response is not a valid JRD: {e}")))?;
+    Ok(jrd)
+}
+
+// Check that the response has the JRD media type, returning a warning if it has
+// the generic JSON media type instead.
+fn check_content_type(headers: &HeaderMap) -> Result<Option<String>, ClientError> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    match media_type.as_str() {
+        "application/jrd+json" => Ok(None),
+        // Some servers use the generic JSON media type.
+        "application/json" => Ok(Some(format!(
+            "Response has content type {content_type:?} rather than \"application/jrd+json\""
+        ))),
+        _ => Err(ClientError(format!(
+            "Response has content type {content_type:?} rather than \"application/jrd+json\""
+        ))),
+    }
+}
+
+// Determine how long a response may be used without revalidation from its
+// Cache-Control header, or None if it must not be cached. A response without
+// max-age is treated as needing revalidation before each use.
+fn freshness(headers: &HeaderMap) -> Option<Duration> {
+    let mut max_age = None;
+    let mut no_cache = false;
+    for value in headers.get_all(CACHE_CONTROL) {
+        for directive in value.to_str().ok()?.split(',') {
+            let directive = directive.trim().to_ascii_lowercase();
+            match directive.split_once('=') {
+                None if directive == "no-store" => return None,
+                None if directive == "no-cache" => no_cache = true,
+                Some(("max-age", seconds)) => max_age = seconds.trim_matches('"').parse().ok(),
+                _ => {}
+            }
+        }
+    }
+    if no_cache {
+        return Some(Duration::ZERO);
+    }
+    Some(Duration::from_secs(max_age.unwrap_or(0)))
+}
+
+// Format the links of a JRD as a table with columns for relation type, media
+// type, and target.
+pub fn links_table(jrd: &Jrd) -> String {
+    let header = ["REL", "TYPE", "HREF"];
+    let rows: Vec<[&str; 3]> = jrd
+        .links
+        .iter()
+        .flatten()
+        .map(|link| {
+            [
+                link.rel.as_str(),
+                link.type_.as_deref().unwrap_or("-"),
+                link.href.as_deref().unwrap_or("-"),
+            ]
+        })
+        .collect();
+
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut table = String::new();
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = format!(
+            "{:w0$}  {:w1$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            w0 = widths[0],
+            w1 = widths[1]
+        );
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Body,
+        extract::RawQuery,
+        http::StatusCode,
+        response::{Redirect, Response},
+        routing::get,
+        Router,
+    };
+    use http_body_util::BodyExt;
+    use pretty_assertions::assert_eq;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+    use tower::ServiceExt;
+
+    const ALICE: &str = r#"{"subject":"acct:alice@example.com","links":[{"rel":"self","type":"application/activity+json","href":"https://example.com/users/alice"},{"rel":"http://webfinger.net/rel/profile-page","href":"https://example.com/@alice"}]}"#;
+
+    // Start a stand-in WebFinger server and return its address.
+    async fn stand_in_server(router: Router) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, router).await.unwrap();
+        });
+        addr.to_string()
+    }
+
+    // A transport which sends requests to an in-process router.
+    struct RouterTransport(Router);
+
+    #[async_trait]
+    impl Transport for RouterTransport {
+        async fn get(&self, url: &str, headers: HeaderMap) -> Result<TransportResponse, ClientError> {
+            let mut request = axum::http::Request::builder().uri(url).body(Body::empty()).unwrap();
+            *request.headers_mut() = headers;
+            let response = self.0.clone().oneshot(request).await.unwrap();
+            let status = response.status();
+            let headers = response.headers().clone();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            Ok(TransportResponse {
+                status,
+                headers,
+                body: String::from_utf8(body.to_vec()).unwrap(),
+            })
+        }
+    }
+
+    // Create a client using an in-process router which counts the requests it receives.
+    fn router_client(router: Router) -> (Client, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let router = router.layer(axum::middleware::from_fn(
+            move |request: axum::extract::Request, next: axum::middleware::Next| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                next.run(request)
+            },
+        ));
+        (Client::with_transport(Arc::new(RouterTransport(router))), requests)
+    }
+
+    fn secure_options() -> QueryOptions {
+        QueryOptions {
+            rels: vec![],
+            host: None,
+            insecure_http: false,
+            max_redirects: 2,
+        }
+    }
+
+    fn jrd_response(content_type: &'static str, body: &'static str) -> Response {
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    fn options(host: String) -> QueryOptions {
+        QueryOptions {
+            rels: vec![],
+            host: Some(host),
+            insecure_http: true,
+            max_redirects: 2,
+        }
+    }
+
+    #[test]
+    fn test_resource_host() {
+        assert_eq!(resource_host("acct:bob@other.example").unwrap(), "other.example");
+        assert_eq!(resource_host("acct:bob%40home@Other.Example").unwrap(), "other.example");
+        assert_eq!(resource_host("mailto:bob@other.example").unwrap(), "other.example");
+        assert_eq!(resource_host("https://other.example/bob").unwrap(), "other.example");
+        assert_eq!(resource_host("https://user@other.example:8443/bob").unwrap(), "other.example:8443");
+        assert!(resource_host("acct:bob").is_err());
+        assert!(resource_host("bob@other.example").is_err());
+        assert!(resource_host("urn:isbn:0451450523").is_err());
+    }
+
+    #[test]
+    fn test_webfinger_url() {
+        assert_eq!(
+            webfinger_url(
+                "https",
+                "example.com",
+                "acct:carol@example.com",
+                &["http://webfinger.net/rel/avatar".to_string(), "me".to_string()]
+            ),
+            "https://example.com/.well-known/webfinger?resource=acct%3Acarol%40example.com&rel=http%3A%2F%2Fwebfinger.net%2Frel%2Favatar&rel=me"
+        );
+    }
+
+    #[test]
+    fn test_links_table() {
+        let jrd = jrdmap::jrd_from_str(ALICE, jrdmap::Format::Json).unwrap();
+        assert_eq!(
+            links_table(&jrd),
+            "REL                                    TYPE                       HREF\n\
+             self                                   application/activity+json  https://example.com/users/alice\n\
+             http://webfinger.net/rel/profile-page  -                          https://example.com/@alice\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query() {
+        let router = Router::new().route(
+            "/.well-known/webfinger",
+            get(|RawQuery(query): RawQuery| async move {
+                assert_eq!(
+                    query.as_deref(),
+                    Some("resource=acct%3Aalice%40example.com&rel=self")
+                );
+                jrd_response("application/jrd+json", ALICE)
+            }),
+        );
+        let host = stand_in_server(router).await;
+        let mut options = options(host);
+        options.rels = vec!["self".to_string()];
+
+        let jrd = query("acct:alice@example.com", &options).await.unwrap().jrd;
+
+        assert_eq!(jrd.subject, "acct:alice@example.com");
+    }
+
+    #[tokio::test]
+    async fn test_query_follows_redirects() {
+        let router = Router::new()
+            .route(
+                "/.well-known/webfinger",
+                get(|RawQuery(query): RawQuery| async move {
+                    Redirect::temporary(&format!("/elsewhere?{}", query.unwrap()))
+                }),
+            )
+            .route(
+                "/elsewhere",
+                get(|| async { jrd_response("application/jrd+json", ALICE) }),
+            );
+        let host = stand_in_server(router).await;
+
+        let jrd = query("acct:alice@example.com", &options(host)).await.unwrap().jrd;
+
+        assert_eq!(jrd.subject, "acct:alice@example.com");
+    }
+
+    #[tokio::test]
+    async fn test_query_redirect_limit() {
+        let router = Router::new().route(
+            "/.well-known/webfinger",
+            get(|RawQuery(query): RawQuery| async move {
+                Redirect::temporary(&format!("/.well-known/webfinger?{}", query.unwrap()))
+            }),
+        );
+        let host = stand_in_server(router).await;
+
+        assert!(query("acct:alice@example.com", &options(host)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_query_not_found() {
+        let router = Router::new().route(
+            "/.well-known/webfinger",
+            get(|| async { StatusCode::NOT_FOUND }),
+        );
+        let host = stand_in_server(router).await;
+
+        let err = query("acct:alice@example.com", &options(host)).await.unwrap_err();
+
+        assert!(err.0.contains("404"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_query_wrong_content_type() {
+        let router = Router::new().route(
+            "/.well-known/webfinger",
+            get(|| async { jrd_response("text/html", ALICE) }),
+        );
+        let host = stand_in_server(router).await;
+
+        let err = query("acct:alice@example.com", &options(host)).await.unwrap_err();
+
+        assert!(err.0.contains("content type"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_query_accepts_json_content_type() {
+        let router = Router::new().route(
+            "/.well-known/webfinger",
+            get(|| async { jrd_response("application/json; charset=utf-8", ALICE) }),
+        );
+        let host = stand_in_server(router).await;
+
+        let response = query("acct:alice@example.com", &options(host)).await.unwrap();
+
+        assert_eq!(
+            response.warnings,
+            ["Response has content type \"application/json; charset=utf-8\" rather than \"application/jrd+json\""]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_invalid_jrd() {
+        let router = Router::new().route(
+            "/.well-known/webfinger",
+            get(|| async { jrd_response("application/jrd+json", r#"{"links":[]}"#) }),
+        );
+        let host = stand_in_server(router).await;
+
+        let err = query("acct:alice@example.com", &options(host)).await.unwrap_err();
+
+        assert!(err.0.contains("not a valid JRD"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_client_caches_fresh_response() {
+        let (client, requests) = router_client(Router::new().route(
+            "/.well-known/webfinger",
+            get(|| async {
+                let mut response = jrd_response("application/jrd+json", ALICE);
+                response
+                    .headers_mut()
+                    .insert(CACHE_CONTROL, HeaderValue::from_static("public, max-age=60"));
+                response
+            }),
+        ));
+
+        for _ in 0..2 {
+            let jrd = client.query("acct:alice@example.com", &secure_options()).await.unwrap().jrd;
+            assert_eq!(jrd.subject, "acct:alice@example.com");
+        }
+
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_client_revalidates_with_etag() {
+        let (client, requests) = router_client(Router::new().route(
+            "/.well-known/webfinger",
+            get(|headers: HeaderMap| async move {
+                if headers.get(IF_NONE_MATCH).is_some_and(|v| v == "\"v1\"") {
+                    return Response::builder()
+                        .status(StatusCode::NOT_MODIFIED)
+                        .body(Body::empty())
+                        .unwrap();
+                }
+                let mut response = jrd_response("application/jrd+json", ALICE);
+                response
+                    .headers_mut()
+                    .insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+                response.headers_mut().insert(ETAG, HeaderValue::from_static("\"v1\""));
+                response
+            }),
+        ));
+
+        for _ in 0..2 {
+            let jrd = client.query("acct:alice@example.com", &secure_options()).await.unwrap().jrd;
+            assert_eq!(jrd.subject, "acct:alice@example.com");
+        }
+
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_client_does_not_cache_no_store() {
+        let (client, requests) = router_client(Router::new().route(
+            "/.well-known/webfinger",
+            get(|| async {
+                let mut response = jrd_response("application/jrd+json", ALICE);
+                response
+                    .headers_mut()
+                    .insert(CACHE_CONTROL, HeaderValue::from_static("max-age=60, no-store"));
+                response.headers_mut().insert(ETAG, HeaderValue::from_static("\"v1\""));
+                response
+            }),
+        ));
+
+        for _ in 0..2 {
+            client.query("acct:alice@example.com", &secure_options()).await.unwrap();
+        }
+
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_client_cache_is_bounded() {
+        let (mut client, requests) = router_client(Router::new().route(
+            "/.well-known/webfinger",
+            get(|RawQuery(query): RawQuery| async move {
+                let max_age = if query.unwrap().contains("rel=") { 120 } else { 60 };
+                let mut response = jrd_response("application/jrd+json", ALICE);
+                response.headers_mut().insert(
+                    CACHE_CONTROL,
+                    HeaderValue::from_str(&format!("max-age={max_age}")).unwrap(),
+                );
+                response
+            }),
+        ));
+        client.max_cached = 1;
+        let mut self_options = secure_options();
+        self_options.rels = vec!["self".to_string()];
+
+        // Caching the JRD with only self links, which stays fresh for longer,
+        // evicts the full JRD.
+        client.query("acct:alice@example.com", &secure_options()).await.unwrap();
+        client.query("acct:alice@example.com", &self_options).await.unwrap();
+        assert_eq!(client.cache.lock().unwrap().len(), 1);
+
+        client.query("acct:alice@example.com", &self_options).await.unwrap();
+        client.query("acct:alice@example.com", &secure_options()).await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_evict() {
+        let jrd = jrdmap::jrd_from_str(ALICE, jrdmap::Format::Json).unwrap();
+        let now = Instant::now();
+        let entry = |fresh_for: u64, etag: Option<&'static str>| CacheEntry {
+            jrd: jrd.clone(),
+            warnings: vec![],
+            etag: etag.map(HeaderValue::from_static),
+            fresh_until: now + Duration::from_secs(fresh_for),
+        };
+        let mut cache = HashMap::new();
+        cache.insert("stale".to_string(), entry(0, None));
+        cache.insert("revalidatable".to_string(), entry(0, Some("\"v1\"")));
+        cache.insert("short".to_string(), entry(10, None));
+        cache.insert("long".to_string(), entry(60, None));
+
+        evict(&mut cache, 3);
+        let mut urls: Vec<&String> = cache.keys().collect();
+        urls.sort();
+        assert_eq!(urls, ["long", "revalidatable", "short"]);
+
+        evict(&mut cache, 1);
+        assert_eq!(cache.keys().collect::<Vec<_>>(), ["long"]);
+    }
+
+    #[tokio::test]
+    async fn test_client_refuses_redirect_to_http() {
+        let (client, _) = router_client(Router::new().route(
+            "/.well-known/webfinger",
+            get(|| async { Redirect::temporary("http://example.com/elsewhere") }),
+        ));
+
+        let err = client
+            .query("acct:alice@example.com", &secure_options())
+            .await
+            .unwrap_err();
+
+        assert!(err.0.contains("non-HTTPS"), "{err}");
+    }
+
+    // A stand-in server which signs ALICE, or another body, with a key.
+    fn signing_router(key: &jws::SigningKey, signed: &'static str) -> Router {
+        let signature = key.sign_detached(signed.as_bytes());
+        let wrapped = key.sign_wrapped(signed.as_bytes());
+        let jwk_set = serde_json::to_string(&key.jwk_set()).unwrap();
+        Router::new()
+            .route(
+                "/.well-known/webfinger",
+                get(move |headers: HeaderMap| {
+                    let (signature, wrapped) = (signature.clone(), wrapped.clone());
+                    async move {
+                        if headers.get(ACCEPT).unwrap() == jws::JOSE_JSON {
+                            return Response::builder()
+                                .header(CONTENT_TYPE, jws::JOSE_JSON)
+                                .body(Body::from(wrapped))
+                                .unwrap();
+                        }
+                        let mut response = jrd_response("application/jrd+json", ALICE);
+                        response
+                            .headers_mut()
+                            .insert(jws::SIGNATURE_HEADER, HeaderValue::from_str(&signature).unwrap());
+                        response
+                    }
+                }),
+            )
+            .route(
+                jws::JWKS_PATH,
+                get(move || {
+                    let jwk_set = jwk_set.clone();
+                    async move { jwk_set }
+                }),
+            )
+    }
+
+    #[tokio::test]
+    async fn test_verify() {
+        let key = jws::generate_key(jws::Algorithm::EdDSA, None);
+        let (client, _) = router_client(signing_router(&key, ALICE));
+
+        for wrapped in [false, true] {
+            let verify_options = VerifyOptions {
+                wrapped,
+                ..Default::default()
+            };
+            let (response, kid) = client
+                .verify("acct:alice@example.com", &secure_options(), &verify_options)
+                .await
+                .unwrap();
+            assert_eq!(response.jrd.subject, "acct:alice@example.com");
+            assert_eq!(kid, key.kid());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_rejects_tampered_jrd() {
+        let key = jws::generate_key(jws::Algorithm::ES256, None);
+        let (client, _) = router_client(signing_router(&key, r#"{"subject":"acct:alice@example.com"}"#));
+
+        let err = client
+            .verify("acct:alice@example.com", &secure_options(), &VerifyOptions::default())
+            .await
+            .unwrap_err();
+
+        assert!(err.0.contains("Signature verification failed"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_verify_with_jwk_set() {
+        let key = jws::generate_key(jws::Algorithm::EdDSA, None);
+        let (client, requests) = router_client(signing_router(&key, ALICE));
+
+        let verify_options = VerifyOptions {
+            jwk_set: Some(jws::generate_key(jws::Algorithm::EdDSA, None).jwk_set()),
+            ..Default::default()
+        };
+        let err = client
+            .verify("acct:alice@example.com", &secure_options(), &verify_options)
+            .await
+            .unwrap_err();
+
+        assert!(err.0.contains("Signature verification failed"), "{err}");
+        // The published JWK set is not fetched.
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_verify_rejects_unsigned_jrd() {
+        let (client, _) = router_client(Router::new().route(
+            "/.well-known/webfinger",
+            get(|| async { jrd_response("application/jrd+json", ALICE) }),
+        ));
+
+        let err = client
+            .verify("acct:alice@example.com", &secure_options(), &VerifyOptions::default())
+            .await
+            .unwrap_err();
+
+        assert!(err.0.contains("is not signed"), "{err}");
+    }
+
+    // A stand-in server which signs its responses with HTTP message signatures as
+    // if they answered requests with the given target or, if None, the actual target.
+    fn message_signing_router(key: &jws::SigningKey, signed_target: Option<&'static str>) -> Router {
+        let key = key.clone();
+        let jwk_set = serde_json::to_string(&key.jwk_set()).unwrap();
+        Router::new()
+            .route(
+                "/.well-known/webfinger",
+                get(move |uri: axum::http::Uri| {
+                    let key = key.clone();
+                    async move {
+                        let content_digest = httpsig::content_digest(ALICE.as_bytes());
+                        let components = httpsig::Components {
+                            status: 200,
+                            content_type: "application/jrd+json",
+                            content_digest: &content_digest,
+                            request_target: signed_target.unwrap_or(uri.path_and_query().unwrap().as_str()),
+                        };
+                        let created = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                        let (signature_input, signature) = httpsig::sign(&key, &components, created);
+                        let mut response = jrd_response("application/jrd+json", ALICE);
+                        let headers = response.headers_mut();
+                        headers.insert(httpsig::CONTENT_DIGEST, HeaderValue::from_str(&content_digest).unwrap());
+                        headers.insert(httpsig::SIGNATURE_INPUT, HeaderValue::from_str(&signature_input).unwrap());
+                        headers.insert(httpsig::SIGNATURE, HeaderValue::from_str(&signature).unwrap());
+                        response
+                    }
+                }),
+            )
+            .route(
+                jws::JWKS_PATH,
+                get(move || {
+                    let jwk_set = jwk_set.clone();
+                    async move { jwk_set }
+                }),
+            )
+    }
+
+    fn http_message_options() -> VerifyOptions {
+        VerifyOptions {
+            scheme: SignatureScheme::HttpMessage,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_http_message_signature() {
+        let key = jws::generate_key(jws::Algorithm::EdDSA, None);
+        let (client, _) = router_client(message_signing_router(&key, None));
+
+        let (response, kid) = client
+            .verify("acct:alice@example.com", &secure_options(), &http_message_options())
+            .await
+            .unwrap();
+
+        assert_eq!(response.jrd.subject, "acct:alice@example.com");
+        assert_eq!(kid, key.kid());
+    }
+
+    #[tokio::test]
+    async fn test_verify_http_message_signature_for_other_request() {
+        let key = jws::generate_key(jws::Algorithm::EdDSA, None);
+        let (client, _) = router_client(message_signing_router(
+            &key,
+            Some("/.well-known/webfinger?resource=acct%3Abob%40example.com"),
+        ));
+
+        let err = client
+            .verify("acct:alice@example.com", &secure_options(), &http_message_options())
+            .await
+            .unwrap_err();
+
+        assert!(err.0.contains("Signature verification failed"), "{err}");
+    }
+}
@@ -0,0 +1,312 @@
+/*
+Copyright 2024 Glyn Normington
+
+This file is part of webfinger-rs.
+
+webfinger-rs is free software: you can redistribute it and/or modify it under the terms
+of the GNU General Public License as published by the Free Software Foundation, either
+version 3 of the License, or (at your option) any later version.
+
+webfinger-rs is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with webfinger-rs.
+If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Compression of JRD response bodies, negotiated using the Accept-Encoding
+// header. The bodies returned to anonymous requests without rel parameters are
+// compressed in advance when the store is loaded and whenever it changes, and
+// kept in a response cache keyed by a hash of the uncompressed body, so that a
+// cached variant is used for any request producing the same body whatever its rel
+// parameters, Accept-Language header, or credentials. Other bodies are compressed
+// as they are requested and not cached, since some, such as JWS-wrapped JRDs with
+// their randomized signatures, are never served twice.
+// Compression is done on blocking threads, since it can take long enough at the
+// levels used to hold up other requests.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+
+use axum::body::Bytes;
+use hyper::header::{HeaderMap, ACCEPT_ENCODING};
+use sha2::{Digest, Sha256};
+
+use crate::server::{self, ServerOptions};
+use crate::store::{JrdStore, StoreError};
+
+/* Options controlling response compression. */
+#[derive(Clone, Debug)]
+pub struct CompressionOptions {
+    // Bodies smaller than this number of bytes are not compressed.
+    pub min_size: usize,
+
+    // Maximum number of compressed bodies to cache. Once the cache is full, no
+    // more bodies are compressed in advance.
+    pub max_cached: usize,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> CompressionOptions {
+        CompressionOptions {
+            min_size: 512,
+            max_cached: 10000,
+        }
+    }
+}
+
+// The content codings supported, in order of preference.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+    // The name of the content coding, as used in Accept-Encoding and Content-Encoding.
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn compress(self, body: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Brotli => {
+                let mut compressed = Vec::new();
+                let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, 9, 22);
+                writer.write_all(body).unwrap();
+                drop(writer);
+                compressed
+            }
+            Encoding::Zstd => zstd::encode_all(body, 9).unwrap(),
+            Encoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body).unwrap();
+                encoder.finish().unwrap()
+            }
+        }
+    }
+
+    // Decompress a body, as a client would.
+    pub fn decompress(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        match self {
+            Encoding::Brotli => {
+                brotli::Decompressor::new(body, 4096).read_to_end(&mut decompressed)?;
+            }
+            Encoding::Zstd => decompressed = zstd::decode_all(body)?,
+            Encoding::Gzip => {
+                flate2::read::GzDecoder::new(body).read_to_end(&mut decompressed)?;
+            }
+        }
+        Ok(decompressed)
+    }
+}
+
+// Choose the content coding to use for a response from the request's
+// Accept-Encoding header: the supported coding with the highest quality value,
+// or None if the body should not be compressed.
+pub fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+    let mut qualities: HashMap<String, f32> = HashMap::new();
+    for item in headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+    {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+        if coding.is_empty() {
+            continue;
+        }
+        let quality = parts
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())
+            .unwrap_or(0.0);
+        qualities.insert(coding, quality);
+    }
+
+    let quality = |encoding: Encoding| {
+        let explicit = match encoding {
+            Encoding::Gzip => qualities.get("gzip").or_else(|| qualities.get("x-gzip")),
+            _ => qualities.get(encoding.name()),
+        };
+        explicit.or_else(|| qualities.get("*")).copied().unwrap_or(0.0)
+    };
+    // Encodings of equal quality are chosen in order of preference.
+    Encoding::ALL
+        .into_iter()
+        .filter(|encoding| quality(*encoding) > 0.0)
+        .fold(None, |best: Option<Encoding>, encoding| match best {
+            Some(best) if quality(best) >= quality(encoding) => Some(best),
+            _ => Some(encoding),
+        })
+}
+
+type Key = ([u8; 32], Encoding);
+
+/* A ResponseCache holds compressed response bodies. */
+pub(crate) struct ResponseCache {
+    options: CompressionOptions,
+    entries: Mutex<HashMap<Key, Bytes>>,
+}
+
+impl ResponseCache {
+    pub(crate) fn new(options: CompressionOptions) -> ResponseCache {
+        ResponseCache {
+            options,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Determine whether a body is large enough to be compressed.
+    pub(crate) fn compressible(&self, body: &str) -> bool {
+        body.len() >= self.options.min_size
+    }
+
+    // Compress a body, using the cached variant if there is one.
+    pub(crate) async fn compress(&self, body: &str, encoding: Encoding) -> Bytes {
+        if let Some(compressed) = self.entries.lock().unwrap().get(&(hash(body), encoding)) {
+            return compressed.clone();
+        }
+        let body = body.to_string();
+        tokio::task::spawn_blocking(move || Bytes::from(encoding.compress(body.as_bytes())))
+            .await
+            .expect("Failed to compress response")
+    }
+
+    // Replace the cache with every supported compression of the body returned to
+    // an anonymous request without rel parameters for each resource in the store.
+    pub(crate) async fn load(&self, store: &dyn JrdStore, options: &ServerOptions) -> Result<(), StoreError> {
+        let capacity = self.options.max_cached / Encoding::ALL.len();
+        let mut bodies = Vec::new();
+        for resource in store.list().await?.unwrap_or_default() {
+            if bodies.len() >= capacity {
+                break;
+            }
+            if let Some(jrd) = store.lookup(&resource).await?.and_then(|jrd| jrd.visible_to(None)) {
+                let body = server::render(options, &jrd);
+                if self.compressible(&body) {
+                    bodies.push(body);
+                }
+            }
+        }
+
+        let entries = tokio::task::spawn_blocking(move || {
+            let mut entries = HashMap::new();
+            for body in bodies {
+                let hash = hash(&body);
+                for encoding in Encoding::ALL {
+                    entries.insert((hash, encoding), Bytes::from(encoding.compress(body.as_bytes())));
+                }
+            }
+            entries
+        })
+        .await
+        .map_err(|e| StoreError(format!("Failed to compress responses: {e}")))?;
+        *self.entries.lock().unwrap() = entries;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
+
+// Load the cache and reload it whenever the store changes.
+pub(crate) async fn keep_loaded(cache: Arc<ResponseCache>, store: Arc<dyn JrdStore>, options: Arc<ServerOptions>) {
+    let mut changes = store.subscribe();
+    loop {
+        if let Err(e) = cache.load(store.as_ref(), &options).await {
+            eprintln!("Failed to load response cache: {e}");
+        }
+        let Some(changes) = changes.as_mut() else {
+            break;
+        };
+        if changes.changed().await.is_err() {
+            break;
+        }
+    }
+}
+
+fn hash(body: &str) -> [u8; 32] {
+    Sha256::digest(body.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jrdmap;
+    use crate::store::JsonMapStore;
+    use hyper::header::HeaderValue;
+
+    fn accept_encoding(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(&accept_encoding("gzip, deflate, br, zstd")), Some(Encoding::Brotli));
+        assert_eq!(negotiate(&accept_encoding("gzip;q=1.0, br;q=0.5")), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&accept_encoding("x-gzip")), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&accept_encoding("*")), Some(Encoding::Brotli));
+        assert_eq!(negotiate(&accept_encoding("br;q=0, *;q=0.1")), Some(Encoding::Zstd));
+        assert_eq!(negotiate(&accept_encoding("deflate, identity")), None);
+        assert_eq!(negotiate(&accept_encoding("gzip;q=0")), None);
+        assert_eq!(negotiate(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_compress_round_trip() {
+        let body = "{\"subject\":\"acct:alice@example.com\"}".repeat(100);
+        for encoding in Encoding::ALL {
+            let compressed = encoding.compress(body.as_bytes());
+            assert!(compressed.len() < body.len(), "{encoding:?}");
+            assert_eq!(encoding.decompress(&compressed).unwrap(), body.as_bytes(), "{encoding:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compress_does_not_cache() {
+        let cache = ResponseCache::new(CompressionOptions::default());
+        let body = "{\"subject\":\"acct:alice@example.com\"}".repeat(100);
+        let compressed = cache.compress(&body, Encoding::Gzip).await;
+        assert_eq!(Encoding::Gzip.decompress(&compressed).unwrap(), body.as_bytes());
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_load() {
+        let long_title = "x".repeat(600);
+        let jm = jrdmap::from_json(&format!(
+            r#"{{
+                "acct:alice@example.com": {{"subject": "acct:alice@example.com", "links": [{{"rel": "me", "titles": {{"und": "{long_title}"}}}}]}},
+                "acct:bob@example.com": {{"subject": "acct:bob@example.com"}},
+                "acct:carol@example.com": {{"subject": "acct:carol@example.com", "links": [{{"rel": "me", "titles": {{"und": "{long_title}"}}}}], "visibility": "private"}}
+            }}"#
+        ));
+        let store = JsonMapStore::new(jm);
+        let cache = ResponseCache::new(CompressionOptions::default());
+        let options = ServerOptions::default();
+
+        cache.load(&store, &options).await.unwrap();
+
+        // Only alice's JRD is both visible to anonymous clients and large enough
+        // to compress.
+        assert_eq!(cache.len(), Encoding::ALL.len());
+        let body = server::render(&options, &store.lookup("acct:alice@example.com").await.unwrap().unwrap());
+        let cached = cache.entries.lock().unwrap().get(&(hash(&body), Encoding::Zstd)).cloned();
+        assert_eq!(Some(cache.compress(&body, Encoding::Zstd).await), cached);
+    }
+}
@@ -0,0 +1,154 @@
+/*
+Copyright 2024 Glyn Normington
+
+This file is part of webfinger-rs.
+
+webfinger-rs is free software: you can redistribute it and/or modify it under the terms
+of the GNU General Public License as published by the Free Software Foundation, either
+version 3 of the License, or (at your option) any later version.
+
+webfinger-rs is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with webfinger-rs.
+If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Cross-origin resource sharing (CORS) for the well-known routes. RFC 7033
+// recommends allowing any origin, which is the default, but the allowed origins,
+// the headers exposed to scripts, and the headers allowed in requests can be
+// configured. Preflight requests are answered here, before any other processing.
+
+use std::time::Duration;
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS,
+    ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+    ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+
+use crate::server::ServerState;
+
+// Methods which may be used in cross-origin requests.
+const ALLOWED_METHODS: &str = "GET";
+
+/* Options controlling CORS. */
+#[derive(Clone, Debug, Default)]
+pub struct CorsOptions {
+    // Origins, such as https://app.example.com, which may read responses. If empty,
+    // any origin may read responses.
+    pub allowed_origins: Vec<String>,
+
+    // Response headers, beyond those which are always exposed, which scripts may read.
+    pub expose_headers: Vec<HeaderName>,
+
+    // Request headers, such as Authorization, which scripts may send.
+    pub allow_headers: Vec<HeaderName>,
+
+    // How long browsers may cache the response to a preflight request.
+    pub max_age: Option<Duration>,
+}
+
+impl CorsOptions {
+    // The value of Access-Control-Allow-Origin for a request, or None if the
+    // request's origin is not allowed.
+    fn allow_origin(&self, headers: &HeaderMap) -> Option<HeaderValue> {
+        if self.allowed_origins.is_empty() {
+            return Some(HeaderValue::from_static("*"));
+        }
+        let origin = headers.get(ORIGIN)?;
+        self.allowed_origins
+            .iter()
+            .any(|allowed| origin.as_bytes().eq_ignore_ascii_case(allowed.as_bytes()))
+            .then(|| origin.clone())
+    }
+
+    // Add the headers common to all responses.
+    fn add_headers(&self, request_headers: &HeaderMap, response_headers: &mut HeaderMap) {
+        // Responses depend on the origin unless any origin is allowed.
+        if !self.allowed_origins.is_empty() {
+            response_headers.append(VARY, HeaderValue::from_static("Origin"));
+        }
+        if let Some(allow_origin) = self.allow_origin(request_headers) {
+            response_headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        }
+    }
+}
+
+// Answer preflight requests and add CORS headers to other responses.
+pub(crate) async fn apply(State(state): State<ServerState>, request: Request, next: Next) -> Response {
+    let cors = &state.options.cors;
+    let headers = request.headers().clone();
+
+    if request.method() == Method::OPTIONS && headers.contains_key(ACCESS_CONTROL_REQUEST_METHOD) {
+        let mut response = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap();
+        let response_headers = response.headers_mut();
+        cors.add_headers(&headers, response_headers);
+        if response_headers.contains_key(ACCESS_CONTROL_ALLOW_ORIGIN) {
+            response_headers.insert(ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_static(ALLOWED_METHODS));
+            if !cors.allow_headers.is_empty() {
+                response_headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, join(&cors.allow_headers));
+            }
+            if let Some(max_age) = cors.max_age {
+                response_headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age.as_secs()));
+            }
+        }
+        return response;
+    }
+
+    let mut response = next.run(request).await;
+    let response_headers = response.headers_mut();
+    cors.add_headers(&headers, response_headers);
+    if response_headers.contains_key(ACCESS_CONTROL_ALLOW_ORIGIN) && !cors.expose_headers.is_empty() {
+        response_headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, join(&cors.expose_headers));
+    }
+    response
+}
+
+fn join(names: &[HeaderName]) -> HeaderValue {
+    let names: Vec<&str> = names.iter().map(HeaderName::as_str).collect();
+    HeaderValue::from_str(&names.join(", ")).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origin(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ORIGIN, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn test_allow_any_origin() {
+        let cors = CorsOptions::default();
+        assert_eq!(cors.allow_origin(&origin("https://app.example.com")).unwrap(), "*");
+        assert_eq!(cors.allow_origin(&HeaderMap::new()).unwrap(), "*");
+    }
+
+    #[test]
+    fn test_allow_listed_origins() {
+        let cors = CorsOptions {
+            allowed_origins: vec!["https://app.example.com".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            cors.allow_origin(&origin("https://APP.example.com")).unwrap(),
+            "https://APP.example.com"
+        );
+        assert!(cors.allow_origin(&origin("https://evil.example.com")).is_none());
+        assert!(cors.allow_origin(&HeaderMap::new()).is_none());
+    }
+}
@@ -0,0 +1,550 @@
+/*
+Copyright 2024 Glyn Normington
+
+This file is part of webfinger-rs.
+
+webfinger-rs is free software: you can redistribute it and/or modify it under the terms
+of the GNU General Public License as published by the Free Software Foundation, either
+version 3 of the License, or (at your option) any later version.
+
+webfinger-rs is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with webfinger-rs.
+If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// A JrdStore which loads one JRD per file from a directory tree, so that each
+// resource can be managed as a separate file in version control. Files may be
+// written in JSON, YAML, or TOML, as indicated by their extensions. Other files,
+// and files and directories whose names begin with ".", are ignored.
+//
+// The directory is watched for changes and only the files which change are
+// reloaded. If a changed file is invalid, the previous version of its entry
+// continues to be served. The changes made, and any changed files which are
+// ignored, are reported to the caller rather than logged.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+
+use async_trait::async_trait;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use percent_encoding::percent_decode_str;
+use tokio::sync::watch;
+
+use crate::jrdmap::{self, Format, Jrd, JrdMap};
+use crate::store::{JrdStore, StoreError};
+
+// How the resource URI of each file's JRD is determined.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum DirectoryKey {
+    // The "subject" of the JRD.
+    Subject,
+
+    // The file name without its extension, percent-decoded (so that characters
+    // which are not permitted in file names may be percent-encoded).
+    Filename,
+}
+
+/* A change to the JRDs of a DirectoryStore, made by reloading a file. */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    // The JRD of a resource was loaded from a file which was created or modified.
+    Reloaded { uri: String, path: PathBuf },
+
+    // A resource was removed because its file was deleted.
+    Removed { uri: String, path: PathBuf },
+}
+
+#[derive(Default)]
+struct Entries {
+    // The resource URI of the JRD loaded from each file.
+    keys: HashMap<PathBuf, String>,
+
+    jrds: JrdMap,
+
+    // The digest of the JRDs, computed whenever they change.
+    digest: String,
+}
+
+impl Entries {
+    fn update_digest(&mut self) {
+        self.digest = jrdmap::digest(&self.jrds);
+    }
+}
+
+pub struct DirectoryStore {
+    dir: PathBuf,
+    key: DirectoryKey,
+    entries: RwLock<Entries>,
+    changes: watch::Sender<()>,
+    watcher: Mutex<Option<RecommendedWatcher>>,
+}
+
+impl DirectoryStore {
+    // Load every JRD file in the directory tree. Fails if any file is invalid or if
+    // two files have the same resource URI. The directory's path is made absolute,
+    // since the paths of changed files reported by the watcher are absolute.
+    pub fn load(dir: &Path, key: DirectoryKey) -> Result<DirectoryStore, StoreError> {
+        let dir = &fs::canonicalize(dir)
+            .map_err(|e| StoreError(format!("Failed to read directory {dir:?}: {e}")))?;
+        let store = DirectoryStore {
+            dir: dir.to_path_buf(),
+            key,
+            entries: RwLock::new(Entries::default()),
+            changes: watch::Sender::new(()),
+            watcher: Mutex::new(None),
+        };
+
+        let mut paths = Vec::new();
+        find_files(dir, &mut paths)
+            .map_err(|e| StoreError(format!("Failed to read directory {dir:?}: {e}")))?;
+
+        let mut entries = Entries::default();
+        for path in paths {
+            let (uri, jrd) = store.read_file(&path)?;
+            if let Some((other, _)) = entries.keys.iter().find(|(_, k)| **k == uri) {
+                return Err(StoreError(format!(
+                    "resource {uri:?} is defined in both {other:?} and {path:?}"
+                )));
+            }
+            entries.keys.insert(path, uri.clone());
+            entries.jrds.insert(uri, jrd);
+        }
+        entries.update_digest();
+        *store.entries.write().unwrap() = entries;
+
+        Ok(store)
+    }
+
+    // Start watching the directory tree, reloading files as they change. Each
+    // change, and each changed file which is ignored or error watching the
+    // directory, is passed to report.
+    pub fn watch<F>(self: &Arc<Self>, report: F) -> Result<(), StoreError>
+    where
+        F: Fn(Result<Change, StoreError>) + Send + 'static,
+    {
+        let weak: Weak<DirectoryStore> = Arc::downgrade(self);
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            let Some(store) = weak.upgrade() else {
+                return;
+            };
+            match event {
+                Ok(event) => {
+                    for path in event.paths {
+                        store.reload(&path).into_iter().for_each(&report);
+                    }
+                }
+                Err(e) => report(Err(StoreError(format!("Error watching {:?}: {e}", store.dir)))),
+            }
+        })
+        .map_err(|e| StoreError(format!("Failed to watch {:?}: {e}", self.dir)))?;
+
+        watcher
+            .watch(&self.dir, RecursiveMode::Recursive)
+            .map_err(|e| StoreError(format!("Failed to watch {:?}: {e}", self.dir)))?;
+        *self.watcher.lock().unwrap() = Some(watcher);
+        Ok(())
+    }
+
+    // Reload the entry for a file which has been created, modified, or deleted. If
+    // the path is a directory, reload the files beneath it. Returns the changes
+    // made and, for a file which is ignored because it is invalid, an error.
+    // Paths which are not in the directory tree, or which are in a directory
+    // whose name begins with ".", are ignored.
+    pub fn reload(&self, path: &Path) -> Vec<Result<Change, StoreError>> {
+        let path = &self.resolve(path);
+        let Ok(relative) = path.strip_prefix(&self.dir) else {
+            return Vec::new();
+        };
+        if relative.iter().any(is_hidden_name) {
+            return Vec::new();
+        }
+
+        if path.is_dir() {
+            let mut paths = Vec::new();
+            if find_files(path, &mut paths).is_err() {
+                return Vec::new();
+            }
+            return paths.iter().flat_map(|path| self.reload(path)).collect();
+        }
+
+        // Forget entries for files which no longer exist, including the files
+        // beneath a deleted directory.
+        let removed: Vec<PathBuf> = {
+            let entries = self.entries.read().unwrap();
+            entries
+                .keys
+                .keys()
+                .filter(|p| p.starts_with(path) && !p.exists())
+                .cloned()
+                .collect()
+        };
+        if !removed.is_empty() {
+            let mut entries = self.entries.write().unwrap();
+            let mut changes = Vec::new();
+            for path in removed {
+                if let Some(uri) = entries.keys.remove(&path) {
+                    entries.jrds.remove(&uri);
+                    changes.push(Ok(Change::Removed { uri, path }));
+                }
+            }
+            entries.update_digest();
+            drop(entries);
+            self.changes.send_replace(());
+            return changes;
+        }
+
+        if !is_jrd_file(path) || !path.exists() {
+            return Vec::new();
+        }
+
+        let (uri, jrd) = match self.read_file(path) {
+            Ok(entry) => entry,
+            Err(e) => return vec![Err(StoreError(format!("Ignoring change to {path:?}: {e}")))],
+        };
+
+        let mut entries = self.entries.write().unwrap();
+        if let Some((other, _)) = entries
+            .keys
+            .iter()
+            .find(|(p, k)| **k == uri && p.as_path() != path)
+        {
+            return vec![Err(StoreError(format!(
+                "Ignoring change to {path:?}: resource {uri:?} is already defined in {other:?}"
+            )))];
+        }
+        if let Some(previous) = entries.keys.insert(path.to_path_buf(), uri.clone()) {
+            entries.jrds.remove(&previous);
+        }
+        entries.jrds.insert(uri.clone(), jrd);
+        entries.update_digest();
+        drop(entries);
+        self.changes.send_replace(());
+        vec![Ok(Change::Reloaded {
+            uri,
+            path: path.to_path_buf(),
+        })]
+    }
+
+    // Resolve a path which is not already in the directory tree, such as a
+    // relative path, in the same way as the directory's path, so that it can be
+    // compared with the paths of the files loaded. The parent is resolved, rather
+    // than the path itself, since the path may have been deleted.
+    fn resolve(&self, path: &Path) -> PathBuf {
+        if path.starts_with(&self.dir) {
+            return path.to_path_buf();
+        }
+        let parent = match path.parent() {
+            Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+            Some(parent) => parent,
+            None => return path.to_path_buf(),
+        };
+        match (fs::canonicalize(parent), path.file_name()) {
+            (Ok(parent), Some(name)) => parent.join(name),
+            _ => path.to_path_buf(),
+        }
+    }
+
+    fn read_file(&self, path: &Path) -> Result<(String, Jrd), StoreError> {
+        let format = Format::from_path(path)
+            .ok_or_else(|| StoreError(format!("unsupported file type {path:?}")))?;
+        let contents = fs::read_to_string(path)
+            .map_err(|e| StoreError(format!("Failed to read {path:?}: {e}")))?;
+        let jrd = jrdmap::jrd_from_str(&contents, format)
+            .map_err(|e| StoreError(format!("Failed to parse {path:?}: {e}")))?;
+
+        let uri = match self.key {
+            DirectoryKey::Subject => jrd.subject.clone(),
+            DirectoryKey::Filename => {
+                let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+                percent_decode_str(stem)
+                    .decode_utf8()
+                    .map_err(|e| StoreError(format!("Invalid file name {path:?}: {e}")))?
+                    .into_owned()
+            }
+        };
+        jrdmap::validate_entry(&uri, &jrd)
+            .map_err(|e| StoreError(format!("Invalid JRD in {path:?}: {e}")))?;
+        Ok((uri, jrd))
+    }
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name().is_some_and(is_hidden_name)
+}
+
+fn is_hidden_name(name: &OsStr) -> bool {
+    name.to_str().is_some_and(|name| name.starts_with('.'))
+}
+
+fn is_jrd_file(path: &Path) -> bool {
+    !is_hidden(path) && Format::from_path(path).is_some()
+}
+
+fn find_files(dir: &Path, paths: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if is_hidden(&path) {
+            continue;
+        }
+        if path.is_dir() {
+            find_files(&path, paths)?;
+        } else if is_jrd_file(&path) {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl JrdStore for DirectoryStore {
+    async fn lookup(&self, resource: &str) -> Result<Option<Jrd>, StoreError> {
+        Ok(self.entries.read().unwrap().jrds.get(resource).cloned())
+    }
+
+    async fn list(&self) -> Result<Option<Vec<String>>, StoreError> {
+        let mut resources: Vec<String> =
+            self.entries.read().unwrap().jrds.keys().cloned().collect();
+        resources.sort();
+        Ok(Some(resources))
+    }
+
+    async fn count(&self) -> Result<Option<usize>, StoreError> {
+        Ok(Some(self.entries.read().unwrap().jrds.len()))
+    }
+
+    async fn digest(&self) -> Result<Option<String>, StoreError> {
+        Ok(Some(self.entries.read().unwrap().digest.clone()))
+    }
+
+    fn subscribe(&self) -> Option<watch::Receiver<()>> {
+        Some(self.changes.subscribe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn write(dir: &Path, name: &str, contents: &str) -> PathBuf {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn setup() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        write(
+            dir.path(),
+            "alice.json",
+            r#"{"subject":"acct:alice@example.com","links":[{"rel":"self","href":"https://example.com/alice"}]}"#,
+        );
+        write(
+            dir.path(),
+            "team/bob.yaml",
+            "subject: acct:bob@example.com\nlinks:\n  - rel: self\n    href: https://example.com/bob\n",
+        );
+        write(
+            dir.path(),
+            "team/carol.toml",
+            "subject = \"acct:carol@example.com\"\n",
+        );
+        write(dir.path(), "README.md", "Not a JRD");
+        write(dir.path(), ".alice.json.swp", "Not a JRD");
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_load_by_subject() {
+        let dir = setup();
+        let store = DirectoryStore::load(dir.path(), DirectoryKey::Subject).unwrap();
+
+        assert_eq!(
+            store.list().await.unwrap(),
+            Some(vec![
+                "acct:alice@example.com".to_string(),
+                "acct:bob@example.com".to_string(),
+                "acct:carol@example.com".to_string()
+            ])
+        );
+        let bob = store.lookup("acct:bob@example.com").await.unwrap().unwrap();
+        assert_eq!(bob.links.unwrap()[0].href.as_deref(), Some("https://example.com/bob"));
+    }
+
+    #[tokio::test]
+    async fn test_load_by_filename() {
+        let dir = tempfile::tempdir().unwrap();
+        write(
+            dir.path(),
+            "acct%3Aalice@example.com.json",
+            r#"{"subject":"acct:alice@example.com"}"#,
+        );
+        let store = DirectoryStore::load(dir.path(), DirectoryKey::Filename).unwrap();
+
+        assert!(store.lookup("acct:alice@example.com").await.unwrap().is_some());
+    }
+
+    #[test]
+    fn test_load_rejects_invalid_file() {
+        let dir = setup();
+        write(dir.path(), "dave.json", r#"{"subject":"dave@example.com"}"#);
+
+        assert!(DirectoryStore::load(dir.path(), DirectoryKey::Subject).is_err());
+    }
+
+    #[test]
+    fn test_load_rejects_duplicate_resource() {
+        let dir = setup();
+        write(dir.path(), "alice2.json", r#"{"subject":"acct:alice@example.com"}"#);
+
+        assert!(DirectoryStore::load(dir.path(), DirectoryKey::Subject).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reload_modified_file() {
+        let dir = setup();
+        let store = DirectoryStore::load(dir.path(), DirectoryKey::Subject).unwrap();
+        let changes = store.subscribe().unwrap();
+
+        let path = write(
+            dir.path(),
+            "team/carol.toml",
+            "subject = \"acct:carol@example.com\"\naliases = [\"https://example.com/carol\"]\n",
+        );
+        let reloaded = store.reload(&path);
+
+        assert_eq!(
+            reloaded.into_iter().map(Result::unwrap).collect::<Vec<_>>(),
+            vec![Change::Reloaded {
+                uri: "acct:carol@example.com".to_string(),
+                path: fs::canonicalize(&path).unwrap()
+            }]
+        );
+        assert!(changes.has_changed().unwrap());
+        let carol = store.lookup("acct:carol@example.com").await.unwrap().unwrap();
+        assert_eq!(carol.aliases, Some(vec!["https://example.com/carol".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn test_reload_renamed_subject() {
+        let dir = setup();
+        let store = DirectoryStore::load(dir.path(), DirectoryKey::Subject).unwrap();
+
+        let path = write(dir.path(), "alice.json", r#"{"subject":"acct:alicia@example.com"}"#);
+        store.reload(&path);
+
+        assert!(store.lookup("acct:alice@example.com").await.unwrap().is_none());
+        assert!(store.lookup("acct:alicia@example.com").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_reload_invalid_file_keeps_previous_entry() {
+        let dir = setup();
+        let store = DirectoryStore::load(dir.path(), DirectoryKey::Subject).unwrap();
+
+        let path = write(dir.path(), "alice.json", r#"{"subject":"#);
+        let reloaded = store.reload(&path);
+
+        assert_eq!(reloaded.len(), 1);
+        assert!(reloaded[0].is_err());
+        assert!(store.lookup("acct:alice@example.com").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_reload_deleted_file() {
+        let dir = setup();
+        let store = DirectoryStore::load(dir.path(), DirectoryKey::Subject).unwrap();
+
+        let path = dir.path().join("team/bob.yaml");
+        fs::remove_file(&path).unwrap();
+        let reloaded = store.reload(&path);
+
+        assert!(matches!(&reloaded[..], [Ok(Change::Removed { uri, .. })] if uri == "acct:bob@example.com"));
+        assert!(store.lookup("acct:bob@example.com").await.unwrap().is_none());
+        assert!(store.lookup("acct:carol@example.com").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_reload_deleted_directory() {
+        let dir = setup();
+        let store = DirectoryStore::load(dir.path(), DirectoryKey::Subject).unwrap();
+
+        let path = dir.path().join("team");
+        fs::remove_dir_all(&path).unwrap();
+        store.reload(&path);
+
+        assert_eq!(
+            store.list().await.unwrap(),
+            Some(vec!["acct:alice@example.com".to_string()])
+        );
+        assert_eq!(store.count().await.unwrap(), Some(1));
+        let jrds = store.entries.read().unwrap().jrds.clone();
+        assert_eq!(store.digest().await.unwrap(), Some(jrdmap::digest(&jrds)));
+    }
+
+    #[tokio::test]
+    async fn test_reload_ignores_hidden_directories() {
+        let dir = setup();
+        let store = DirectoryStore::load(dir.path(), DirectoryKey::Subject).unwrap();
+
+        let path = write(dir.path(), ".git/dave.json", r#"{"subject":"acct:dave@example.com"}"#);
+
+        assert!(store.reload(&path).is_empty());
+        assert!(store.reload(&dir.path().join(".git")).is_empty());
+        assert!(store.lookup("acct:dave@example.com").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reload_relative_directory() {
+        // The watcher reports absolute paths, even if the directory is relative.
+        let dir = tempfile::Builder::new().prefix("jrds").tempdir_in(".").unwrap();
+        let relative = Path::new(dir.path().file_name().unwrap());
+        write(relative, "alice.json", r#"{"subject":"acct:alice@example.com"}"#);
+        write(relative, "bob.json", r#"{"subject":"acct:bob@example.com"}"#);
+        let store = DirectoryStore::load(relative, DirectoryKey::Subject).unwrap();
+
+        let path = write(
+            relative,
+            "alice.json",
+            r#"{"subject":"acct:alice@example.com","aliases":["https://example.com/alice"]}"#,
+        );
+        assert!(store.reload(&fs::canonicalize(&path).unwrap())[0].is_ok());
+        let alice = store.lookup("acct:alice@example.com").await.unwrap().unwrap();
+        assert_eq!(alice.aliases, Some(vec!["https://example.com/alice".to_string()]));
+
+        let path = fs::canonicalize(relative.join("bob.json")).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(store.reload(&path)[0].is_ok());
+        assert!(store.lookup("acct:bob@example.com").await.unwrap().is_none());
+
+        // Relative paths are also accepted.
+        write(relative, "carol.json", r#"{"subject":"acct:carol@example.com"}"#);
+        assert!(store.reload(&relative.join("carol.json"))[0].is_ok());
+        assert!(store.lookup("acct:carol@example.com").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let dir = setup();
+        let store = Arc::new(DirectoryStore::load(dir.path(), DirectoryKey::Subject).unwrap());
+        store.watch(|_| {}).unwrap();
+
+        write(dir.path(), "dave.json", r#"{"subject":"acct:dave@example.com"}"#);
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while store.lookup("acct:dave@example.com").await.unwrap().is_none() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
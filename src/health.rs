@@ -0,0 +1,67 @@
+/*
+Copyright 2024 Glyn Normington
+
+This file is part of webfinger-rs.
+
+webfinger-rs is free software: you can redistribute it and/or modify it under the terms
+of the GNU General Public License as published by the Free Software Foundation, either
+version 3 of the License, or (at your option) any later version.
+
+webfinger-rs is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with webfinger-rs.
+If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use axum::{body::Body, extract::State, http::StatusCode, response::Response};
+use hyper::header::CONTENT_TYPE;
+use serde_json::json;
+
+use crate::server::ServerState;
+
+// Liveness probe: if the process can answer this, it is alive.
+pub async fn healthz() -> Response {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/plain")
+        .body(Body::from("OK"))
+        .unwrap()
+}
+
+// Readiness probe. The router is only created once the map has been loaded and
+// validated and the listeners have been bound, so reaching this handler implies both.
+// The server does not proxy to any upstreams, so the only further check is that
+// the store can be queried. The hash and resource count are null if the store
+// does not support computing them.
+pub async fn readyz(State(state): State<ServerState>) -> Response {
+    let status = match state.store.check().await {
+        Ok(()) => (state.store.digest().await, state.store.count().await),
+        Err(e) => (Err(e), Ok(None)),
+    };
+    let (hash, resources) = match status {
+        (Ok(hash), Ok(resources)) => (hash, resources),
+        (Err(e), _) | (_, Err(e)) => {
+            let body = json!({"status": "unavailable", "error": e.to_string()});
+            return json_response(StatusCode::SERVICE_UNAVAILABLE, body.to_string());
+        }
+    };
+
+    let body = json!({
+        "status": "ready",
+        "map": {
+            "hash": hash,
+            "resources": resources,
+        },
+    });
+    json_response(StatusCode::OK, body.to_string())
+}
+
+fn json_response(status: StatusCode, body: String) -> Response {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
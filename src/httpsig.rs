@@ -0,0 +1,401 @@
+/*
+Copyright 2024 Glyn Normington
+
+This file is part of webfinger-rs.
+
+webfinger-rs is free software: you can redistribute it and/or modify it under the terms
+of the GNU General Public License as published by the Free Software Foundation, either
+version 3 of the License, or (at your option) any later version.
+
+webfinger-rs is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with webfinger-rs.
+If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// HTTP Message Signatures (RFC 9421) over WebFinger responses, as an alternative
+// to signing the JRD itself with a JWS. A signature covers the response's status,
+// content type, and Content-Digest (RFC 9530), and the target of the request it
+// answers, so that a response cannot be replayed for a different resource. The
+// signing key is identified by its key ID, so that a verifier can pick the right
+// key from the JWK set published by the server while keys are being rotated.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+use crate::jws::{Algorithm, JwkSet, SigningKey};
+
+// Response header fields defined by RFC 9421 and RFC 9530.
+pub const SIGNATURE_INPUT: &str = "signature-input";
+pub const SIGNATURE: &str = "signature";
+pub const CONTENT_DIGEST: &str = "content-digest";
+
+// Label of the signature in the Signature-Input and Signature dictionaries.
+const LABEL: &str = "webfinger";
+
+// Maximum age in seconds of a signature accepted by a verifier, so that an old
+// response cannot be replayed indefinitely.
+pub const MAX_AGE: u64 = 24 * 60 * 60;
+
+// Allowance in seconds for a signer's clock being ahead of the verifier's.
+const CLOCK_SKEW: u64 = 60;
+
+// The components covered by a signature, each of which a verifier requires.
+const COVERED: [&str; 4] = [
+    "\"@status\"",
+    "\"content-type\"",
+    "\"content-digest\"",
+    "\"@request-target\";req",
+];
+
+/* SignatureScheme determines how JRD responses are signed. */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum SignatureScheme {
+    // Sign the JRD with a JWS, either detached in a header or wrapping the JRD.
+    #[default]
+    Jws,
+
+    // Sign the response with an HTTP message signature.
+    HttpMessage,
+}
+
+/* Components holds the values of the message components covered by a signature. */
+pub struct Components<'a> {
+    pub status: u16,
+
+    pub content_type: &'a str,
+
+    pub content_digest: &'a str,
+
+    // Target of the request, such as /.well-known/webfinger?resource=acct%3Aalice%40example.com
+    pub request_target: &'a str,
+}
+
+impl Components<'_> {
+    fn value(&self, component: &str) -> Option<String> {
+        match component {
+            "\"@status\"" => Some(self.status.to_string()),
+            "\"content-type\"" => Some(self.content_type.trim().to_string()),
+            "\"content-digest\"" => Some(self.content_digest.trim().to_string()),
+            "\"@request-target\";req" => Some(self.request_target.to_string()),
+            _ => None,
+        }
+    }
+
+    // Build the signature base of section 2.5 of RFC 9421.
+    fn signature_base(&self, covered: &[&str], signature_params: &str) -> Result<String, String> {
+        let mut base = String::new();
+        for component in covered {
+            let value = self
+                .value(component)
+                .ok_or_else(|| format!("unsupported component {component}"))?;
+            base.push_str(&format!("{component}: {value}\n"));
+        }
+        base.push_str(&format!("\"@signature-params\": {signature_params}"));
+        Ok(base)
+    }
+}
+
+// The value of the Content-Digest header of a response with the given content.
+pub fn content_digest(content: &[u8]) -> String {
+    format!("sha-256=:{}:", STANDARD.encode(Sha256::digest(content)))
+}
+
+// Check the SHA-256 digest in a Content-Digest header against the content.
+pub fn verify_content_digest(header: &str, content: &[u8]) -> Result<(), String> {
+    let digest = split_members(header)
+        .into_iter()
+        .find_map(|member| member.strip_prefix("sha-256="))
+        .ok_or("the Content-Digest header has no sha-256 digest")?;
+    if digest != content_digest(content).trim_start_matches("sha-256=") {
+        return Err("the content does not match the Content-Digest header".to_string());
+    }
+    Ok(())
+}
+
+// Sign the components of a response, created at the given time in seconds since
+// the Unix epoch, and return the values of the Signature-Input and Signature headers.
+pub fn sign(key: &SigningKey, components: &Components, created: u64) -> (String, String) {
+    let signature_params = format!(
+        "({});created={created};keyid={};alg={}",
+        COVERED.join(" "),
+        quote(key.kid()),
+        quote(algorithm_name(key.algorithm()))
+    );
+    let base = components
+        .signature_base(&COVERED, &signature_params)
+        .expect("covered components are supported");
+    let signature = key.sign_message(base.as_bytes());
+    (
+        format!("{LABEL}={signature_params}"),
+        format!("{LABEL}=:{}:", STANDARD.encode(signature)),
+    )
+}
+
+// Verify the signature of a response, given the values of its Signature-Input and
+// Signature headers, at the given time in seconds since the Unix epoch, and return
+// the ID of the key which signed it.
+pub fn verify(
+    components: &Components,
+    signature_input: &str,
+    signature: &str,
+    jwks: &JwkSet,
+    now: u64,
+) -> Result<String, String> {
+    let signature_params =
+        member(signature_input, LABEL).ok_or(format!("the Signature-Input header has no {LABEL:?} signature"))?;
+    let signature = member(signature, LABEL)
+        .and_then(|value| value.strip_prefix(':')?.strip_suffix(':'))
+        .and_then(|value| STANDARD.decode(value).ok())
+        .ok_or(format!("the Signature header has no valid {LABEL:?} signature"))?;
+
+    let (covered, params) = signature_params
+        .strip_prefix('(')
+        .and_then(|rest| rest.split_once(')'))
+        .ok_or("the signature parameters are malformed")?;
+    let covered: Vec<&str> = covered.split_whitespace().collect();
+    for required in COVERED {
+        if !covered.contains(&required) {
+            return Err(format!("the signature does not cover {required}"));
+        }
+    }
+    let base = components.signature_base(&covered, signature_params)?;
+
+    let params = parse_params(params);
+    let created: u64 = params
+        .iter()
+        .find(|(name, _)| *name == "created")
+        .and_then(|(_, value)| value.parse().ok())
+        .ok_or("the signature has no creation time")?;
+    if created > now + CLOCK_SKEW {
+        return Err(format!("the signature was created in the future, at {created}"));
+    }
+    if now.saturating_sub(created) > MAX_AGE {
+        return Err(format!(
+            "the signature was created {} seconds ago, more than the maximum of {MAX_AGE}",
+            now - created
+        ));
+    }
+    let kid = params
+        .iter()
+        .find(|(name, _)| *name == "keyid")
+        .and_then(|(_, value)| unquote(value))
+        .ok_or("the signature has no key ID")?;
+    let algorithm = match params.iter().find(|(name, _)| *name == "alg") {
+        Some((_, alg)) => {
+            let alg = unquote(alg).unwrap_or_default();
+            algorithm_from_name(&alg).ok_or(format!("unsupported algorithm {alg:?}"))?
+        }
+        // Without an algorithm parameter, the key determines the algorithm.
+        None => jwks
+            .keys
+            .iter()
+            .find(|jwk| jwk.kid.as_deref() == Some(kid.as_str()))
+            .ok_or(format!("no key with ID {kid:?} is published"))?
+            .algorithm()?,
+    };
+    jwks.verify_message(algorithm, Some(&kid), base.as_bytes(), &signature)
+}
+
+// Names of algorithms in the HTTP Signature Algorithms registry.
+fn algorithm_name(algorithm: Algorithm) -> &'static str {
+    match algorithm {
+        Algorithm::EdDSA => "ed25519",
+        Algorithm::ES256 => "ecdsa-p256-sha256",
+        Algorithm::ES384 => "ecdsa-p384-sha384",
+    }
+}
+
+fn algorithm_from_name(name: &str) -> Option<Algorithm> {
+    [Algorithm::EdDSA, Algorithm::ES256, Algorithm::ES384]
+        .into_iter()
+        .find(|algorithm| algorithm_name(*algorithm) == name)
+}
+
+// Serialize a structured field string.
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// Parse a structured field string.
+fn unquote(s: &str) -> Option<String> {
+    let inner = s.strip_prefix('"')?.strip_suffix('"')?;
+    let mut unquoted = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        unquoted.push(if c == '\\' { chars.next()? } else { c });
+    }
+    Some(unquoted)
+}
+
+// The value of a member of a structured field dictionary.
+fn member<'a>(dictionary: &'a str, label: &str) -> Option<&'a str> {
+    split_members(dictionary)
+        .into_iter()
+        .find_map(|member| member.strip_prefix(label)?.strip_prefix('='))
+}
+
+// Split a structured field dictionary into its members, ignoring commas in
+// strings and inner lists.
+fn split_members(dictionary: &str) -> Vec<&str> {
+    split_outside_strings(dictionary, ',')
+}
+
+// Split the parameters of a structured field item, such as ;created=1;keyid="a",
+// into names and values.
+fn parse_params(params: &str) -> Vec<(&str, &str)> {
+    split_outside_strings(params, ';')
+        .into_iter()
+        .filter(|param| !param.is_empty())
+        .map(|param| param.split_once('=').unwrap_or((param, "?1")))
+        .collect()
+}
+
+fn split_outside_strings(s: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut start, mut in_string, mut escaped, mut depth) = (0, false, false, 0);
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '(' if !in_string => depth += 1,
+            ')' if !in_string => depth -= 1,
+            c if c == separator && !in_string && depth == 0 => {
+                parts.push(s[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(s[start..].trim());
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jws;
+    use pretty_assertions::assert_eq;
+
+    const CONTENT: &[u8] = br#"{"subject":"acct:alice@example.com"}"#;
+    const TARGET: &str = "/.well-known/webfinger?resource=acct%3Aalice%40example.com";
+    const CREATED: u64 = 1718000000;
+
+    fn components(content_digest: &str) -> Components<'_> {
+        Components {
+            status: 200,
+            content_type: "application/jrd+json",
+            content_digest,
+            request_target: TARGET,
+        }
+    }
+
+    #[test]
+    fn test_content_digest() {
+        // Example from RFC 9530, appendix B.
+        assert_eq!(
+            content_digest(br#"{"hello": "world"}"#),
+            "sha-256=:X48E9qOokqqrvdts8nOJRJN3OWDUoyWxBf7kbu9DBPE=:"
+        );
+        let digest = content_digest(CONTENT);
+        assert!(verify_content_digest(&digest, CONTENT).is_ok());
+        assert!(verify_content_digest(&format!("sha-512=:AAAA:, {digest}"), CONTENT).is_ok());
+        assert!(verify_content_digest(&digest, b"{}").is_err());
+        assert!(verify_content_digest("sha-512=:AAAA:", CONTENT).is_err());
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        for algorithm in [Algorithm::EdDSA, Algorithm::ES256, Algorithm::ES384] {
+            let key = jws::generate_key(algorithm, None);
+            let digest = content_digest(CONTENT);
+            let (input, signature) = sign(&key, &components(&digest), CREATED);
+
+            assert_eq!(
+                input,
+                format!(
+                    r#"webfinger=("@status" "content-type" "content-digest" "@request-target";req);created={CREATED};keyid="{}";alg="{}""#,
+                    key.kid(),
+                    algorithm_name(algorithm)
+                )
+            );
+            assert_eq!(
+                verify(&components(&digest), &input, &signature, &key.jwk_set(), CREATED).unwrap(),
+                key.kid()
+            );
+
+            // A response for a different resource does not match.
+            let other = Components {
+                request_target: "/.well-known/webfinger?resource=acct%3Abob%40example.com",
+                ..components(&digest)
+            };
+            assert!(verify(&other, &input, &signature, &key.jwk_set(), CREATED).is_err());
+        }
+    }
+
+    #[test]
+    fn test_verify_picks_key_by_id() {
+        let old = jws::generate_key(Algorithm::EdDSA, Some("2023".to_string()));
+        let new = jws::generate_key(Algorithm::EdDSA, Some("2024".to_string()));
+        let jwks = JwkSet {
+            keys: [new.jwk_set().keys, old.jwk_set().keys].concat(),
+        };
+        let digest = content_digest(CONTENT);
+
+        let (input, signature) = sign(&old, &components(&digest), CREATED);
+        assert_eq!(verify(&components(&digest), &input, &signature, &jwks, CREATED).unwrap(), "2023");
+
+        // Once the old key is no longer published, the signature cannot be verified.
+        let err = verify(&components(&digest), &input, &signature, &new.jwk_set(), CREATED).unwrap_err();
+        assert_eq!(err, r#"no EdDSA key with ID "2023" is published"#);
+    }
+
+    #[test]
+    fn test_verify_requires_covered_components() {
+        let key = jws::generate_key(Algorithm::EdDSA, None);
+        let digest = content_digest(CONTENT);
+        let (input, signature) = sign(&key, &components(&digest), CREATED);
+
+        let input = input.replace(r#" "content-digest""#, "");
+        let err = verify(&components(&digest), &input, &signature, &key.jwk_set(), CREATED).unwrap_err();
+        assert_eq!(err, r#"the signature does not cover "content-digest""#);
+    }
+
+    #[test]
+    fn test_verify_checks_creation_time() {
+        let key = jws::generate_key(Algorithm::EdDSA, None);
+        let digest = content_digest(CONTENT);
+        let (input, signature) = sign(&key, &components(&digest), CREATED);
+        let verify_at = |now| verify(&components(&digest), &input, &signature, &key.jwk_set(), now);
+
+        assert!(verify_at(CREATED + MAX_AGE).is_ok());
+        assert!(verify_at(CREATED - CLOCK_SKEW).is_ok());
+        assert_eq!(
+            verify_at(CREATED + MAX_AGE + 1).unwrap_err(),
+            "the signature was created 86401 seconds ago, more than the maximum of 86400"
+        );
+        assert_eq!(
+            verify_at(CREATED - CLOCK_SKEW - 1).unwrap_err(),
+            format!("the signature was created in the future, at {CREATED}")
+        );
+
+        let input = input.replace(&format!(";created={CREATED}"), "");
+        let err = verify(&components(&digest), &input, &signature, &key.jwk_set(), CREATED).unwrap_err();
+        assert_eq!(err, "the signature has no creation time");
+    }
+
+    #[test]
+    fn test_structured_fields() {
+        assert_eq!(
+            split_members(r#"a=("x" "y");keyid="p,q", b=:AB==:"#),
+            vec![r#"a=("x" "y");keyid="p,q""#, "b=:AB==:"]
+        );
+        assert_eq!(parse_params(r#";created=1;keyid="a;b""#), vec![("created", "1"), ("keyid", r#""a;b""#)]);
+        assert_eq!(unquote(&quote(r#"a"b\c"#)).unwrap(), r#"a"b\c"#);
+    }
+}
@@ -0,0 +1,381 @@
+/*
+Copyright 2024 Glyn Normington
+
+This file is part of webfinger-rs.
+
+webfinger-rs is free software: you can redistribute it and/or modify it under the terms
+of the GNU General Public License as published by the Free Software Foundation, either
+version 3 of the License, or (at your option) any later version.
+
+webfinger-rs is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with webfinger-rs.
+If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Integrity checks on JRD map files, so that a map which has been tampered with
+// is never served. A map file may be required to have a detached minisign
+// signature, made with a given Ed25519 public key, in a file alongside it with
+// the extension ".minisig" appended, or to have one of a set of pinned SHA-256
+// digests, or both. The checks are applied to the content of the file exactly as
+// it is read, before the content is parsed.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use blake2::Blake2b512;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use sha2::{Digest, Sha256};
+
+use crate::jrdmap::{self, Format, JrdMap};
+use crate::store::JsonMapStore;
+
+// The signature algorithm identifiers of minisign, which sign either the content
+// itself or, for large files, its BLAKE2b-512 hash.
+const ALG_ED25519: &[u8; 2] = b"Ed";
+const ALG_ED25519_PREHASHED: &[u8; 2] = b"ED";
+
+const TRUSTED_COMMENT: &str = "trusted comment: ";
+
+/* A minisign public key. */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublicKey {
+    key_id: [u8; 8],
+    key: [u8; 32],
+}
+
+impl PublicKey {
+    // The key ID, in the hexadecimal form minisign displays.
+    pub fn key_id(&self) -> String {
+        self.key_id.iter().rev().map(|b| format!("{b:02X}")).collect()
+    }
+}
+
+// Parse a public key, either as written by minisign, with an untrusted comment
+// line followed by the base64 encoded key, or as the base64 encoded key alone.
+impl FromStr for PublicKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PublicKey, String> {
+        let encoded = s
+            .lines()
+            .map(str::trim)
+            .rfind(|line| !line.is_empty() && !line.starts_with("untrusted comment:"))
+            .ok_or("the public key is empty")?;
+        let decoded = STANDARD
+            .decode(encoded)
+            .map_err(|e| format!("the public key is not valid base64: {e}"))?;
+        if decoded.len() != 42 || &decoded[..2] != ALG_ED25519 {
+            return Err("the public key is not a minisign Ed25519 public key".to_string());
+        }
+        Ok(PublicKey {
+            key_id: decoded[2..10].try_into().unwrap(),
+            key: decoded[10..].try_into().unwrap(),
+        })
+    }
+}
+
+/* The integrity checks a JRD map file must pass before it is loaded. */
+#[derive(Clone, Debug, Default)]
+pub struct MapIntegrity {
+    // Public key which must have signed each map file.
+    pub public_key: Option<PublicKey>,
+
+    // SHA-256 digests, one of which each map file must have, unless empty.
+    pub sha256_pins: Vec<[u8; 32]>,
+}
+
+impl MapIntegrity {
+    // Check the content of a map file read from the given path.
+    pub fn verify(&self, path: &Path, content: &[u8]) -> Result<(), String> {
+        if !self.sha256_pins.is_empty() {
+            let digest: [u8; 32] = Sha256::digest(content).into();
+            if !self.sha256_pins.contains(&digest) {
+                return Err(format!("{path:?} does not match a pinned SHA-256 digest (its digest is {})", hex(&digest)));
+            }
+        }
+        if let Some(public_key) = &self.public_key {
+            let signature_path = signature_path(path);
+            let signature = fs::read_to_string(&signature_path)
+                .map_err(|e| format!("Failed to read signature file {signature_path:?}: {e}"))?;
+            verify_signature(public_key, content, &signature).map_err(|e| format!("{path:?} is not validly signed: {e}"))?;
+        }
+        Ok(())
+    }
+}
+
+// The path of the detached signature file of a map file.
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut signature_path = path.as_os_str().to_owned();
+    signature_path.push(".minisig");
+    PathBuf::from(signature_path)
+}
+
+// Parse a SHA-256 digest given in hexadecimal.
+pub fn parse_sha256_pin(s: &str) -> Result<[u8; 32], String> {
+    let s = s.trim();
+    if s.len() != 64 || !s.is_ascii() {
+        return Err("a SHA-256 digest must be 64 hexadecimal digits".to_string());
+    }
+    let mut digest = [0u8; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16)
+            .map_err(|_| "a SHA-256 digest must be 64 hexadecimal digits".to_string())?;
+    }
+    Ok(digest)
+}
+
+// Read a map file, check its integrity, and parse and validate it.
+pub fn read_map(path: &Path, format: Format, integrity: &MapIntegrity) -> Result<JrdMap, String> {
+    let content = fs::read(path).map_err(|e| format!("Failed to read {path:?}: {e}"))?;
+    integrity.verify(path, &content)?;
+    let content = String::from_utf8(content).map_err(|e| format!("Failed to read {path:?}: {e}"))?;
+    let jm = jrdmap::from_str(&content, format).map_err(|e| format!("Failed to parse {path:?}: {e}"))?;
+    jrdmap::validate(&jm).map_err(|e| format!("Invalid JRD map {path:?}: {e}"))?;
+    Ok(jm)
+}
+
+// Reload a map file into a store. If the file fails its integrity checks or is
+// invalid, the store keeps the previous map. Returns whether the map changed.
+pub fn reload(store: &JsonMapStore, path: &Path, format: Format, integrity: &MapIntegrity) -> Result<bool, String> {
+    let jm = read_map(path, format, integrity)?;
+    if jrdmap::digest(&jm) == jrdmap::digest(&store.snapshot()) {
+        return Ok(false);
+    }
+    store.replace(jm);
+    Ok(true)
+}
+
+// Verify a minisign signature file over some content, including the signature
+// over its trusted comment.
+fn verify_signature(public_key: &PublicKey, content: &[u8], signature_file: &str) -> Result<(), String> {
+    let mut lines = signature_file.lines().map(str::trim).filter(|line| !line.is_empty());
+    let (Some(_untrusted_comment), Some(signature), Some(trusted_comment), Some(global_signature)) =
+        (lines.next(), lines.next(), lines.next(), lines.next())
+    else {
+        return Err("the signature file is incomplete".to_string());
+    };
+
+    let signature = STANDARD
+        .decode(signature)
+        .map_err(|e| format!("the signature is not valid base64: {e}"))?;
+    if signature.len() != 74 {
+        return Err("the signature is not a minisign signature".to_string());
+    }
+    let (algorithm, rest) = signature.split_at(2);
+    let (key_id, signature) = rest.split_at(8);
+    if key_id != public_key.key_id {
+        return Err(format!("the signature was not made with the key {}", public_key.key_id()));
+    }
+    let message = match algorithm {
+        a if a == ALG_ED25519_PREHASHED => Blake2b512::digest(content).to_vec(),
+        a if a == ALG_ED25519 => content.to_vec(),
+        _ => return Err("the signature algorithm is not supported".to_string()),
+    };
+    let key = UnparsedPublicKey::new(&ED25519, public_key.key);
+    key.verify(&message, signature)
+        .map_err(|_| "the signature does not match the content".to_string())?;
+
+    let trusted_comment = trusted_comment
+        .strip_prefix(TRUSTED_COMMENT)
+        .ok_or("the signature file has no trusted comment")?;
+    let global_signature = STANDARD
+        .decode(global_signature)
+        .map_err(|e| format!("the trusted comment signature is not valid base64: {e}"))?;
+    key.verify(&[signature, trusted_comment.as_bytes()].concat(), &global_signature)
+        .map_err(|_| "the signature does not match the trusted comment".to_string())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::JrdStore;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    const MAP: &str = r#"{"acct:alice@example.com": {"subject": "acct:alice@example.com"}}"#;
+    const KEY_ID: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+    fn generate_key() -> (Ed25519KeyPair, PublicKey) {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let public_key = PublicKey {
+            key_id: KEY_ID,
+            key: key_pair.public_key().as_ref().try_into().unwrap(),
+        };
+        (key_pair, public_key)
+    }
+
+    // Sign some content as minisign does.
+    fn sign(key_pair: &Ed25519KeyPair, content: &[u8], prehashed: bool) -> String {
+        let (algorithm, message) = if prehashed {
+            (ALG_ED25519_PREHASHED, Blake2b512::digest(content).to_vec())
+        } else {
+            (ALG_ED25519, content.to_vec())
+        };
+        let signature = key_pair.sign(&message);
+        let trusted_comment = "timestamp:1718000000\tfile:map.json";
+        let global_signature = key_pair.sign(&[signature.as_ref(), trusted_comment.as_bytes()].concat());
+        format!(
+            "untrusted comment: signature from minisign secret key\n{}\n{TRUSTED_COMMENT}{trusted_comment}\n{}\n",
+            STANDARD.encode([algorithm.as_slice(), &KEY_ID, signature.as_ref()].concat()),
+            STANDARD.encode(global_signature),
+        )
+    }
+
+    fn setup(key_pair: &Ed25519KeyPair) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map.json");
+        fs::write(&path, MAP).unwrap();
+        fs::write(signature_path(&path), sign(key_pair, MAP.as_bytes(), true)).unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn test_parse_public_key() {
+        let encoded = STANDARD.encode([ALG_ED25519.as_slice(), &KEY_ID, &[9; 32]].concat());
+        let expected = PublicKey {
+            key_id: KEY_ID,
+            key: [9; 32],
+        };
+        assert_eq!(encoded.parse::<PublicKey>().unwrap(), expected);
+        let file = format!("untrusted comment: minisign public key 0807060504030201\n{encoded}\n");
+        assert_eq!(file.parse::<PublicKey>().unwrap(), expected);
+        assert_eq!(expected.key_id(), "0807060504030201");
+
+        assert!("".parse::<PublicKey>().is_err());
+        assert!("not base64!".parse::<PublicKey>().is_err());
+        let encoded = STANDARD.encode([b"ED".as_slice(), &KEY_ID, &[9; 32]].concat());
+        assert!(encoded.parse::<PublicKey>().is_err());
+    }
+
+    #[test]
+    fn test_parse_sha256_pin() {
+        let digest = parse_sha256_pin(&"0a".repeat(32)).unwrap();
+        assert_eq!(digest, [10; 32]);
+        assert_eq!(parse_sha256_pin(&"0A".repeat(32)).unwrap(), digest);
+        assert!(parse_sha256_pin(&"0a".repeat(31)).is_err());
+        assert!(parse_sha256_pin(&"zz".repeat(32)).is_err());
+        assert!(parse_sha256_pin(&"é".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let (key_pair, public_key) = generate_key();
+        for prehashed in [true, false] {
+            let signature = sign(&key_pair, MAP.as_bytes(), prehashed);
+            verify_signature(&public_key, MAP.as_bytes(), &signature).unwrap();
+            assert_eq!(
+                verify_signature(&public_key, b"{}", &signature).unwrap_err(),
+                "the signature does not match the content"
+            );
+        }
+    }
+
+    #[test]
+    fn test_verify_signature_rejects_altered_trusted_comment() {
+        let (key_pair, public_key) = generate_key();
+        let signature = sign(&key_pair, MAP.as_bytes(), true).replace("file:map.json", "file:other.json");
+        assert_eq!(
+            verify_signature(&public_key, MAP.as_bytes(), &signature).unwrap_err(),
+            "the signature does not match the trusted comment"
+        );
+    }
+
+    #[test]
+    fn test_verify_signature_rejects_other_key() {
+        let (key_pair, _) = generate_key();
+        let (_, mut other_key) = generate_key();
+        let signature = sign(&key_pair, MAP.as_bytes(), true);
+        assert_eq!(
+            verify_signature(&other_key, MAP.as_bytes(), &signature).unwrap_err(),
+            "the signature does not match the content"
+        );
+        other_key.key_id = [0; 8];
+        assert_eq!(
+            verify_signature(&other_key, MAP.as_bytes(), &signature).unwrap_err(),
+            "the signature was not made with the key 0000000000000000"
+        );
+    }
+
+    #[test]
+    fn test_read_map() {
+        let (key_pair, public_key) = generate_key();
+        let (_dir, path) = setup(&key_pair);
+        let pin: [u8; 32] = Sha256::digest(MAP).into();
+
+        for integrity in [
+            MapIntegrity::default(),
+            MapIntegrity {
+                public_key: Some(public_key.clone()),
+                sha256_pins: vec![],
+            },
+            MapIntegrity {
+                public_key: Some(public_key.clone()),
+                sha256_pins: vec![[0; 32], pin],
+            },
+        ] {
+            let jm = read_map(&path, Format::Json, &integrity).unwrap();
+            assert!(jm.contains_key("acct:alice@example.com"));
+        }
+    }
+
+    #[test]
+    fn test_read_map_rejects_unpinned_map() {
+        let (key_pair, _) = generate_key();
+        let (_dir, path) = setup(&key_pair);
+        let integrity = MapIntegrity {
+            public_key: None,
+            sha256_pins: vec![[0; 32]],
+        };
+        let error = read_map(&path, Format::Json, &integrity).unwrap_err();
+        assert!(error.contains("does not match a pinned SHA-256 digest"), "{error}");
+    }
+
+    #[test]
+    fn test_read_map_rejects_unsigned_map() {
+        let (key_pair, public_key) = generate_key();
+        let (_dir, path) = setup(&key_pair);
+        fs::remove_file(signature_path(&path)).unwrap();
+        let integrity = MapIntegrity {
+            public_key: Some(public_key),
+            sha256_pins: vec![],
+        };
+        let error = read_map(&path, Format::Json, &integrity).unwrap_err();
+        assert!(error.starts_with("Failed to read signature file"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let (key_pair, public_key) = generate_key();
+        let (_dir, path) = setup(&key_pair);
+        let integrity = MapIntegrity {
+            public_key: Some(public_key),
+            sha256_pins: vec![],
+        };
+        let store = JsonMapStore::new(read_map(&path, Format::Json, &integrity).unwrap());
+        assert!(!reload(&store, &path, Format::Json, &integrity).unwrap());
+
+        // A tampered map is refused and the previous map continues to be served.
+        let tampered = MAP.replace("alice", "mallory");
+        fs::write(&path, &tampered).unwrap();
+        let error = reload(&store, &path, Format::Json, &integrity).unwrap_err();
+        assert!(error.contains("is not validly signed"), "{error}");
+        assert!(store.lookup("acct:alice@example.com").await.unwrap().is_some());
+        assert!(store.lookup("acct:mallory@example.com").await.unwrap().is_none());
+
+        // Once the map is signed, it is loaded.
+        fs::write(signature_path(&path), sign(&key_pair, tampered.as_bytes(), true)).unwrap();
+        assert!(reload(&store, &path, Format::Json, &integrity).unwrap());
+        assert!(store.lookup("acct:mallory@example.com").await.unwrap().is_some());
+    }
+}
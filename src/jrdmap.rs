@@ -8,19 +8,24 @@ licensed under the GPL.
 
 */
 
-use std::collections::hash_map::HashMap;
+use std::collections::{BTreeMap, HashSet};
 use std::option::Option;
+use std::path::Path;
 
-use serde;
-use serde_json;
+use sha2::{Digest, Sha256};
 
+use fluent_uri::Uri;
+
+use crate::acct::{self, AcctUri};
+use crate::language;
 use crate::rel::{Rel, make_rel};
 
 /* A JrdMap maps string URIs to the JSON Resource Descriptors associated
-with those URIs. */
-pub type JrdMap = HashMap<String, Jrd>;
+with those URIs. Maps are ordered by key throughout, so that serialization is
+deterministic. */
+pub type JrdMap = BTreeMap<String, Jrd>;
 
-#[derive(Clone, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct Jrd {
     // The value of the "subject" member is a URI that identifies the entity
     // that the JRD describes.
@@ -36,18 +41,23 @@ pub struct Jrd {
     // values are strings or null. Properties are used to convey additional
     // information about the subject of the JRD.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub properties: Option<HashMap<String, String>>,
+    pub properties: Option<BTreeMap<String, String>>,
 
     // The "links" array has any number of member objects, each of which
     // represents a link [4].
     #[serde(skip_serializing_if = "Option::is_none")]
     pub links: Option<Vec<ResourceLink>>,
+
+    // Which clients may see the JRD. This is not part of RFC 7033 and is never
+    // included in responses.
+    #[serde(default, skip_serializing_if = "Visibility::is_public")]
+    pub visibility: Visibility,
 }
 
 impl Jrd {
     // Filter the links to include only those with the specified rel values
     pub fn filter(&self, rel: Vec<String>) -> Jrd {
-        let rels: Vec<Rel> = rel.iter().map(|r| make_rel(r.clone())).collect();
+        let rels: HashSet<Rel> = rel.into_iter().map(make_rel).collect();
         Jrd {
             subject: self.subject.clone(),
             aliases: self.aliases.clone(),
@@ -57,11 +67,44 @@ impl Jrd {
                     filter(|lk| rels.contains(&lk.rel)).
                     collect()
             }),
+            visibility: self.visibility.clone(),
         }
     }
+
+    // The part of the JRD which the given principal, or an anonymous client if
+    // principal is None, may see: None if the JRD itself is hidden, otherwise the
+    // JRD without any hidden links.
+    pub fn visible_to(&self, principal: Option<&str>) -> Option<Jrd> {
+        if !self.visibility.allows(principal) {
+            return None;
+        }
+        let mut jrd = self.clone();
+        jrd.visibility = Visibility::Public;
+        if let Some(links) = jrd.links.as_mut() {
+            links.retain(|lk| lk.visibility.allows(principal));
+            for lk in links.iter_mut() {
+                lk.visibility = Visibility::Public;
+            }
+        }
+        Some(jrd)
+    }
 }
 
-#[derive(Clone, serde::Deserialize, serde::Serialize)]
+impl Jrd {
+    // Reduce the titles of each link to the one in the language which best matches
+    // the language ranges, if any.
+    pub fn select_titles(mut self, ranges: &[String]) -> Jrd {
+        for titles in self.links.iter_mut().flatten().filter_map(|lk| lk.titles.as_mut()) {
+            let tags: Vec<&str> = titles.keys().map(String::as_str).collect();
+            if let Some(tag) = language::lookup(ranges, &tags).map(str::to_string) {
+                titles.retain(|t, _| *t == tag);
+            }
+        }
+        self
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct ResourceLink {
     // Each of these link objects can have the following members:
     //         o rel
@@ -76,8 +119,6 @@ pub struct ResourceLink {
     // type.  The URI or registered relation type identifies the type of the
     // link relation.
     // The "rel" member MUST be present in the link relation object.
-    //#[serde(serialize_with = "serialize_rel", deserialize_with = "deserialize_rel")]
-    #[serde(flatten)]
     pub rel: Rel,
 
     // The value of the "type" member is a string that indicates the media
@@ -98,7 +139,7 @@ pub struct ResourceLink {
     // be duly used as the name.  If the language is unknown or unspecified,
     // then the name is "und".
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub titles: Option<HashMap<String, String>>,
+    pub titles: Option<BTreeMap<String, String>>,
 
     // The "properties" object within the link relation object comprises
     // zero or more name/value pairs whose names are URIs (referred to as
@@ -106,13 +147,498 @@ pub struct ResourceLink {
     // Properties are used to convey additional information about the link
     // relation.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub properties: Option<HashMap<String, String>>,
+    pub properties: Option<BTreeMap<String, String>>,
+
+    // Which clients may see the link.
+    #[serde(default, skip_serializing_if = "Visibility::is_public")]
+    pub visibility: Visibility,
+}
+
+/* Visibility determines which clients may see a JRD or link. It is written as
+"public" (the default), "private" (visible to any authenticated principal), or
+a list of the names of the principals which may see it. */
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(try_from = "VisibilityRepr", into = "VisibilityRepr")]
+pub enum Visibility {
+    #[default]
+    Public,
+    Private,
+    Restricted(Vec<String>),
+}
+
+impl Visibility {
+    pub fn is_public(&self) -> bool {
+        *self == Visibility::Public
+    }
+
+    // Determine whether the given principal, or an anonymous client if principal
+    // is None, may see something with this visibility.
+    pub fn allows(&self, principal: Option<&str>) -> bool {
+        match self {
+            Visibility::Public => true,
+            Visibility::Private => principal.is_some(),
+            Visibility::Restricted(names) => {
+                principal.is_some_and(|p| names.iter().any(|name| name == p))
+            }
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(untagged)]
+enum VisibilityRepr {
+    Name(String),
+    Principals(Vec<String>),
+}
+
+impl TryFrom<VisibilityRepr> for Visibility {
+    type Error = String;
+
+    fn try_from(repr: VisibilityRepr) -> Result<Visibility, String> {
+        match repr {
+            VisibilityRepr::Name(name) => match name.as_str() {
+                "public" => Ok(Visibility::Public),
+                "private" => Ok(Visibility::Private),
+                _ => Err(format!(
+                    "invalid visibility {name:?}: expected \"public\", \"private\" or a list of principals"
+                )),
+            },
+            VisibilityRepr::Principals(names) => Ok(Visibility::Restricted(names)),
+        }
+    }
+}
+
+impl From<Visibility> for VisibilityRepr {
+    fn from(visibility: Visibility) -> VisibilityRepr {
+        match visibility {
+            Visibility::Public => VisibilityRepr::Name("public".to_string()),
+            Visibility::Private => VisibilityRepr::Name("private".to_string()),
+            Visibility::Restricted(names) => VisibilityRepr::Principals(names),
+        }
+    }
+}
+
+// The file formats in which JRDs and JRD maps may be written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    // Determine the format of a file from its extension.
+    pub fn from_path(path: &Path) -> Option<Format> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "json" => Some(Format::Json),
+            "yaml" | "yml" => Some(Format::Yaml),
+            "toml" => Some(Format::Toml),
+            _ => None,
+        }
+    }
+}
+
+pub fn jrd_from_str(s: &str, format: Format) -> Result<Jrd, String> {
+    match format {
+        Format::Json => serde_json::from_str(s).map_err(|e| e.to_string()),
+        Format::Yaml => serde_yaml::from_str(s).map_err(|e| e.to_string()),
+        Format::Toml => toml::from_str(s).map_err(|e| e.to_string()),
+    }
 }
 
 pub fn to_json(resource: &Jrd) -> String {
     serde_json::to_string(&resource).unwrap()
 }
 
+pub fn to_json_pretty(jm: &JrdMap) -> String {
+    serde_json::to_string_pretty(jm).unwrap()
+}
+
+#[allow(clippy::ptr_arg)]
 pub fn from_json(s: &String) -> JrdMap {
     serde_json::from_str(s).unwrap()
 }
+
+pub fn from_str(s: &str, format: Format) -> Result<JrdMap, String> {
+    match format {
+        Format::Json => serde_json::from_str(s).map_err(|e| e.to_string()),
+        Format::Yaml => serde_yaml::from_str(s).map_err(|e| e.to_string()),
+        Format::Toml => toml::from_str(s).map_err(|e| e.to_string()),
+    }
+}
+
+// Serialize a JRD map in a human-readable form. This is the canonical form of a
+// JRD map file: map keys are sorted and the members of each JRD and link are in
+// the order in which RFC 7033 defines them.
+pub fn to_string(jm: &JrdMap, format: Format) -> Result<String, String> {
+    match format {
+        Format::Json => serde_json::to_string_pretty(jm)
+            .map(|s| s + "\n")
+            .map_err(|e| e.to_string()),
+        Format::Yaml => serde_yaml::to_string(jm).map_err(|e| e.to_string()),
+        Format::Toml => toml::to_string_pretty(jm).map_err(|e| e.to_string()),
+    }
+}
+
+// Rewrite the content of a JRD map file in canonical form, after checking that
+// the map is valid.
+pub fn format_map(s: &str, format: Format) -> Result<String, String> {
+    let jm = from_str(s, format)?;
+    validate(&jm)?;
+    to_string(&jm, format)
+}
+
+// Serialize a value as canonical JSON: compact, with the members of every object
+// sorted by name.
+pub fn to_canonical_json<T: serde::Serialize>(value: &T) -> String {
+    // serde_json::Value sorts object keys.
+    serde_json::to_value(value).unwrap().to_string()
+}
+
+pub fn valid_uri(uri: &str) -> bool {
+    match Uri::parse(uri) {
+        Ok(uri_reference) => uri_reference.has_scheme(),
+        Err(_) => false,
+    }
+}
+
+// Check that every entry of a JRD map is valid.
+pub fn validate(jm: &JrdMap) -> Result<(), String> {
+    for (uri, jrd) in jm {
+        validate_entry(uri, jrd)?;
+    }
+    Ok(())
+}
+
+// Check that a map entry's key and JRD are made up of valid URIs and relation types.
+// A key which is an acct URI must be in normal form, with the host in lower case,
+// since queries are looked up in normal form.
+pub fn validate_entry(uri: &str, jrd: &Jrd) -> Result<(), String> {
+    check_uri(uri).map_err(|e| format!("resource {uri:?} {e}"))?;
+    if let Ok(acct) = AcctUri::parse(uri) {
+        if acct.to_string() != uri {
+            return Err(format!("resource {uri:?} is not in normal form (\"{acct}\")"));
+        }
+    }
+    validate_jrd(uri, jrd)
+}
+
+// Check that the JRD of a resource is made up of valid URIs and relation types.
+pub fn validate_jrd(uri: &str, jrd: &Jrd) -> Result<(), String> {
+    check_uri(&jrd.subject)
+        .map_err(|e| format!("subject {:?} of resource {uri:?} {e}", jrd.subject))?;
+    for alias in jrd.aliases.iter().flatten() {
+        check_uri(alias).map_err(|e| format!("alias {alias:?} of resource {uri:?} {e}"))?;
+    }
+    for link in jrd.links.iter().flatten() {
+        if link.rel.as_str().is_empty() {
+            return Err(format!("link of resource {uri:?} has an empty \"rel\""));
+        }
+        if let Some(href) = &link.href {
+            check_uri(href).map_err(|e| format!("link href {href:?} of resource {uri:?} {e}"))?;
+        }
+    }
+    Ok(())
+}
+
+// Check that a string is a valid URI and, if it has the "acct" scheme, that it is
+// a valid acct URI.
+fn check_uri(uri: &str) -> Result<(), String> {
+    if !valid_uri(uri) {
+        return Err("is not a valid URI".to_string());
+    }
+    if acct::has_acct_scheme(uri) {
+        AcctUri::parse(uri).map_err(|e| format!("is not a valid acct URI: {e}"))?;
+    }
+    Ok(())
+}
+
+// List the link relation types in a JRD map which are neither URIs nor registered
+// in the IANA Link Relations registry, and so may be misspelled.
+pub fn unregistered_rels(jm: &JrdMap) -> Vec<String> {
+    let mut warnings: Vec<String> = jm
+        .iter()
+        .flat_map(|(uri, jrd)| {
+            jrd.links
+                .iter()
+                .flatten()
+                .filter(|link| !link.rel.is_extension() && !link.rel.is_registered())
+                .map(move |link| {
+                    format!(
+                        "link rel {:?} of resource {uri:?} is neither a URI nor a registered relation type",
+                        link.rel.as_str()
+                    )
+                })
+        })
+        .collect();
+    warnings.sort();
+    warnings
+}
+
+// Compute a SHA-256 digest of the map which identifies its content independently
+// of the ordering of its keys.
+pub fn digest(jm: &JrdMap) -> String {
+    let canonical = to_canonical_json(jm);
+    format!("sha256:{:x}", Sha256::digest(canonical.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_uri() {
+        assert!(!valid_uri(""));
+        assert!(!valid_uri("alice@example.org"));
+        assert!(valid_uri("acct:alice@example.org"));
+    }
+
+    #[test]
+    fn test_validate_accepts_valid_map() {
+        let jm = from_json(&r#"{"acct:a@example.com":{"subject":"acct:a@example.com","aliases":["https://example.com/a"],"links":[{"rel":"self","href":"https://example.com/users/a"}]}}"#.to_string());
+        assert_eq!(validate(&jm), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_rejects_invalid_key() {
+        let jm = from_json(&r#"{"a@example.com":{"subject":"acct:a@example.com"}}"#.to_string());
+        assert!(validate(&jm).is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_acct_key_not_in_normal_form() {
+        let jm = from_json(&r#"{"acct:alice@Example.com":{"subject":"acct:alice@Example.com"}}"#.to_string());
+        assert_eq!(
+            validate(&jm),
+            Err(r#"resource "acct:alice@Example.com" is not in normal form ("acct:alice@example.com")"#.to_string())
+        );
+        let jm = from_json(&r#"{"acct:alice@example.com":{"subject":"acct:alice@Example.com"}}"#.to_string());
+        assert_eq!(validate(&jm), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_rejects_invalid_subject() {
+        let jm = from_json(&r#"{"acct:a@example.com":{"subject":"a@example.com"}}"#.to_string());
+        assert!(validate(&jm).is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_invalid_acct_key() {
+        let jm = from_json(&r#"{"acct:@example.com":{"subject":"acct:a@example.com"}}"#.to_string());
+        assert_eq!(
+            validate(&jm),
+            Err("resource \"acct:@example.com\" is not a valid acct URI: empty userpart".to_string())
+        );
+    }
+
+    #[test]
+    fn test_validate_rejects_invalid_acct_alias() {
+        let jm = from_json(&r#"{"acct:a@example.com":{"subject":"acct:a@example.com","aliases":["acct:a@b@c"]}}"#.to_string());
+        assert!(validate(&jm).is_err());
+    }
+
+    #[test]
+    fn test_unregistered_rels() {
+        let jm = from_json(&r#"{"acct:a@example.com":{"subject":"acct:a@example.com","links":[{"rel":"self"},{"rel":"avtar"},{"rel":"http://webfinger.net/rel/avatar"}]}}"#.to_string());
+        assert_eq!(
+            unregistered_rels(&jm),
+            vec!["link rel \"avtar\" of resource \"acct:a@example.com\" is neither a URI nor a registered relation type".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_validate_rejects_empty_rel() {
+        let jm = from_json(&r#"{"acct:a@example.com":{"subject":"acct:a@example.com","links":[{"rel":""}]}}"#.to_string());
+        assert!(validate(&jm).is_err());
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(Format::from_path(Path::new("a/b.json")), Some(Format::Json));
+        assert_eq!(Format::from_path(Path::new("b.YAML")), Some(Format::Yaml));
+        assert_eq!(Format::from_path(Path::new("b.yml")), Some(Format::Yaml));
+        assert_eq!(Format::from_path(Path::new("b.toml")), Some(Format::Toml));
+        assert_eq!(Format::from_path(Path::new("b.txt")), None);
+        assert_eq!(Format::from_path(Path::new("b")), None);
+    }
+
+    #[test]
+    fn test_jrd_from_str_formats() {
+        let expected = serde_json::json!({
+            "subject": "acct:a@example.com",
+            "links": [{"rel": "self", "href": "https://example.com/a"}]
+        });
+        let json = r#"{"subject":"acct:a@example.com","links":[{"rel":"self","href":"https://example.com/a"}]}"#;
+        let yaml = "subject: acct:a@example.com\nlinks:\n  - rel: self\n    href: https://example.com/a\n";
+        let toml = "subject = \"acct:a@example.com\"\n\n[[links]]\nrel = \"self\"\nhref = \"https://example.com/a\"\n";
+        for (s, format) in [(json, Format::Json), (yaml, Format::Yaml), (toml, Format::Toml)] {
+            let jrd = jrd_from_str(s, format).unwrap();
+            assert_eq!(serde_json::to_value(&jrd).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_map_round_trip_through_all_formats() {
+        let jm = from_json(&r#"
+            {
+                "acct:alice@example.com":{
+                    "subject": "acct:alice@example.com",
+                    "aliases": ["https://example.com/alice"],
+                    "properties": {"http://example.com/ns/role": "admin"},
+                    "links": [
+                        {
+                            "rel": "http://webfinger.net/rel/avatar",
+                            "type": "image/jpeg",
+                            "href": "https://example.com/data/alice-avatar.jpeg",
+                            "titles": {"en": "Avatar", "und": "Avatar"},
+                            "properties": {"http://example.com/ns/size": "large"}
+                        },
+                        {
+                            "rel": "self",
+                            "href": "https://example.com/users/alice"
+                        }
+                    ]
+                },
+                "acct:bob@example.com":{
+                    "subject": "acct:bob@example.com"
+                }
+            }"#.to_string());
+        let expected = serde_json::to_value(&jm).unwrap();
+
+        let mut current = jm;
+        for format in [Format::Yaml, Format::Toml, Format::Json] {
+            let s = to_string(&current, format).unwrap();
+            current = from_str(&s, format).unwrap();
+            assert_eq!(serde_json::to_value(&current).unwrap(), expected, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn test_from_str_reports_errors() {
+        assert!(from_str("{", Format::Json).is_err());
+        assert!(from_str("- a", Format::Yaml).is_err());
+        assert!(from_str("a = ", Format::Toml).is_err());
+    }
+
+    #[test]
+    fn test_digest_independent_of_key_order() {
+        let a = from_json(&r#"{"acct:a@example.com":{"subject":"acct:a@example.com"},"acct:b@example.com":{"subject":"acct:b@example.com"}}"#.to_string());
+        let b = from_json(&r#"{"acct:b@example.com":{"subject":"acct:b@example.com"},"acct:a@example.com":{"subject":"acct:a@example.com"}}"#.to_string());
+        assert_eq!(digest(&a), digest(&b));
+    }
+
+    #[test]
+    fn test_digest_depends_on_content() {
+        let a = from_json(&r#"{"acct:a@example.com":{"subject":"acct:a@example.com"}}"#.to_string());
+        let b = from_json(&r#"{"acct:a@example.com":{"subject":"acct:b@example.com"}}"#.to_string());
+        assert_ne!(digest(&a), digest(&b));
+    }
+
+    #[test]
+    fn test_format_map() {
+        let s = r#"{"acct:b@example.com":{"links":[{"titles":{"fr":"Moi","en":"Me"},"href":"https://example.com/b","rel":"me"}],"subject":"acct:b@example.com"},"acct:a@example.com":{"properties":{"http://z.example/":"z","http://a.example/":"a"},"subject":"acct:a@example.com"}}"#;
+        let expected = r#"{
+  "acct:a@example.com": {
+    "subject": "acct:a@example.com",
+    "properties": {
+      "http://a.example/": "a",
+      "http://z.example/": "z"
+    }
+  },
+  "acct:b@example.com": {
+    "subject": "acct:b@example.com",
+    "links": [
+      {
+        "rel": "me",
+        "href": "https://example.com/b",
+        "titles": {
+          "en": "Me",
+          "fr": "Moi"
+        }
+      }
+    ]
+  }
+}
+"#;
+        assert_eq!(format_map(s, Format::Json).unwrap(), expected);
+        assert_eq!(format_map(expected, Format::Json).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_map_rejects_invalid_map() {
+        assert!(format_map(r#"{"a@example.com":{"subject":"acct:a@example.com"}}"#, Format::Json).is_err());
+    }
+
+    #[test]
+    fn test_select_titles() {
+        let jm = from_json(&r#"{"acct:a@example.com":{"subject":"acct:a@example.com","links":[{"rel":"me","titles":{"en":"Me","fr":"Moi"}},{"rel":"author","titles":{"de":"Autor","und":"Author"}},{"rel":"self"}]}}"#.to_string());
+        let jrd = jm["acct:a@example.com"]
+            .clone()
+            .select_titles(&["fr".to_string()]);
+        assert_eq!(
+            to_canonical_json(&jrd),
+            r#"{"links":[{"rel":"me","titles":{"fr":"Moi"}},{"rel":"author","titles":{"und":"Author"}},{"rel":"self"}],"subject":"acct:a@example.com"}"#
+        );
+    }
+
+    #[test]
+    fn test_to_canonical_json() {
+        let jm = from_json(&r#"{"acct:a@example.com":{"subject":"acct:a@example.com","links":[{"rel":"me","href":"https://example.com/a","titles":{"fr":"Moi","en":"Me"}}]}}"#.to_string());
+        assert_eq!(
+            to_canonical_json(&jm["acct:a@example.com"]),
+            r#"{"links":[{"href":"https://example.com/a","rel":"me","titles":{"en":"Me","fr":"Moi"}}],"subject":"acct:a@example.com"}"#
+        );
+    }
+
+    #[test]
+    fn test_visible_to() {
+        let jm = from_json(&r#"{"acct:a@example.com":{"subject":"acct:a@example.com","links":[{"rel":"self"},{"rel":"me","visibility":"private"},{"rel":"author","visibility":["partner"]}]}}"#.to_string());
+        let jrd = &jm["acct:a@example.com"];
+        let rels = |principal| -> Vec<String> {
+            jrd.visible_to(principal)
+                .unwrap()
+                .links
+                .unwrap()
+                .iter()
+                .map(|lk| lk.rel.to_string())
+                .collect()
+        };
+        assert_eq!(rels(None), ["self"]);
+        assert_eq!(rels(Some("other")), ["self", "me"]);
+        assert_eq!(rels(Some("partner")), ["self", "me", "author"]);
+        assert_eq!(
+            to_canonical_json(&jrd.visible_to(Some("partner")).unwrap()),
+            r#"{"links":[{"rel":"self"},{"rel":"me"},{"rel":"author"}],"subject":"acct:a@example.com"}"#
+        );
+    }
+
+    #[test]
+    fn test_visible_to_hidden_jrd() {
+        let jm = from_json(&r#"{"acct:a@example.com":{"subject":"acct:a@example.com","visibility":"private"},"acct:b@example.com":{"subject":"acct:b@example.com","visibility":["partner"]}}"#.to_string());
+        assert!(jm["acct:a@example.com"].visible_to(None).is_none());
+        assert!(jm["acct:a@example.com"].visible_to(Some("other")).is_some());
+        assert!(jm["acct:b@example.com"].visible_to(Some("other")).is_none());
+        assert!(jm["acct:b@example.com"].visible_to(Some("partner")).is_some());
+    }
+
+    #[test]
+    fn test_visibility_formats() {
+        let toml = r#"
+            ["acct:a@example.com"]
+            subject = "acct:a@example.com"
+            visibility = ["partner"]
+
+            [["acct:a@example.com".links]]
+            rel = "self"
+            visibility = "private"
+        "#;
+        let jm = from_str(toml, Format::Toml).unwrap();
+        let jrd = &jm["acct:a@example.com"];
+        assert_eq!(jrd.visibility, Visibility::Restricted(vec!["partner".to_string()]));
+        assert_eq!(jrd.links.as_ref().unwrap()[0].visibility, Visibility::Private);
+        assert_eq!(from_str(&to_string(&jm, Format::Toml).unwrap(), Format::Toml).unwrap()["acct:a@example.com"].visibility, jrd.visibility);
+
+        assert!(from_str(r#"{"acct:a@example.com":{"subject":"acct:a@example.com","visibility":"secret"}}"#, Format::Json).is_err());
+    }
+}
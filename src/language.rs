@@ -0,0 +1,99 @@
+/*
+Copyright 2024 Glyn Normington
+
+This file is part of webfinger-rs.
+
+webfinger-rs is free software: you can redistribute it and/or modify it under the terms
+of the GNU General Public License as published by the Free Software Foundation, either
+version 3 of the License, or (at your option) any later version.
+
+webfinger-rs is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with webfinger-rs.
+If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Selection of a language tag according to a client's language preferences.
+
+// The language tag of a title whose language is unknown or unspecified.
+pub const UNDETERMINED: &str = "und";
+
+// Parse the value of an Accept-Language header into language ranges in descending
+// order of preference. Ranges with quality zero are omitted.
+pub fn parse_accept_language(header: &str) -> Vec<String> {
+    let mut ranges: Vec<(String, f32)> = header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let range = parts.next()?.trim();
+            if range.is_empty() {
+                return None;
+            }
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            (quality > 0.0).then(|| (range.to_string(), quality))
+        })
+        .collect();
+    // A stable sort keeps ranges of equal quality in the order given.
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ranges.into_iter().map(|(range, _)| range).collect()
+}
+
+// Choose the tag which best matches the language ranges using the "lookup" scheme
+// of RFC 4647, section 3.4, falling back to "und" if no tag matches.
+pub fn lookup<'a>(ranges: &[String], tags: &[&'a str]) -> Option<&'a str> {
+    let find = |range: &str| tags.iter().copied().find(|tag| tag.eq_ignore_ascii_case(range));
+    for range in ranges {
+        if range == "*" {
+            continue;
+        }
+        // Progressively truncate the range until it matches a tag, also removing any
+        // single-character subtag which would be left at the end.
+        let mut range = range.as_str();
+        loop {
+            if let Some(tag) = find(range) {
+                return Some(tag);
+            }
+            match range.rfind('-') {
+                Some(i) => {
+                    range = &range[..i];
+                    if range.len() >= 2 && range.as_bytes()[range.len() - 2] == b'-' {
+                        range = &range[..range.len() - 2];
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+    find(UNDETERMINED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_accept_language() {
+        assert_eq!(
+            parse_accept_language("fr-CH, fr;q=0.9, en;q=0.8, de;q=0.7, *;q=0.5"),
+            vec!["fr-CH", "fr", "en", "de", "*"]
+        );
+        assert_eq!(parse_accept_language("en;q=0.5, de, fr;q=0"), vec!["de", "en"]);
+        assert!(parse_accept_language("").is_empty());
+    }
+
+    #[test]
+    fn test_lookup() {
+        let tags = ["en", "fr", "zh-Hant", "und"];
+        let ranges = |header| parse_accept_language(header);
+        assert_eq!(lookup(&ranges("fr-CH, en;q=0.5"), &tags), Some("fr"));
+        assert_eq!(lookup(&ranges("DE, EN"), &tags), Some("en"));
+        assert_eq!(lookup(&ranges("zh-Hant-CN-x-private1"), &tags), Some("zh-Hant"));
+        assert_eq!(lookup(&ranges("de"), &tags), Some("und"));
+        assert_eq!(lookup(&ranges("*"), &tags), Some("und"));
+        assert_eq!(lookup(&ranges("de"), &["en", "fr"]), None);
+    }
+}
@@ -0,0 +1,60 @@
+/*
+Copyright 2024 Glyn Normington
+
+This file is part of webfinger-rs.
+
+webfinger-rs is free software: you can redistribute it and/or modify it under the terms
+of the GNU General Public License as published by the Free Software Foundation, either
+version 3 of the License, or (at your option) any later version.
+
+webfinger-rs is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with webfinger-rs.
+If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! WebFinger (RFC 7033) data model, JRD map parsing and serialization, JRD
+//! stores, and, depending on the enabled features, an axum router serving
+//! WebFinger requests and a WebFinger client.
+
+pub mod acct;
+pub mod jrdmap;
+pub mod language;
+mod link_relations;
+pub mod rel;
+pub mod store;
+
+#[cfg(feature = "server")]
+pub mod access;
+#[cfg(feature = "server")]
+pub mod admin;
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "server")]
+pub mod compression;
+#[cfg(feature = "directory")]
+pub mod directory;
+#[cfg(any(feature = "client", feature = "server"))]
+pub mod jws;
+#[cfg(feature = "server")]
+pub mod cors;
+#[cfg(feature = "server")]
+mod health;
+#[cfg(any(feature = "client", feature = "server"))]
+pub mod httpsig;
+#[cfg(feature = "server")]
+pub mod integrity;
+#[cfg(feature = "server")]
+pub mod limits;
+#[cfg(feature = "server")]
+mod metrics;
+#[cfg(feature = "server")]
+mod problem;
+#[cfg(feature = "server")]
+pub mod ratelimit;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
@@ -0,0 +1,333 @@
+/*
+Copyright 2024 Glyn Normington
+
+This file is part of webfinger-rs.
+
+webfinger-rs is free software: you can redistribute it and/or modify it under the terms
+of the GNU General Public License as published by the Free Software Foundation, either
+version 3 of the License, or (at your option) any later version.
+
+webfinger-rs is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with webfinger-rs.
+If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Limits on the size of requests and on the resources a client can tie up, so
+// that oversized requests and slow or numerous clients cannot exhaust the server.
+// Limits on individual requests are applied to WebFinger requests by the router;
+// limits on connections are applied by serve.
+
+use std::convert::Infallible;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+    Router,
+};
+use hyper::{body::Incoming, service::service_fn};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tower::ServiceExt;
+
+use crate::problem::Problem;
+use crate::server::ServerState;
+
+/* Limits on individual WebFinger requests. */
+#[derive(Clone, Debug)]
+pub struct RequestLimits {
+    // Maximum length of the request target, including the query, in bytes.
+    pub max_uri_length: usize,
+
+    // Maximum number of "rel" parameters.
+    pub max_rels: usize,
+
+    // Maximum total size of the request headers, in bytes.
+    pub max_header_size: usize,
+
+    // Maximum time to spend answering a request.
+    pub request_timeout: Duration,
+}
+
+impl Default for RequestLimits {
+    fn default() -> RequestLimits {
+        RequestLimits {
+            max_uri_length: 4096,
+            max_rels: 16,
+            max_header_size: 16 * 1024,
+            request_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/* Limits on the connections accepted by serve. */
+#[derive(Clone, Debug)]
+pub struct ConnectionLimits {
+    // Maximum number of connections served at once. Further connections are
+    // answered with 503 (Service Unavailable) and closed.
+    pub max_connections: usize,
+
+    // Maximum time a client may take to send the headers of an HTTP/1 request,
+    // once it has started to send them. HTTP/2 connections are bounded instead
+    // by connection_timeout.
+    pub header_read_timeout: Duration,
+
+    // Maximum lifetime of a connection, after which it is closed once any request
+    // in progress has been answered. This also bounds how long an idle client can
+    // hold a connection open.
+    pub connection_timeout: Duration,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> ConnectionLimits {
+        ConnectionLimits {
+            max_connections: 1024,
+            header_read_timeout: Duration::from_secs(10),
+            connection_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+// Reject WebFinger requests whose target or headers are too large, and give up on
+// those which take too long to answer.
+pub(crate) async fn check(State(state): State<ServerState>, request: Request, next: Next) -> Response {
+    let limits = &state.options.limits;
+    let headers = request.headers().clone();
+
+    let uri_length = request.uri().path_and_query().map_or(0, |pq| pq.as_str().len());
+    if uri_length > limits.max_uri_length {
+        return Problem::new(StatusCode::URI_TOO_LONG, "uri-too-long", "Request URI too long")
+            .detail(format!(
+                "The request URI is {uri_length} bytes long, but must be at most {} bytes long",
+                limits.max_uri_length
+            ))
+            .text("Request URI too long")
+            .respond(&headers);
+    }
+
+    // Count each header as it would be sent: name, ": ", value, and CRLF.
+    let header_size: usize = headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum();
+    if header_size > limits.max_header_size {
+        return Problem::new(
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            "headers-too-large",
+            "Request headers too large",
+        )
+        .detail(format!(
+            "The request headers are {header_size} bytes long, but must be at most {} bytes long",
+            limits.max_header_size
+        ))
+        .text("Request headers too large")
+        .respond(&headers);
+    }
+
+    match tokio::time::timeout(limits.request_timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => Problem::new(StatusCode::SERVICE_UNAVAILABLE, "request-timeout", "Request timed out")
+            .detail(format!(
+                "The request could not be answered within {} milliseconds",
+                limits.request_timeout.as_millis()
+            ))
+            .text("Request timed out")
+            .respond(&headers),
+    }
+}
+
+// Serve a router on a listener over HTTP/1 or HTTP/2, within the given connection
+// limits. Each request carries the client's address as ConnectInfo<SocketAddr>,
+// as with Router::into_make_service_with_connect_info.
+pub async fn serve(listener: TcpListener, router: Router, limits: ConnectionLimits) -> io::Result<()> {
+    let permits = Arc::new(Semaphore::new(limits.max_connections));
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // Errors such as running out of file descriptors are transient, so
+                // wait a moment rather than failing or spinning.
+                eprintln!("Failed to accept connection: {e}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let permit = permits.clone().try_acquire_owned().ok();
+        let router = router.clone();
+        let limits = limits.clone();
+
+        tokio::spawn(async move {
+            let io = TokioIo::new(stream);
+            let mut builder = auto::Builder::new(TokioExecutor::new());
+            builder
+                .http1()
+                .timer(TokioTimer::new())
+                .header_read_timeout(limits.header_read_timeout);
+            builder.http2().timer(TokioTimer::new());
+
+            // Errors are due to the client, for example disconnecting, so are ignored.
+            match permit {
+                Some(_permit) => {
+                    let service = service_fn(move |mut request: hyper::Request<Incoming>| {
+                        request.extensions_mut().insert(ConnectInfo(peer));
+                        router.clone().oneshot(request)
+                    });
+                    let conn = builder.serve_connection(io, service);
+                    tokio::pin!(conn);
+                    tokio::select! {
+                        _ = conn.as_mut() => {}
+                        _ = tokio::time::sleep(limits.connection_timeout) => {
+                            conn.as_mut().graceful_shutdown();
+                            let _ = conn.await;
+                        }
+                    }
+                }
+                None => {
+                    let service = service_fn(|request: hyper::Request<Incoming>| async move {
+                        Ok::<_, Infallible>(
+                            Problem::status(StatusCode::SERVICE_UNAVAILABLE)
+                                .text("Too many connections")
+                                .respond(request.headers()),
+                        )
+                    });
+                    // An HTTP/2 client may keep the connection open after being
+                    // refused, so it is closed after header_read_timeout regardless.
+                    builder.http1().keep_alive(false);
+                    let conn = builder.serve_connection(io, service);
+                    let _ = tokio::time::timeout(limits.header_read_timeout, conn).await;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    async fn start(limits: ConnectionLimits) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Router::new().route(
+            "/",
+            get(|ConnectInfo(peer): ConnectInfo<SocketAddr>| async move { peer.ip().to_string() }),
+        );
+        tokio::spawn(serve(listener, router, limits));
+        addr
+    }
+
+    // Send a request on a new connection and return the response, up to the point
+    // at which the server closes the connection.
+    async fn request(addr: SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_serve_provides_connect_info() {
+        let addr = start(ConnectionLimits::default()).await;
+
+        let response = request(addr).await;
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("127.0.0.1"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn test_serve_http2() {
+        let addr = start(ConnectionLimits::default()).await;
+        let client = hyper_util::client::legacy::Client::builder(TokioExecutor::new())
+            .http2_only(true)
+            .build_http();
+
+        let response = client
+            .request(
+                Request::builder()
+                    .uri(format!("http://{addr}/"))
+                    .body(axum::body::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.version(), hyper::Version::HTTP_2);
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let addr = start(ConnectionLimits {
+            max_connections: 1,
+            ..Default::default()
+        })
+        .await;
+
+        let idle = TcpStream::connect(addr).await.unwrap();
+        let response = request(addr).await;
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"), "{response}");
+
+        drop(idle);
+        let mut response = String::new();
+        for _ in 0..50 {
+            // Wait for the server to notice that the idle connection has closed.
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            response = request(addr).await;
+            if response.starts_with("HTTP/1.1 200 OK") {
+                break;
+            }
+        }
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn test_header_read_timeout() {
+        let addr = start(ConnectionLimits {
+            header_read_timeout: Duration::from_millis(100),
+            ..Default::default()
+        })
+        .await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: local").await.unwrap();
+
+        let mut buf = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut buf)).await;
+        assert!(read.is_ok(), "connection was not closed");
+        assert!(!String::from_utf8_lossy(&buf).contains("200 OK"));
+    }
+
+    #[tokio::test]
+    async fn test_connection_timeout() {
+        let addr = start(ConnectionLimits {
+            connection_timeout: Duration::from_millis(100),
+            ..Default::default()
+        })
+        .await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        let mut buf = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut buf)).await;
+        assert!(read.is_ok(), "connection was not closed");
+        assert!(buf.is_empty());
+    }
+}
@@ -0,0 +1,136 @@
+// Transcribed by hand from the IANA Link Relations registry, in the form written
+// by `cargo run --example gen_link_relations`, and not yet generated from the
+// published link-relations.xml. The table may be incomplete or out of date until
+// it is regenerated.
+
+// Registered link relation types and their descriptions, sorted by relation
+// type in lower case.
+pub(crate) const LINK_RELATIONS: &[(&str, &str)] = &[
+    ("about", "Refers to a resource that is the subject of the link's context."),
+    ("acl", "Asserts that the link target provides an access control description for the link context."),
+    ("alternate", "Refers to a substitute for this context"),
+    ("amphtml", "Used to reference alternative content that uses the AMP profile of the HTML format."),
+    ("api-catalog", "Refers to a list of APIs available from the publisher of the link context."),
+    ("appendix", "Refers to an appendix."),
+    ("apple-touch-icon", "An icon for the link context that is suited to Apple's iOS devices."),
+    ("apple-touch-startup-image", "A startup screen image for the link context that is suited to Apple's iOS devices."),
+    ("archives", "Refers to a collection of records, documents, or other materials of historical interest."),
+    ("author", "Refers to the context's author."),
+    ("blocked-by", "Identifies the entity that blocks access to a resource following receipt of a legal demand."),
+    ("bookmark", "Gives a permanent link to use for bookmarking purposes."),
+    ("canonical", "Designates the preferred version of a resource (the IRI and its contents)."),
+    ("chapter", "Refers to a chapter in a collection of resources."),
+    ("cite-as", "Indicates that the link target is preferred over the link context for the purpose of permanent citation."),
+    ("collection", "The target IRI points to a resource which represents the collection resource for the context IRI."),
+    ("compression-dictionary", "Refers to a compression dictionary used for content encoding."),
+    ("contents", "Refers to a table of contents."),
+    ("convertedfrom", "The document linked to was later converted to the document that contains this link relation."),
+    ("copyright", "Refers to a copyright statement that applies to the link's context."),
+    ("create-form", "The target IRI points to a resource where a submission form can be obtained."),
+    ("current", "Refers to a resource containing the most recent item(s) in a collection of resources."),
+    ("deprecation", "Refers to a resource providing information about the link's context's deprecation."),
+    ("describedby", "Refers to a resource providing information about the link's context."),
+    ("describes", "The relationship A 'describes' B asserts that resource A provides a description of resource B."),
+    ("disclosure", "Refers to a list of patent disclosures made with respect to material for which 'disclosure' relation is specified."),
+    ("dns-prefetch", "Used to indicate an origin that will be used to fetch required resources for the link context, and that the user agent ought to resolve as early as possible."),
+    ("duplicate", "Refers to a resource whose available representations are byte-for-byte identical with the corresponding representations of the context IRI."),
+    ("edit", "Refers to a resource that can be used to edit the link's context."),
+    ("edit-form", "The target IRI points to a resource where a submission form for editing associated resource can be obtained."),
+    ("edit-media", "Refers to a resource that can be used to edit media associated with the link's context."),
+    ("enclosure", "Identifies a related resource that is potentially large and might require special handling."),
+    ("external", "Refers to a resource that is not part of the same site as the current context."),
+    ("first", "An IRI that refers to the furthest preceding resource in a series of resources."),
+    ("geofeed", "Refers to a geofeed file, which provides geolocation information for IP addresses."),
+    ("glossary", "Refers to a glossary of terms."),
+    ("help", "Refers to context-sensitive help."),
+    ("hosts", "Refers to a resource hosted by the server indicated by the link context."),
+    ("hub", "Refers to a hub that enables registration for notification of updates to the context."),
+    ("ice-server", "Conveys the URI of an ICE server or TURN server."),
+    ("icon", "Refers to an icon representing the link's context."),
+    ("index", "Refers to an index."),
+    ("intervalAfter", "refers to a resource associated with a time interval that ends before the beginning of the time interval associated with the context resource"),
+    ("intervalBefore", "refers to a resource associated with a time interval that begins after the end of the time interval associated with the context resource"),
+    ("intervalContains", "refers to a resource associated with a time interval that begins after the beginning of the time interval associated with the context resource, and ends before the end of the time interval associated with the context resource"),
+    ("intervalDisjoint", "refers to a resource associated with a time interval that begins after the end of the time interval associated with the context resource, or ends before the beginning of the time interval associated with the context resource"),
+    ("intervalDuring", "refers to a resource associated with a time interval that begins before the beginning of the time interval associated with the context resource, and ends after the end of the time interval associated with the context resource"),
+    ("intervalEquals", "refers to a resource associated with a time interval whose beginning coincides with the beginning of the time interval associated with the context resource, and whose end coincides with the end of the time interval associated with the context resource"),
+    ("intervalFinishedBy", "refers to a resource associated with a time interval that begins after the beginning of the time interval associated with the context resource, and whose end coincides with the end of the time interval associated with the context resource"),
+    ("intervalFinishes", "refers to a resource associated with a time interval that begins before the beginning of the time interval associated with the context resource, and whose end coincides with the end of the time interval associated with the context resource"),
+    ("intervalIn", "refers to a resource associated with a time interval that begins before or is coincident with the beginning of the time interval associated with the context resource, and ends after or is coincident with the end of the time interval associated with the context resource"),
+    ("intervalMeets", "refers to a resource associated with a time interval whose beginning coincides with the end of the time interval associated with the context resource"),
+    ("intervalMetBy", "refers to a resource associated with a time interval whose end coincides with the beginning of the time interval associated with the context resource"),
+    ("intervalOverlappedBy", "refers to a resource associated with a time interval that begins before the beginning of the time interval associated with the context resource, and ends after the beginning of the time interval associated with the context resource"),
+    ("intervalOverlaps", "refers to a resource associated with a time interval that begins before the end of the time interval associated with the context resource, and ends after the end of the time interval associated with the context resource"),
+    ("intervalStartedBy", "refers to a resource associated with a time interval whose beginning coincides with the beginning of the time interval associated with the context resource, and ends before the end of the time interval associated with the context resource"),
+    ("intervalStarts", "refers to a resource associated with a time interval whose beginning coincides with the beginning of the time interval associated with the context resource, and ends after the end of the time interval associated with the context resource"),
+    ("item", "The target IRI points to a resource that is a member of the collection represented by the context IRI."),
+    ("last", "An IRI that refers to the furthest following resource in a series of resources."),
+    ("latest-version", "Points to a resource containing the latest (e.g., current) version of the context."),
+    ("license", "Refers to a license associated with this context."),
+    ("linkset", "The link target of a link with the \"linkset\" relation type provides a set of links, including links in which the link context of the link participates."),
+    ("lrdd", "Refers to further information about the link's context, expressed as a LRDD (\"Link-based Resource Descriptor Document\") resource."),
+    ("manifest", "Links to a manifest file for the context."),
+    ("mask-icon", "Refers to a mask that can be applied to the icon for the context."),
+    ("me", "Indicates that the current resource is represented by the linked resource, for example a profile of the same person."),
+    ("media-feed", "Refers to a feed of personalised media recommendations relevant to the link context."),
+    ("memento", "The Target IRI points to a Memento, a fixed resource that will not change state anymore."),
+    ("micropub", "Links to the context's Micropub endpoint."),
+    ("modulepreload", "Refers to a module that the user agent is to preemptively fetch and store for use in the current context."),
+    ("monitor", "Refers to a resource that can be used to monitor changes in an HTTP resource."),
+    ("monitor-group", "Refers to a resource that can be used to monitor changes in a specified group of HTTP resources."),
+    ("next", "Indicates that the link's context is a part of a series, and that the next in the series is the link target."),
+    ("next-archive", "Refers to the immediately following archive resource."),
+    ("nofollow", "Indicates that the context's original author or publisher does not endorse the link target."),
+    ("noopener", "Indicates that any newly created top-level browsing context which results from following the link will not be an auxiliary browsing context."),
+    ("noreferrer", "Indicates that no referrer information is to be leaked when following the link."),
+    ("opener", "Indicates that any newly created top-level browsing context which results from following the link will be an auxiliary browsing context."),
+    ("openid2.local_id", "Refers to an OpenID Authentication server on which the context relies for an assertion that the end user controls an Identifier."),
+    ("openid2.provider", "Refers to a resource which accepts OpenID Authentication protocol messages for the context."),
+    ("original", "The Target IRI points to an Original Resource."),
+    ("P3Pv1", "Refers to a P3P privacy policy for the context."),
+    ("payment", "indicates a resource where payment is accepted."),
+    ("pingback", "Gives the address of the pingback resource for the link context."),
+    ("preconnect", "Used to indicate an origin that will be used to fetch required resources for the link context. Initiating an early connection, which includes the DNS lookup, TCP handshake, and optional TLS negotiation, allows the user agent to mask the high latency costs of establishing a connection."),
+    ("predecessor-version", "Points to a resource containing the predecessor version in the version history."),
+    ("prefetch", "The prefetch link relation type is used to identify a resource that might be required by the next navigation from the link context, and that the user agent ought to fetch, such that the user agent can deliver a faster response once the resource is requested in the future."),
+    ("preload", "Refers to a resource that should be loaded early in the processing of the link's context, without blocking rendering."),
+    ("prerender", "Used to identify a resource that might be required by the next navigation from the link context, and that the user agent ought to fetch and execute, such that the user agent can deliver a faster response once the resource is requested in the future."),
+    ("prev", "Indicates that the link's context is a part of a series, and that the previous in the series is the link target."),
+    ("prev-archive", "Refers to the immediately preceding archive resource."),
+    ("preview", "Refers to a resource that provides a preview of the link's context."),
+    ("previous", "Refers to the previous resource in an ordered series of resources. Synonym for \"prev\"."),
+    ("privacy-policy", "Refers to a privacy policy associated with the link's context."),
+    ("profile", "Identifying that a resource representation conforms to a certain profile, without affecting the non-profile semantics of the resource representation."),
+    ("publication", "Links to a publication manifest."),
+    ("related", "Identifies a related resource."),
+    ("replies", "Identifies a resource that is a reply to the context of the link."),
+    ("restconf", "Conveys the root of a RESTCONF API."),
+    ("ruleinput", "The resource identified by the link target provides an input value to an instance of a rule, where the resource which represents the rule instance is identified by the link context."),
+    ("search", "Refers to a resource that can be used to search through the link's context and related resources."),
+    ("section", "Refers to a section in a collection of resources."),
+    ("self", "Conveys an identifier for the link's context."),
+    ("service", "Indicates a URI that can be used to retrieve a service document."),
+    ("service-desc", "Identifies service description for the context that is primarily intended for consumption by machines."),
+    ("service-doc", "Identifies service documentation for the context that is primarily intended for human consumption."),
+    ("service-meta", "Identifies general metadata for the context that is primarily intended for consumption by machines."),
+    ("sip-trunking-capability", "Refers to a capability set document that defines parameters or configuration requirements for automated peering and communication channel negotiation of the Session Initiation Protocol (SIP)."),
+    ("sponsored", "Refers to a resource that is within a context that is sponsored (such as advertising or another compensation agreement)."),
+    ("start", "Refers to the first resource in a collection of resources."),
+    ("status", "Identifies a resource that represents the context's status."),
+    ("stylesheet", "Refers to a stylesheet."),
+    ("subsection", "Refers to a resource serving as a subsection in a collection of resources."),
+    ("successor-version", "Points to a resource containing the successor version in the version history."),
+    ("sunset", "Identifies a resource that provides information about the context's retirement policy."),
+    ("tag", "Gives a tag (identified by the given address) that applies to the current document."),
+    ("terms-of-service", "Refers to the terms of service associated with the link's context."),
+    ("timegate", "The Target IRI points to a TimeGate for an Original Resource."),
+    ("timemap", "The Target IRI points to a TimeMap for an Original Resource."),
+    ("type", "Refers to a resource identifying the abstract semantic type of which the link's context is considered to be an instance."),
+    ("ugc", "Refers to a resource that is within a context that is User Generated Content."),
+    ("up", "Refers to a parent document in a hierarchy of documents."),
+    ("version-history", "Points to a resource containing the version history for the context."),
+    ("via", "Identifies a resource that is the source of the information in the link's context."),
+    ("webmention", "Identifies a target URI that supports the Webmention protocol. This allows clients that mention a resource in some form of publishing process to contact that endpoint and inform it that this resource has been mentioned."),
+    ("working-copy", "Points to a working copy for this resource."),
+    ("working-copy-of", "Points to the versioned resource from which this working copy was obtained."),
+];
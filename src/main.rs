@@ -15,496 +15,859 @@ You should have received a copy of the GNU General Public License along with web
 If not, see <https://www.gnu.org/licenses/>.
 */
 
-mod jrdmap;
-mod rel;
-
-use axum::{
-    body::Body, extract::State, http::StatusCode, response::Response, routing::get, Router,
-};
-use axum_extra::extract::Query;
-use fluent_uri::Uri;
-use hyper::header::{ACCESS_CONTROL_ALLOW_ORIGIN, CONTENT_TYPE};
-use serde::Deserialize;
 use std::fs;
 use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
-
-use clap::Parser;
+use webfinger_rs::compression::CompressionOptions;
+use webfinger_rs::directory::{Change, DirectoryKey, DirectoryStore};
+use webfinger_rs::httpsig::SignatureScheme;
+use webfinger_rs::integrity::{self, MapIntegrity};
+use webfinger_rs::cors::CorsOptions;
+use webfinger_rs::jrdmap::{self, Format};
+use webfinger_rs::ratelimit::{IpNet, RateLimitOptions};
+use webfinger_rs::sqlite::SqliteStore;
+use webfinger_rs::store::{CompositeStore, JrdStore, JsonMapStore};
+use webfinger_rs::limits::{self, ConnectionLimits, RequestLimits};
+use webfinger_rs::server::TrailingSlash;
+use webfinger_rs::{access, admin, client, jws, server};
+
+use clap::{ArgGroup, Parser, Subcommand};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use hyper::header::HeaderName;
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-struct Args {
-    /// File path of webfinger JRD map file
-    #[arg(short, long)]
-    jrd_map_path: String,
-
-    /// Port number to listen on
-    #[arg(short, long)]
-    port: u16,
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    args: Option<Args>,
+
+    // The CORS options belong with the server's arguments, but clap cannot tell
+    // whether an optional flattened struct is present if it flattens another, so
+    // each CORS option requires the server's port instead.
+    #[command(flatten)]
+    cors: CorsArgs,
 }
 
-#[derive(Clone)]
-struct ServerState {
-    webfinger_jrdmap: jrdmap::JrdMap,
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Convert a JRD map file between JSON, YAML, and TOML
+    Convert {
+        /// File path of JRD map file to convert
+        input: String,
+
+        /// File path to write the converted JRD map to (standard output if omitted)
+        output: Option<String>,
+
+        /// Format of the input file (determined by its extension if omitted)
+        #[arg(long, value_enum)]
+        from: Option<Format>,
+
+        /// Format of the output (determined by the output file's extension if omitted)
+        #[arg(long, value_enum)]
+        to: Option<Format>,
+    },
+
+    /// Rewrite JRD map files in canonical form
+    Fmt {
+        /// File paths of JRD map files to rewrite
+        #[arg(required = true)]
+        paths: Vec<String>,
+
+        /// Format of the files (determined by each file's extension if omitted)
+        #[arg(long, value_enum)]
+        format: Option<Format>,
+
+        /// Check that the files are in canonical form instead of rewriting them
+        #[arg(long)]
+        check: bool,
+    },
+
+    /// Perform a WebFinger query against another server and print the result
+    Query {
+        /// URI of the resource to query, such as acct:bob@example.com
+        resource: String,
+
+        /// Link relation type to request (may be repeated; all links are returned if omitted)
+        #[arg(long)]
+        rel: Vec<String>,
+
+        /// Host (and optional port) to query, instead of the host of the resource
+        #[arg(long)]
+        host: Option<String>,
+
+        /// Use HTTP instead of HTTPS (insecure: for testing only)
+        #[arg(long)]
+        insecure_http: bool,
+
+        /// Maximum number of redirects to follow
+        #[arg(long, default_value_t = 5)]
+        max_redirects: usize,
+
+        /// How to print the result
+        #[arg(long, value_enum, default_value_t = QueryOutput::Json)]
+        output: QueryOutput,
+    },
+
+    /// Query another server for a signed JRD, verify its signature, and print the result
+    Verify {
+        /// URI of the resource to query, such as acct:bob@example.com
+        resource: String,
+
+        /// Link relation type to request (may be repeated; all links are returned if omitted)
+        #[arg(long)]
+        rel: Vec<String>,
+
+        /// Host (and optional port) to query, instead of the host of the resource
+        #[arg(long)]
+        host: Option<String>,
+
+        /// Use HTTP instead of HTTPS (insecure: for testing only)
+        #[arg(long)]
+        insecure_http: bool,
+
+        /// Maximum number of redirects to follow
+        #[arg(long, default_value_t = 5)]
+        max_redirects: usize,
+
+        /// File path of a JWK set with which to verify the signature, instead of the one published by the host
+        #[arg(long)]
+        jwks_path: Option<String>,
+
+        /// How the response is expected to be signed
+        #[arg(long, value_enum, default_value_t = SignatureScheme::Jws)]
+        scheme: SignatureScheme,
+
+        /// Request the JRD wrapped in a JWS (application/jose+json) instead of signed in a response header
+        #[arg(long)]
+        wrapped: bool,
+
+        /// How to print the result
+        #[arg(long, value_enum, default_value_t = QueryOutput::Json)]
+        output: QueryOutput,
+    },
+
+    /// Manage a SQLite JRD database
+    #[command(subcommand)]
+    Sqlite(SqliteCommand),
 }
 
-#[derive(Deserialize)]
-struct Params {
-    #[serde(default)]
-    resource: Vec<String>,
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum QueryOutput {
+    /// The JRD as pretty-printed JSON
+    Json,
 
-    #[serde(default)]
-    rel: Vec<String>,
+    /// A table of the JRD's links
+    Table,
 }
 
-#[tokio::main]
-async fn main() -> io::Result<()> {
-    let args = Args::parse();
+#[derive(Subcommand, Debug)]
+enum SqliteCommand {
+    /// Import the entries of a JRD map file into a SQLite database, replacing any
+    /// existing entries for the same resources
+    Import {
+        /// File path of SQLite database (created if it does not exist)
+        #[arg(long)]
+        sqlite_path: String,
+
+        /// File path of webfinger JRD map file to import
+        jrd_map_path: String,
+
+        /// Format of the JRD map file (determined by its extension if omitted)
+        #[arg(long, value_enum)]
+        format: Option<Format>,
+    },
+
+    /// Export the entries of a SQLite database as a JRD map file
+    Export {
+        /// File path of SQLite database
+        #[arg(long)]
+        sqlite_path: String,
+
+        /// File path to write the JRD map to (standard output if omitted)
+        jrd_map_path: Option<String>,
+
+        /// Format of the JRD map (determined by the file's extension if omitted)
+        #[arg(long, value_enum)]
+        format: Option<Format>,
+    },
+}
 
-    let webfinger_jrdmap = fs::read_to_string(args.jrd_map_path).expect("Failed to read file");
+#[derive(clap::Args, Debug)]
+#[command(group(ArgGroup::new("source").required(true).multiple(true).args(["jrd_map_path", "jrd_dir", "sqlite_path"])))]
+struct Args {
+    /// File path of webfinger JRD map file. May be repeated, in which case the maps
+    /// are consulted in the order given and the admin API manages the first map
+    #[arg(short, long)]
+    jrd_map_path: Vec<String>,
 
-    let jm = jrdmap::from_json(&webfinger_jrdmap);
+    /// Format of the JRD map files (determined by each file's extension if omitted)
+    #[arg(long, value_enum, requires = "jrd_map_path")]
+    jrd_map_format: Option<Format>,
 
-    let router = create_router(jm);
+    /// Reload the JRD map files whenever they, or their signature files, change
+    #[arg(long, requires = "jrd_map_path")]
+    watch_jrd_maps: bool,
 
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", args.port)).await?;
-    println!("Listening on http://{}", listener.local_addr()?);
+    /// File path of a minisign public key with which each JRD map file must be signed. The signature of each
+    /// file is read from the file of the same name with .minisig appended
+    #[arg(long, requires = "jrd_map_path", conflicts_with = "admin_port")]
+    jrd_map_public_key_path: Option<String>,
 
-    axum::serve(listener, router).await
-}
+    /// SHA-256 digest, in hexadecimal, which a JRD map file must have. May be repeated, in which case each
+    /// file must have one of the digests
+    #[arg(long, value_parser = integrity::parse_sha256_pin, requires = "jrd_map_path", conflicts_with = "admin_port")]
+    jrd_map_sha256: Vec<[u8; 32]>,
 
-fn create_router(jm: jrdmap::JrdMap) -> Router {
-    let state = ServerState {
-        webfinger_jrdmap: jm,
-    };
+    /// Path of a directory tree containing one JRD file per resource, consulted after
+    /// any JRD map files. The directory is watched for changes
+    #[arg(long)]
+    jrd_dir: Option<String>,
 
-    Router::new()
-        .route("/.well-known/webfinger", get(handler))
-        .with_state(state)
-}
+    /// How the resource URI of each file in the JRD directory is determined
+    #[arg(long, value_enum, default_value_t = DirectoryKey::Subject, requires = "jrd_dir")]
+    jrd_dir_key: DirectoryKey,
 
-fn valid_uri(uri: &str) -> bool {
-    let uri_reference = Uri::parse(uri);
-    !uri_reference.is_err() && uri_reference.unwrap().has_scheme()
-}
+    /// File path of SQLite database of JRDs, consulted after any JRD map files and
+    /// JRD directory
+    #[arg(long)]
+    sqlite_path: Option<String>,
 
-async fn handler(State(state): State<ServerState>, Query(params): Query<Params>) -> Response {
-    let uri = params.resource;
+    /// Port number to listen on
+    #[arg(short, long)]
+    port: u16,
 
-    // "resource" parameter must be specified exactly once
-    if uri.len() != 1 {
-        Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body(Body::from("Exactly one \"resource\" query parameter must be provided"))
-            .unwrap()
-    } else {
-        let uri = uri.get(0).unwrap();
-        if !valid_uri(&uri) {
-            // Malformed "resource" parameter
-            Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body(Body::from("Malformed \"resource\" query parameter"))
-                .unwrap()
-        } else if let Some(jrd) = state.webfinger_jrdmap.get(uri) {
-            let body = if params.rel.is_empty() {
-                jrdmap::to_json(&jrd)
-            } else {
-                jrdmap::to_json(&jrd.filter(params.rel))
-            };
+    /// Port number for the admin API to listen on (the admin API is disabled if omitted). The first JRD map
+    /// file must be JSON, since the admin API rewrites it
+    #[arg(long, requires_all = ["admin_token_path", "jrd_map_path"])]
+    admin_port: Option<u16>,
+
+    /// File path of a file containing the bearer token required by the admin API
+    #[arg(long, requires = "admin_port")]
+    admin_token_path: Option<String>,
+
+    /// Interpret resources of the form user@host or @user@host as acct URIs instead of rejecting them
+    #[arg(long)]
+    lenient: bool,
+
+    /// Write JRDs in canonical JSON form, with the members of every object sorted by name
+    #[arg(long)]
+    canonical_json: bool,
+
+    /// Reduce the titles of each link to the one in the language which best matches the request's Accept-Language header
+    #[arg(long)]
+    select_title_language: bool,
+
+    /// File path of a file of principals, with their bearer tokens or client certificates, which may see
+    /// JRDs and links which are not public (all requests are treated as anonymous if omitted)
+    #[arg(long)]
+    principals_path: Option<String>,
+
+    /// Format of the principals file (determined by its extension if omitted)
+    #[arg(long, value_enum, requires = "principals_path")]
+    principals_format: Option<Format>,
+
+    /// Request header in which a TLS-terminating reverse proxy identifies the client certificate, for
+    /// example by its fingerprint. The proxy must remove this header from client requests
+    #[arg(long, requires = "principals_path")]
+    client_certificate_header: Option<HeaderName>,
+
+    /// Number of requests answered with a JRD which each client IP address may make per minute (unlimited if omitted)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    rate_limit_hits: Option<u32>,
+
+    /// Number of requests not answered with a JRD, such as those for unknown resources, which each client IP
+    /// address may make per minute (unlimited if omitted)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    rate_limit_misses: Option<u32>,
+
+    /// IP address or CIDR range of a reverse proxy whose X-Forwarded-For header identifies the client for rate
+    /// limiting. May be repeated
+    #[arg(long)]
+    trusted_proxy: Vec<IpNet>,
+
+    /// Explanation given to a client whose request is refused by rate limiting, in place of the default
+    #[arg(long)]
+    rate_limit_message: Option<String>,
+
+    /// Delay responses not answered with a JRD until this many milliseconds after the request was received, so
+    /// that their timing does not reveal why the resource was not found
+    #[arg(long)]
+    miss_response_time: Option<u64>,
+
+    /// Maximum length in bytes of the URI of a request, including the query
+    #[arg(long, default_value_t = RequestLimits::default().max_uri_length)]
+    max_uri_length: usize,
+
+    /// Maximum number of rel parameters in a request
+    #[arg(long, default_value_t = RequestLimits::default().max_rels)]
+    max_rels: usize,
+
+    /// Maximum total size in bytes of the headers of a request
+    #[arg(long, default_value_t = RequestLimits::default().max_header_size)]
+    max_header_size: usize,
+
+    /// Maximum time in milliseconds to spend answering a request
+    #[arg(long, default_value_t = RequestLimits::default().request_timeout.as_millis() as u64)]
+    request_timeout: u64,
+
+    /// Maximum number of connections to serve at once
+    #[arg(long, default_value_t = ConnectionLimits::default().max_connections)]
+    max_connections: usize,
+
+    /// Maximum time in milliseconds a client may take to send the headers of an HTTP/1 request
+    #[arg(long, default_value_t = ConnectionLimits::default().header_read_timeout.as_millis() as u64)]
+    header_read_timeout: u64,
+
+    /// Maximum time in milliseconds a connection may stay open
+    #[arg(long, default_value_t = ConnectionLimits::default().connection_timeout.as_millis() as u64)]
+    connection_timeout: u64,
+
+    /// How to handle requests for paths with a trailing slash, such as /.well-known/webfinger/
+    #[arg(long, value_enum, default_value_t = TrailingSlash::NotFound)]
+    trailing_slash: TrailingSlash,
+
+    /// Compress JRDs with gzip, brotli, or zstd for clients which accept them in Accept-Encoding
+    #[arg(long)]
+    compression: bool,
+
+    /// Minimum size in bytes of a JRD to be compressed
+    #[arg(long, default_value_t = CompressionOptions::default().min_size, requires = "compression")]
+    compression_min_size: usize,
+
+    /// Maximum number of compressed JRDs to cache
+    #[arg(long, default_value_t = CompressionOptions::default().max_cached, requires = "compression")]
+    compression_cache_size: usize,
+
+    /// File path of a PEM-encoded PKCS#8 Ed25519, P-256, or P-384 private key with which to sign JRDs. The
+    /// public key is published at /.well-known/jwks.json
+    #[arg(long)]
+    signing_key_path: Option<String>,
+
+    /// Key ID of the signing key (the key's JWK thumbprint if omitted)
+    #[arg(long, requires = "signing_key_path")]
+    signing_key_id: Option<String>,
+
+    /// How to sign JRD responses: with a JWS of the JRD, or with an HTTP message signature (RFC 9421) of the
+    /// response
+    #[arg(long, value_enum, default_value_t = SignatureScheme::Jws, requires = "signing_key_path")]
+    signature_scheme: SignatureScheme,
+
+    /// File path of a JWK set of the public keys of earlier signing keys, which are published alongside the
+    /// signing key's so that responses signed with them can still be verified
+    #[arg(long, requires = "signing_key_path")]
+    previous_jwks_path: Option<String>,
+}
 
-            Response::builder()
-                .status(StatusCode::OK)
-                .header(CONTENT_TYPE, "application/jrd+json")
-                .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
-                .body(Body::from(body))
-                .unwrap()
-        } else {
-            // URI not found
-            Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(Body::from(""))
-                .unwrap()
+// Cross-origin resource sharing (CORS) policy of the well-known routes.
+#[derive(clap::Args, Debug)]
+#[command(next_help_heading = "CORS options")]
+struct CorsArgs {
+    /// Origin, such as https://app.example.com, which may read responses. May be repeated (any origin may read
+    /// responses if omitted)
+    #[arg(long, requires = "port")]
+    cors_allowed_origin: Vec<String>,
+
+    /// Response header, such as ETag, which scripts may read. May be repeated
+    #[arg(long, requires = "port")]
+    cors_expose_header: Vec<HeaderName>,
+
+    /// Request header, such as Authorization, which scripts may send. May be repeated
+    #[arg(long, requires = "port")]
+    cors_allow_header: Vec<HeaderName>,
+
+    /// Number of seconds for which browsers may cache the response to a preflight request
+    #[arg(long, requires = "port")]
+    cors_max_age: Option<u64>,
+}
+
+impl From<CorsArgs> for CorsOptions {
+    fn from(args: CorsArgs) -> CorsOptions {
+        CorsOptions {
+            allowed_origins: args.cors_allowed_origin,
+            expose_headers: args.cors_expose_header,
+            allow_headers: args.cors_allow_header,
+            max_age: args.cors_max_age.map(Duration::from_secs),
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use axum::{
-        body::Body,
-        http::{Request, StatusCode},
-    };
-    use http_body_util::BodyExt;
-    use pretty_assertions::assert_eq;
-    use serde_json::{json, Value};
-    use std::str;
-    use tokio::net::TcpListener;
-    use tower::ServiceExt;
-
-    #[tokio::test]
-    async fn router_test() {
-        let jm = jrdmap::from_json(
-            &r#"
-            {
-                "acct:alice@example.com":{
-                    "subject": "acct:alice@example.com",
-                    "links": [
-                        {
-                            "rel": "http://webfinger.net/rel/avatar",
-                            "type": "image/jpeg",
-                            "href": "https://example.com/data/alice-avatar.jpeg"
-                        }
-                    ]
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let cli = Cli::parse();
+
+    match (cli.command, cli.args) {
+        (
+            Some(Command::Convert {
+                input,
+                output,
+                from,
+                to,
+            }),
+            _,
+        ) => {
+            let jm = read_jrd_map(&input, map_format(&input, from), &MapIntegrity::default());
+            write_jrd_map(output.as_deref(), &jm, to)
+        }
+        (
+            Some(Command::Fmt {
+                paths,
+                format,
+                check,
+            }),
+            _,
+        ) => fmt_command(&paths, format, check),
+        (
+            Some(Command::Query {
+                resource,
+                rel,
+                host,
+                insecure_http,
+                max_redirects,
+                output,
+            }),
+            _,
+        ) => {
+            let options = client::QueryOptions {
+                rels: rel,
+                host,
+                insecure_http,
+                max_redirects,
+            };
+            match client::query(&resource, &options).await {
+                Ok(response) => {
+                    print_query_response(&response, output);
+                    Ok(())
                 }
-            }"#
-            .to_string(),
-        );
-        let router = create_router(jm);
+                Err(e) => {
+                    eprintln!("{e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        (
+            Some(Command::Verify {
+                resource,
+                rel,
+                host,
+                insecure_http,
+                max_redirects,
+                jwks_path,
+                scheme,
+                wrapped,
+                output,
+            }),
+            _,
+        ) => {
+            let options = client::QueryOptions {
+                rels: rel,
+                host,
+                insecure_http,
+                max_redirects,
+            };
+            let verify_options = client::VerifyOptions {
+                jwk_set: jwks_path.map(|path| read_jwk_set(&path)),
+                scheme,
+                wrapped,
+            };
+            let result = match client::Client::new() {
+                Ok(client) => client.verify(&resource, &options, &verify_options).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok((response, kid)) => {
+                    eprintln!("Signature verified with key {kid}");
+                    print_query_response(&response, output);
+                    Ok(())
+                }
+                Err(e) => {
+                    eprintln!("{e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        (Some(Command::Sqlite(command)), _) => sqlite_command(command),
+        (None, Some(args)) => {
+            check_args(&args).unwrap_or_else(|e| e.exit());
+            serve(args, cli.cors).await
+        }
+        (None, None) => {
+            use clap::CommandFactory;
+            Cli::command().print_help()
+        }
+    }
+}
 
-        let response = router
-            .oneshot(Request::builder().uri("/.well-known/webfinger?resource=acct:alice@example.com&rel=http://webfinger.net/rel/avatar").body(Body::empty()).unwrap())
-            .await
-            .unwrap();
+// Print the JRD resulting from a query, after any warnings about the response.
+fn print_query_response(response: &client::QueryResponse, output: QueryOutput) {
+    for warning in &response.warnings {
+        eprintln!("Warning: {warning}");
+    }
+    match output {
+        QueryOutput::Json => println!("{}", serde_json::to_string_pretty(&response.jrd).unwrap()),
+        QueryOutput::Table => print!("{}", client::links_table(&response.jrd)),
+    }
+}
 
-        assert_eq!(response.status(), StatusCode::OK);
-        assert_eq!(
-            response.headers().get(CONTENT_TYPE).unwrap(),
-            "application/jrd+json"
-        );
+// Determine the format of a JRD map file from an explicit format, if specified,
+// or otherwise from the file's extension, defaulting to JSON.
+fn map_format(path: &str, format: Option<Format>) -> Format {
+    format
+        .or_else(|| Format::from_path(Path::new(path)))
+        .unwrap_or(Format::Json)
+}
 
-        let body = response.into_body().collect().await.unwrap().to_bytes();
-        let actual: Value = serde_json::from_str(str::from_utf8(&body[..]).unwrap()).unwrap();
-        let expected = json!(
-        {
-            "subject":"acct:alice@example.com",
-            "links": [
-                {
-                    "rel":"http://webfinger.net/rel/avatar",
-                    "type":"image/jpeg",
-                    "href":"https://example.com/data/alice-avatar.jpeg"
-                }
-            ]
-        });
-        assert_eq!(actual, expected);
+fn read_jrd_map(path: &str, format: Format, integrity: &MapIntegrity) -> jrdmap::JrdMap {
+    let jm = integrity::read_map(Path::new(path), format, integrity).expect("Failed to load JRD map");
+    for warning in jrdmap::unregistered_rels(&jm) {
+        eprintln!("Warning: {warning}");
     }
+    jm
+}
 
-    #[tokio::test]
-    async fn router_test_with_multiple_rels_in_query() {
-        let jm = jrdmap::from_json(
-            &r#"
-            {
-                "acct:alice@example.com":{
-                    "subject": "acct:alice@example.com",
-                    "links": [
-                        {
-                            "rel": "http://webfinger.net/rel/avatar",
-                            "type": "image/jpeg",
-                            "href": "https://example.com/data/alice-avatar.jpeg"
-                        },
-                        {
-                            "rel": "me",
-                            "href": "acct:me@example.com"
-                        },
-                        {
-                            "rel": "author",
-                            "href": "acct:author@example.com"
-                        }
-                    ]
-                }
-            }"#
-            .to_string(),
-        );
-        let router = create_router(jm);
+fn read_principals(path: &str, format: Format) -> access::Principals {
+    let principals = fs::read_to_string(path).expect("Failed to read principals file");
+    access::Principals::from_str(&principals, format).expect("Failed to parse principals file")
+}
 
-        let response = router
-            .oneshot(Request::builder().uri("/.well-known/webfinger?resource=acct:alice@example.com&rel=http://webfinger.net/rel/avatar&rel=me").body(Body::empty()).unwrap())
-            .await
-            .unwrap();
+fn read_signing_key(path: &str, kid: Option<String>) -> jws::SigningKey {
+    let pem = fs::read_to_string(path).expect("Failed to read signing key file");
+    jws::SigningKey::from_pem(&pem, kid).expect("Failed to parse signing key file")
+}
 
-        assert_eq!(response.status(), StatusCode::OK);
-        assert_eq!(
-            response.headers().get(CONTENT_TYPE).unwrap(),
-            "application/jrd+json"
-        );
+fn read_jwk_set(path: &str) -> jws::JwkSet {
+    let jwk_set = fs::read_to_string(path).expect("Failed to read JWK set file");
+    jwk_set.parse().expect("Failed to parse JWK set file")
+}
 
-        let body = response.into_body().collect().await.unwrap().to_bytes();
-        let actual: Value = serde_json::from_str(str::from_utf8(&body[..]).unwrap()).unwrap();
-        let expected = json!(
-        {
-            "subject":"acct:alice@example.com",
-            "links": [
-                {
-                    "rel":"http://webfinger.net/rel/avatar",
-                    "type":"image/jpeg",
-                    "href":"https://example.com/data/alice-avatar.jpeg"
-                },
-                {
-                    "rel": "me",
-                    "href": "acct:me@example.com"
-                }
-            ]
-        });
-        assert_eq!(actual, expected);
+// Write a JRD map to a file or, if no file path is given, to standard output.
+fn write_jrd_map(path: Option<&str>, jm: &jrdmap::JrdMap, format: Option<Format>) -> io::Result<()> {
+    match path {
+        Some(path) => {
+            let s = jrdmap::to_string(jm, map_format(path, format)).expect("Failed to serialize JRD map");
+            fs::write(path, s)
+        }
+        None => {
+            let s = jrdmap::to_string(jm, format.unwrap_or(Format::Json)).expect("Failed to serialize JRD map");
+            print!("{s}");
+            Ok(())
+        }
     }
+}
 
-    #[tokio::test]
-    async fn router_test_with_encoded_query() {
-        let jm = jrdmap::from_json(
-            &r#"
-            {
-                "acct:alice@example.com":{
-                    "subject": "acct:alice@example.com",
-                    "links": [
-                        {
-                            "rel": "http://webfinger.net/rel/avatar",
-                            "type": "image/jpeg",
-                            "href": "https://example.com/data/alice-avatar.jpeg"
-                        }
-                    ]
-                }
-            }"#
-            .to_string(),
-        );
-        let router = create_router(jm);
-
-        let response = router
-            .oneshot(Request::builder().uri("/.well-known/webfinger?resource=acct%3Aalice%40example.com&rel=http%3a//webfinger.net/rel/avatar").body(Body::empty()).unwrap())
-            .await
-            .unwrap();
-
-        assert_eq!(response.status(), StatusCode::OK);
-        assert_eq!(
-            response.headers().get(CONTENT_TYPE).unwrap(),
-            "application/jrd+json"
-        );
+// Rewrite JRD map files in canonical form or, when checking, report those which
+// are not in canonical form and exit with a non-zero status.
+fn fmt_command(paths: &[String], format: Option<Format>, check: bool) -> io::Result<()> {
+    let mut unformatted = false;
+    for path in paths {
+        let content = fs::read_to_string(path)?;
+        let formatted = match jrdmap::format_map(&content, map_format(path, format)) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                eprintln!("{path}: {e}");
+                std::process::exit(1);
+            }
+        };
+        if formatted == content {
+            continue;
+        }
+        if check {
+            eprintln!("{path} is not in canonical form");
+            unformatted = true;
+        } else {
+            fs::write(path, formatted)?;
+            println!("Formatted {path}");
+        }
+    }
+    if unformatted {
+        std::process::exit(1);
+    }
+    Ok(())
+}
 
-        let body = response.into_body().collect().await.unwrap().to_bytes();
-        let actual: Value = serde_json::from_str(str::from_utf8(&body[..]).unwrap()).unwrap();
-        let expected = json!(
-        {
-            "subject":"acct:alice@example.com",
-            "links": [
-                {
-                    "rel":"http://webfinger.net/rel/avatar",
-                    "type":"image/jpeg",
-                    "href":"https://example.com/data/alice-avatar.jpeg"
-                }
-            ]
-        });
-        assert_eq!(actual, expected);
+fn sqlite_command(command: SqliteCommand) -> io::Result<()> {
+    match command {
+        SqliteCommand::Import {
+            sqlite_path,
+            jrd_map_path,
+            format,
+        } => {
+            let jm = read_jrd_map(&jrd_map_path, map_format(&jrd_map_path, format), &MapIntegrity::default());
+            let store = SqliteStore::open(Path::new(&sqlite_path)).expect("Failed to open SQLite database");
+            store.import(&jm).expect("Failed to import JRD map");
+            println!("Imported {} entries into {sqlite_path}", jm.len());
+            Ok(())
+        }
+        SqliteCommand::Export {
+            sqlite_path,
+            jrd_map_path,
+            format,
+        } => {
+            let store = SqliteStore::open(Path::new(&sqlite_path)).expect("Failed to open SQLite database");
+            let jm = store.export().expect("Failed to export JRD map");
+            write_jrd_map(jrd_map_path.as_deref(), &jm, format)
+        }
     }
+}
 
-    #[tokio::test]
-    async fn not_found() {
-        let jm = jrdmap::from_json(
-            &r#"
-            {
-                "acct:other@example.com":{
-                    "subject": "acct:other@example.com"
-                }
-            }"#
-            .to_string(),
-        );
-        let router = create_router(jm);
-
-        let response = router
-            .oneshot(
-                Request::builder()
-                    .uri("/.well-known/webfinger?resource=acct:alice@example.com")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-
-        assert_eq!(response.status(), StatusCode::NOT_FOUND);
-        let body = response.into_body().collect().await.unwrap().to_bytes();
-        assert!(body.is_empty());
+// Check the constraints on the server's arguments which clap cannot express.
+fn check_args(args: &Args) -> Result<(), clap::Error> {
+    use clap::CommandFactory;
+    if args.admin_port.is_some() && map_format(&args.jrd_map_path[0], args.jrd_map_format) != Format::Json {
+        return Err(Cli::command().error(
+            clap::error::ErrorKind::ArgumentConflict,
+            "--admin-port requires the first JRD map file to be JSON, since rewriting a YAML or TOML map would discard its comments",
+        ));
     }
+    Ok(())
+}
 
-    #[tokio::test]
-    async fn missing_resource() {
-        let jm = jrdmap::from_json(
-            &r#"
-            {
-                "acct:other@example.com":{
-                    "subject": "acct:other@example.com"
-                }
-            }"#
-            .to_string(),
+async fn serve(args: Args, cors: CorsArgs) -> io::Result<()> {
+    let map_integrity = MapIntegrity {
+        public_key: args.jrd_map_public_key_path.map(|path| {
+            let public_key = fs::read_to_string(path).expect("Failed to read JRD map public key file");
+            public_key.parse().expect("Failed to parse JRD map public key file")
+        }),
+        sha256_pins: args.jrd_map_sha256,
+    };
+    let json_stores: Vec<Arc<JsonMapStore>> = args
+        .jrd_map_path
+        .iter()
+        .map(|path| {
+            let jm = read_jrd_map(path, map_format(path, args.jrd_map_format), &map_integrity);
+            Arc::new(JsonMapStore::new(jm))
+        })
+        .collect();
+    let _map_watcher = args.watch_jrd_maps.then(|| {
+        let maps = args
+            .jrd_map_path
+            .iter()
+            .zip(&json_stores)
+            .map(|(path, store)| (PathBuf::from(path), map_format(path, args.jrd_map_format), store.clone()))
+            .collect();
+        watch_jrd_maps(maps, map_integrity).expect("Failed to watch JRD map files")
+    });
+
+    // Bind the listeners before creating the routers so that readiness implies
+    // the listeners are up.
+    let listener = TcpListener::bind(format!("127.0.0.1:{}", args.port)).await?;
+    println!("Listening on http://{}", listener.local_addr()?);
+
+    let admin = match (args.admin_port, args.admin_token_path) {
+        (Some(admin_port), Some(admin_token_path)) => {
+            let token = fs::read_to_string(admin_token_path).expect("Failed to read admin token file");
+            let admin_listener = TcpListener::bind(format!("127.0.0.1:{}", admin_port)).await?;
+            println!("Admin API listening on http://{}", admin_listener.local_addr()?);
+            let admin_router = admin::create_admin_router(
+                json_stores[0].clone(),
+                PathBuf::from(&args.jrd_map_path[0]),
+                token.trim().to_string(),
+            );
+            Some((admin_listener, admin_router))
+        }
+        _ => None,
+    };
+
+    let mut stores: Vec<Arc<dyn JrdStore>> = json_stores
+        .into_iter()
+        .map(|s| s as Arc<dyn JrdStore>)
+        .collect();
+    if let Some(jrd_dir) = &args.jrd_dir {
+        let directory_store = Arc::new(
+            DirectoryStore::load(Path::new(jrd_dir), args.jrd_dir_key).expect("Failed to load JRD directory"),
         );
-        let router = create_router(jm);
-
-        let response = router
-            .oneshot(
-                Request::builder()
-                    .uri("/.well-known/webfinger")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-
-        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
-        let body = response.into_body().collect().await.unwrap().to_bytes();
-        assert_eq!(body, "Exactly one \"resource\" query parameter must be provided");
+        directory_store
+            .watch(|change| match change {
+                Ok(Change::Reloaded { uri, path }) => println!("Reloaded {uri} ({path:?})"),
+                Ok(Change::Removed { uri, path }) => println!("Removed {uri} ({path:?})"),
+                Err(e) => eprintln!("{e}"),
+            })
+            .expect("Failed to watch JRD directory");
+        stores.push(directory_store);
     }
+    if let Some(sqlite_path) = &args.sqlite_path {
+        let sqlite_store = SqliteStore::open(Path::new(sqlite_path)).expect("Failed to open SQLite database");
+        stores.push(Arc::new(sqlite_store));
+    }
+    let store: Arc<dyn JrdStore> = if stores.len() == 1 {
+        stores.remove(0)
+    } else {
+        Arc::new(CompositeStore::new(stores))
+    };
+    log_changes(store.clone());
+
+    let options = server::ServerOptions {
+        lenient: args.lenient,
+        canonical_json: args.canonical_json,
+        select_title_language: args.select_title_language,
+        principals: args
+            .principals_path
+            .map(|path| read_principals(&path, map_format(&path, args.principals_format)))
+            .unwrap_or_default(),
+        client_certificate_header: args.client_certificate_header,
+        rate_limit: (args.rate_limit_hits.is_some() || args.rate_limit_misses.is_some()).then_some(
+            RateLimitOptions {
+                hits_per_minute: args.rate_limit_hits,
+                misses_per_minute: args.rate_limit_misses,
+                trusted_proxies: args.trusted_proxy,
+                message: args.rate_limit_message,
+            },
+        ),
+        miss_response_time: args.miss_response_time.map(Duration::from_millis),
+        limits: RequestLimits {
+            max_uri_length: args.max_uri_length,
+            max_rels: args.max_rels,
+            max_header_size: args.max_header_size,
+            request_timeout: Duration::from_millis(args.request_timeout),
+        },
+        cors: cors.into(),
+        trailing_slash: args.trailing_slash,
+        compression: args.compression.then_some(CompressionOptions {
+            min_size: args.compression_min_size,
+            max_cached: args.compression_cache_size,
+        }),
+        signing_key: args
+            .signing_key_path
+            .map(|path| read_signing_key(&path, args.signing_key_id)),
+        signature_scheme: args.signature_scheme,
+        previous_keys: args
+            .previous_jwks_path
+            .map(|path| read_jwk_set(&path))
+            .unwrap_or_default(),
+    };
+    let router = server::create_store_router(store, options);
+    let connection_limits = ConnectionLimits {
+        max_connections: args.max_connections,
+        header_read_timeout: Duration::from_millis(args.header_read_timeout),
+        connection_timeout: Duration::from_millis(args.connection_timeout),
+    };
 
-    #[tokio::test]
-    async fn duplicate_resource() {
-        let jm = jrdmap::from_json(
-            &r#"
-            {
-                "acct:other@example.com":{
-                    "subject": "acct:other@example.com"
-                }
-            }"#
-            .to_string(),
-        );
-        let router = create_router(jm);
-
-        let response = router
-            .oneshot(
-                Request::builder()
-                    .uri("/.well-known/webfinger?resource=acct:other@example.com&resource=acct:other@example.com")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-
-        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
-        let body = response.into_body().collect().await.unwrap().to_bytes();
-        assert_eq!(body, "Exactly one \"resource\" query parameter must be provided");
+    match admin {
+        Some((admin_listener, admin_router)) => {
+            // The admin API is subject to the same connection limits, but has its own
+            // allowance of connections so that it remains available to the operator
+            // while the public listener is saturated.
+            tokio::try_join!(
+                limits::serve(listener, router, connection_limits.clone()),
+                limits::serve(admin_listener, admin_router, connection_limits)
+            )?;
+            Ok(())
+        }
+        None => limits::serve(listener, router, connection_limits).await,
     }
+}
 
-    #[tokio::test]
-    async fn malformed_resource() {
-        let jm = jrdmap::from_json(
-            &r#"
-            {
-                "acct:other@example.com":{
-                    "subject": "acct:other@example.com"
+// Reload JRD map files as they, or their signature files, change. If a changed
+// map fails its integrity checks or is invalid, the previous map continues to be
+// served.
+fn watch_jrd_maps(
+    maps: Vec<(PathBuf, Format, Arc<JsonMapStore>)>,
+    integrity: MapIntegrity,
+) -> notify::Result<RecommendedWatcher> {
+    // Watch the directories containing the files, since files are often replaced
+    // by renaming a new file over them, and compare canonical paths with those of
+    // the events.
+    let maps = maps
+        .into_iter()
+        .map(|(path, format, store)| Ok((fs::canonicalize(path)?, format, store)))
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut dirs: Vec<PathBuf> = maps
+        .iter()
+        .filter_map(|(path, _, _)| path.parent().map(Path::to_path_buf))
+        .collect();
+    dirs.sort();
+    dirs.dedup();
+
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+        Ok(event) => {
+            for (path, format, store) in &maps {
+                let signature_path = integrity::signature_path(path);
+                if !event.paths.iter().any(|p| p == path || *p == signature_path) {
+                    continue;
                 }
-            }"#
-            .to_string(),
-        );
-        let router = create_router(jm);
-
-        let response = router
-            .oneshot(
-                Request::builder()
-                    .uri("/.well-known/webfinger?resource=alice@example.com") // resource not a URI according to RFC 3986
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-
-        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
-        let body = response.into_body().collect().await.unwrap().to_bytes();
-        assert_eq!(body, "Malformed \"resource\" query parameter");
+                if let Err(e) = integrity::reload(store, path, *format, &integrity) {
+                    eprintln!("{e}; continuing to serve the previous JRD map");
+                }
+            }
+        }
+        Err(e) => eprintln!("Error watching JRD map files: {e}"),
+    })?;
+    for dir in dirs {
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
     }
+    Ok(watcher)
+}
 
-    #[tokio::test]
-    async fn invalid_rel() {
-        let jm = jrdmap::from_json(
-            &r#"
-            {
-                "acct:other@example.com":{
-                    "subject": "acct:other@example.com"
+// Report each change to the store, such as one made via the admin API.
+fn log_changes(store: Arc<dyn JrdStore>) {
+    if let Some(mut changes) = store.subscribe() {
+        tokio::spawn(async move {
+            while changes.changed().await.is_ok() {
+                match store.digest().await {
+                    Ok(Some(digest)) => println!("JRD map changed ({digest})"),
+                    _ => println!("JRD map changed"),
                 }
-            }"#
-            .to_string(),
-        );
-        let router = create_router(jm);
-
-        let response = router
-            .oneshot(
-                Request::builder()
-                    .uri("/.well-known/webfinger?resource=acct:alice@example.com&rel=")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-
-        assert_eq!(response.status(), StatusCode::NOT_FOUND);
-        let body = response.into_body().collect().await.unwrap().to_bytes();
-        assert!(body.is_empty());
+            }
+        });
     }
+}
 
-    #[tokio::test]
-    async fn integration_test() {
-        let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
-        let addr = listener.local_addr().unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        tokio::spawn(async move {
-            let jm = jrdmap::from_json(
-                &r#"
-                {
-                    "acct:alice@example.com":{
-                        "subject": "acct:alice@example.com",
-                        "links": [
-                            {
-                                "rel":"http://webfinger.net/rel/avatar",
-                                "type":"image/jpeg",
-                                "href":"https://example.com/data/alice-avatar.jpeg"
-                            }
-                        ]
-                    }
-                }"#
-                .to_string(),
-            );
-            axum::serve(listener, create_router(jm)).await.unwrap();
-        });
+    #[test]
+    fn test_parse_cors_args() {
+        let cli = Cli::try_parse_from([
+            "webfinger-rs",
+            "--port",
+            "8080",
+            "--jrd-map-path",
+            "jrdmap.json",
+            "--cors-allowed-origin",
+            "https://app.example.com",
+            "--cors-expose-header",
+            "ETag",
+            "--cors-max-age",
+            "600",
+        ])
+        .unwrap();
+
+        assert_eq!(cli.args.unwrap().port, 8080);
+        assert_eq!(cli.cors.cors_allowed_origin, vec!["https://app.example.com"]);
+        assert_eq!(cli.cors.cors_expose_header, vec![HeaderName::from_static("etag")]);
+        assert_eq!(cli.cors.cors_max_age, Some(600));
+    }
 
-        let client =
-            hyper_util::client::legacy::Client::builder(hyper_util::rt::TokioExecutor::new())
-                .build_http();
-
-        let response = client
-            .request(
-                Request::builder()
-                    .uri(format!(
-                        "http://{addr}/.well-known/webfinger?resource=acct:alice@example.com"
-                    ))
-                    .header("Host", "localhost")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-
-        assert_eq!(response.status(), StatusCode::OK);
-        assert_eq!(
-            response.headers().get(CONTENT_TYPE).unwrap(),
-            "application/jrd+json"
-        );
-        assert_eq!(
-            response.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
-            "*"
-        );
+    #[test]
+    fn test_parse_cors_args_without_server_args() {
+        let result = Cli::try_parse_from(["webfinger-rs", "--cors-allowed-origin", "https://app.example.com"]);
 
-        let body = response.into_body().collect().await.unwrap().to_bytes();
-        let actual: Value = serde_json::from_str(str::from_utf8(&body[..]).unwrap()).unwrap();
-        let expected = json!({"subject":"acct:alice@example.com",
-            "links": [
-                {
-                    "rel":"http://webfinger.net/rel/avatar",
-                    "type":"image/jpeg",
-                    "href":"https://example.com/data/alice-avatar.jpeg"
-                }
-            ]
-        });
-        assert_eq!(actual, expected);
+        assert_eq!(result.unwrap_err().kind(), clap::error::ErrorKind::MissingRequiredArgument);
     }
 
     #[test]
-    fn test_valid_uri() {
-        assert_eq!(false, valid_uri(""));
-        assert_eq!(false, valid_uri("alice@example.org"));
-        assert_eq!(true, valid_uri("acct:alice@example.org"));
+    fn test_check_args_admin_map_format() {
+        let args = |map: &str, format: &[&str]| {
+            let mut argv = vec!["webfinger-rs", "--port", "8080", "--jrd-map-path", map];
+            argv.extend_from_slice(format);
+            argv.extend_from_slice(&["--admin-port", "8081", "--admin-token-path", "token"]);
+            Cli::try_parse_from(argv).unwrap().args.unwrap()
+        };
+
+        assert!(check_args(&args("jrdmap.json", &[])).is_ok());
+        assert!(check_args(&args("jrdmap", &[])).is_ok());
+        for args in [
+            args("jrdmap.yaml", &[]),
+            args("jrdmap.toml", &[]),
+            args("jrdmap", &["--jrd-map-format", "yaml"]),
+        ] {
+            assert_eq!(check_args(&args).unwrap_err().kind(), clap::error::ErrorKind::ArgumentConflict);
+        }
     }
 }
@@ -0,0 +1,67 @@
+/*
+Copyright 2024 Glyn Normington
+
+This file is part of webfinger-rs.
+
+webfinger-rs is free software: you can redistribute it and/or modify it under the terms
+of the GNU General Public License as published by the Free Software Foundation, either
+version 3 of the License, or (at your option) any later version.
+
+webfinger-rs is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with webfinger-rs.
+If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use axum::{body::Body, extract::State, http::StatusCode, response::Response};
+use hyper::header::CONTENT_TYPE;
+
+use crate::server::ServerState;
+
+/* Counters of notable events, reported in the Prometheus text format. */
+#[derive(Default)]
+pub(crate) struct Metrics {
+    // Resources without a scheme which were interpreted as acct URIs in lenient mode.
+    pub(crate) lenient_resources: AtomicU64,
+
+    // Requests refused because the client exceeded its rate limit.
+    pub(crate) rate_limited_requests: AtomicU64,
+}
+
+impl Metrics {
+    fn render(&self) -> String {
+        let mut s = String::new();
+        counter(
+            &mut s,
+            "webfinger_lenient_resources_total",
+            "Resources without a scheme interpreted as acct URIs in lenient mode.",
+            &self.lenient_resources,
+        );
+        counter(
+            &mut s,
+            "webfinger_rate_limited_requests_total",
+            "Requests refused because the client exceeded its rate limit.",
+            &self.rate_limited_requests,
+        );
+        s
+    }
+}
+
+fn counter(s: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    let _ = writeln!(s, "# HELP {name} {help}");
+    let _ = writeln!(s, "# TYPE {name} counter");
+    let _ = writeln!(s, "{name} {}", value.load(Ordering::Relaxed));
+}
+
+pub async fn metrics(State(state): State<ServerState>) -> Response {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(state.metrics.render()))
+        .unwrap()
+}
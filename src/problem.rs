@@ -0,0 +1,168 @@
+/*
+Copyright 2024 Glyn Normington
+
+This file is part of webfinger-rs.
+
+webfinger-rs is free software: you can redistribute it and/or modify it under the terms
+of the GNU General Public License as published by the Free Software Foundation, either
+version 3 of the License, or (at your option) any later version.
+
+webfinger-rs is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with webfinger-rs.
+If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Error responses, which are "problem details" as described in RFC 7807 for
+// clients which accept them and plain text otherwise.
+
+use axum::{body::Body, http::StatusCode, response::Response};
+use hyper::header::{HeaderMap, ACCEPT, CONTENT_TYPE};
+use serde_json::json;
+
+// Base URI of the problem types, which are described in the README.
+const PROBLEM_TYPE_BASE: &str = "https://github.com/glyn/webfinger-rs/blob/main/README.md#";
+
+pub(crate) struct Problem {
+    status: StatusCode,
+
+    // Name of the problem type, or None for a problem which has no meaning beyond
+    // its status code (the type "about:blank").
+    kind: Option<&'static str>,
+
+    title: &'static str,
+
+    detail: Option<String>,
+
+    // Body of the plain-text response.
+    text: String,
+}
+
+impl Problem {
+    pub(crate) fn new(status: StatusCode, kind: &'static str, title: &'static str) -> Problem {
+        Problem {
+            status,
+            kind: Some(kind),
+            title,
+            detail: None,
+            text: String::new(),
+        }
+    }
+
+    // A problem with no meaning beyond its status code.
+    pub(crate) fn status(status: StatusCode) -> Problem {
+        Problem {
+            status,
+            kind: None,
+            title: status.canonical_reason().unwrap_or_default(),
+            detail: None,
+            text: String::new(),
+        }
+    }
+
+    pub(crate) fn detail(mut self, detail: String) -> Problem {
+        self.detail = Some(detail);
+        self
+    }
+
+    pub(crate) fn text(mut self, text: impl Into<String>) -> Problem {
+        self.text = text.into();
+        self
+    }
+
+    // Respond with problem details if the request accepts them, otherwise with
+    // plain text.
+    pub(crate) fn respond(self, headers: &HeaderMap) -> Response {
+        let builder = Response::builder().status(self.status);
+        if !accepts_problem_json(headers) {
+            return builder
+                .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+                .body(Body::from(self.text))
+                .unwrap();
+        }
+
+        let kind = match self.kind {
+            Some(kind) => format!("{PROBLEM_TYPE_BASE}{kind}"),
+            None => "about:blank".to_string(),
+        };
+        let mut body = json!({
+            "type": kind,
+            "title": self.title,
+            "status": self.status.as_u16(),
+        });
+        if let Some(detail) = self.detail {
+            body["detail"] = detail.into();
+        }
+        builder
+            .header(CONTENT_TYPE, "application/problem+json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+}
+
+// Determine whether the Accept headers of a request include problem details,
+// either explicitly or as JSON, with a non-zero quality value. Wildcards are
+// not taken into account so that clients which do not ask for problem details
+// continue to receive plain text.
+fn accepts_problem_json(headers: &HeaderMap) -> bool {
+    accepts(headers, &["application/problem+json", "application/json"])
+}
+
+// Determine whether the Accept headers of a request name any of the given media
+// types with a non-zero quality value. Wildcards are not taken into account.
+pub(crate) fn accepts(headers: &HeaderMap, media_types: &[&str]) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|range| {
+            let mut parts = range.split(';');
+            let media_type = parts.next().unwrap_or_default().trim();
+            let zero_quality = parts.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            !zero_quality && media_types.iter().any(|t| media_type.eq_ignore_ascii_case(t))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn accept(values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(ACCEPT, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_accepts_problem_json() {
+        assert!(accepts_problem_json(&accept(&["application/problem+json"])));
+        assert!(accepts_problem_json(&accept(&["application/jrd+json, application/json;q=0.5"])));
+        assert!(accepts_problem_json(&accept(&["text/html", "Application/Problem+JSON"])));
+        assert!(!accepts_problem_json(&accept(&[])));
+        assert!(!accepts_problem_json(&accept(&["*/*"])));
+        assert!(!accepts_problem_json(&accept(&["application/jrd+json"])));
+        assert!(!accepts_problem_json(&accept(&["application/problem+json; q=0"])));
+        assert!(!accepts_problem_json(&accept(&["application/problem+json;q=0.0"])));
+    }
+
+    #[test]
+    fn test_accepts() {
+        let jose = ["application/jose+json"];
+        assert!(accepts(&accept(&["application/jrd+json, application/jose+json;q=0.9"]), &jose));
+        assert!(accepts(&accept(&["Application/JOSE+JSON ; q=1"]), &jose));
+        assert!(!accepts(&accept(&["application/jose+json; q=0"]), &jose));
+        assert!(!accepts(&accept(&["application/*"]), &jose));
+    }
+}
@@ -0,0 +1,454 @@
+/*
+Copyright 2024 Glyn Normington
+
+This file is part of webfinger-rs.
+
+webfinger-rs is free software: you can redistribute it and/or modify it under the terms
+of the GNU General Public License as published by the Free Software Foundation, either
+version 3 of the License, or (at your option) any later version.
+
+webfinger-rs is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with webfinger-rs.
+If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Protection against enumeration of the resources a server knows about. Each
+// client IP address has two token buckets: one for hits (requests answered with
+// a JRD) and one for misses (all other requests), so that a client probing for
+// resources which do not exist is throttled long before one which looks up
+// resources it knows. A client with an empty bucket of either kind is refused,
+// since answering its misses but not its hits, or vice versa, would reveal which
+// resources exist. Misses may also be padded to a uniform response time so that
+// their timing does not distinguish, for example, a resource which is hidden from
+// one which does not exist.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use hyper::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+
+use crate::problem::Problem;
+use crate::server::ServerState;
+
+// Header in which a reverse proxy passes the addresses of the client and of any
+// proxies the request passed through.
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+// How often buckets which have refilled are discarded.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/* Options controlling rate limiting. A limit of None means the corresponding
+requests are not limited. */
+#[derive(Clone, Debug, Default)]
+pub struct RateLimitOptions {
+    // Number of hits per minute allowed for each client, which is also the number
+    // which may be made in a burst.
+    pub hits_per_minute: Option<u32>,
+
+    // Number of misses per minute allowed for each client, which is also the number
+    // which may be made in a burst.
+    pub misses_per_minute: Option<u32>,
+
+    // Reverse proxies whose X-Forwarded-For headers are trusted to identify the client.
+    pub trusted_proxies: Vec<IpNet>,
+
+    // Explanation given to a client whose request is refused, in place of the default.
+    pub message: Option<String>,
+}
+
+/* An IpNet is an IP address range written in CIDR notation, such as 10.0.0.0/8,
+or a single IP address. */
+#[derive(Clone, Debug, PartialEq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<IpNet, String> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| format!("invalid IP address {addr:?}"))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("invalid prefix length {len:?}"))?,
+            None => max_len,
+        };
+        Ok(IpNet { addr, prefix_len })
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(capacity: u32, now: Instant) -> Bucket {
+        Bucket {
+            tokens: f64::from(capacity),
+            updated: now,
+        }
+    }
+
+    // Add the tokens accrued since the bucket was last updated, at a rate of
+    // capacity per minute.
+    fn refill(&mut self, capacity: u32, now: Instant) {
+        let capacity = f64::from(capacity);
+        let accrued = now.duration_since(self.updated).as_secs_f64() * capacity / 60.0;
+        self.tokens = (self.tokens + accrued).min(capacity);
+        self.updated = now;
+    }
+
+    // Time until the bucket contains a whole token.
+    fn wait(&self, capacity: u32) -> Duration {
+        if self.tokens >= 1.0 || capacity == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((1.0 - self.tokens) * 60.0 / f64::from(capacity))
+    }
+}
+
+/* The identity by which a client is rate limited: normally its IP address but,
+if a trusted proxy forwarded the request from a client which it identified by
+something other than an IP address, the proxy's identification of the client. */
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Client {
+    Addr(IpAddr),
+    Forwarded(String),
+}
+
+struct Buckets {
+    hits: Bucket,
+    misses: Bucket,
+}
+
+/* A RateLimiter holds the buckets of each client. */
+pub(crate) struct RateLimiter {
+    options: RateLimitOptions,
+    clients: Mutex<HashMap<Client, Buckets>>,
+    pruned: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(options: RateLimitOptions) -> RateLimiter {
+        RateLimiter {
+            options,
+            clients: Mutex::new(HashMap::new()),
+            pruned: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn capacities(&self) -> (u32, u32) {
+        (
+            self.options.hits_per_minute.unwrap_or(u32::MAX),
+            self.options.misses_per_minute.unwrap_or(u32::MAX),
+        )
+    }
+
+    // Admit a request from a client, or return how long the client must wait.
+    fn admit(&self, client: &Client, now: Instant) -> Result<(), Duration> {
+        self.prune(now);
+        let (hit_capacity, miss_capacity) = self.capacities();
+        let mut clients = self.clients.lock().unwrap();
+        let buckets = clients.entry(client.clone()).or_insert_with(|| Buckets {
+            hits: Bucket::full(hit_capacity, now),
+            misses: Bucket::full(miss_capacity, now),
+        });
+        buckets.hits.refill(hit_capacity, now);
+        buckets.misses.refill(miss_capacity, now);
+        let wait = buckets.hits.wait(hit_capacity).max(buckets.misses.wait(miss_capacity));
+        if wait.is_zero() {
+            Ok(())
+        } else {
+            Err(wait)
+        }
+    }
+
+    // Charge a client for a request once its outcome is known. Concurrent requests
+    // may take a bucket below zero, which delays the client's next request.
+    fn charge(&self, client: &Client, hit: bool) {
+        if let Some(buckets) = self.clients.lock().unwrap().get_mut(client) {
+            let bucket = if hit { &mut buckets.hits } else { &mut buckets.misses };
+            bucket.tokens -= 1.0;
+        }
+    }
+
+    // Discard the buckets of clients which have not made requests for long enough
+    // for their buckets to refill, so that memory use is bounded by the number of
+    // recently active clients.
+    fn prune(&self, now: Instant) {
+        let mut pruned = self.pruned.lock().unwrap();
+        if now.duration_since(*pruned) < PRUNE_INTERVAL {
+            return;
+        }
+        *pruned = now;
+        let (hit_capacity, miss_capacity) = self.capacities();
+        self.clients.lock().unwrap().retain(|_, buckets| {
+            buckets.hits.refill(hit_capacity, now);
+            buckets.misses.refill(miss_capacity, now);
+            buckets.hits.tokens < f64::from(hit_capacity)
+                || buckets.misses.tokens < f64::from(miss_capacity)
+        });
+    }
+
+    // Identify the client which made a request. If the peer is a trusted proxy,
+    // X-Forwarded-For is read from right to left, since each proxy appends the
+    // address it received the request from, and the client is the first entry
+    // which is not itself a trusted proxy. Entries to the left of that were
+    // supplied by the client and cannot be trusted. An entry which is not an IP
+    // address identifies the client as it stands, and if every entry is a trusted
+    // proxy, the client is the first.
+    fn client(&self, peer: IpAddr, headers: &HeaderMap) -> Client {
+        let trusted = |addr: IpAddr| self.options.trusted_proxies.iter().any(|net| net.contains(addr));
+        let mut client = Client::Addr(peer);
+        if !trusted(peer) {
+            return client;
+        }
+        let forwarded: Vec<&str> = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .collect();
+        for entry in forwarded.into_iter().rev() {
+            match entry.parse() {
+                Ok(addr) => {
+                    client = Client::Addr(addr);
+                    if !trusted(addr) {
+                        break;
+                    }
+                }
+                Err(_) => {
+                    client = Client::Forwarded(entry.to_string());
+                    break;
+                }
+            }
+        }
+        client
+    }
+}
+
+// Apply rate limiting and uniform miss timing to WebFinger requests. Requests
+// served without connection information, such as by a router which has been
+// nested in an application served without it, are not rate limited.
+pub(crate) async fn limit(
+    State(state): State<ServerState>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    let client = match (&state.rate_limiter, peer) {
+        (Some(limiter), Some(ConnectInfo(peer))) => {
+            let client = limiter.client(peer.ip(), request.headers());
+            if let Err(wait) = limiter.admit(&client, start) {
+                state.metrics.rate_limited_requests.fetch_add(1, Ordering::Relaxed);
+                return rate_limited(request.headers(), wait, limiter.options.message.as_deref());
+            }
+            Some((limiter, client))
+        }
+        _ => None,
+    };
+
+    let response = next.run(request).await;
+
+    let hit = response.status().is_success();
+    if let Some((limiter, client)) = client {
+        limiter.charge(&client, hit);
+    }
+    if let Some(miss_response_time) = state.options.miss_response_time {
+        if !hit {
+            tokio::time::sleep_until((start + miss_response_time).into()).await;
+        }
+    }
+    response
+}
+
+fn rate_limited(headers: &HeaderMap, wait: Duration, message: Option<&str>) -> Response {
+    // Round up, so that a client which waits as requested is admitted.
+    let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    let detail = match message {
+        Some(message) => message.to_string(),
+        None => format!("Too many requests have been made by this client; retry after {seconds} seconds"),
+    };
+    let mut response = Problem::new(StatusCode::TOO_MANY_REQUESTS, "rate-limited", "Too many requests")
+        .detail(detail)
+        .text(message.unwrap_or("Too many requests"))
+        .respond(headers);
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(seconds));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn client_at(s: &str) -> Client {
+        Client::Addr(addr(s))
+    }
+
+    #[test]
+    fn test_ip_net() {
+        let net: IpNet = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(addr("10.1.2.3")));
+        assert!(!net.contains(addr("10.2.0.1")));
+        assert!(!net.contains(addr("::ffff:10.1.2.3")));
+
+        let net: IpNet = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains(addr("2001:db8:1::1")));
+        assert!(!net.contains(addr("2001:db9::1")));
+
+        let net: IpNet = "127.0.0.1".parse().unwrap();
+        assert!(net.contains(addr("127.0.0.1")));
+        assert!(!net.contains(addr("127.0.0.2")));
+
+        assert!("0.0.0.0/0".parse::<IpNet>().unwrap().contains(addr("192.0.2.1")));
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("example.com".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn test_separate_budgets() {
+        let limiter = RateLimiter::new(RateLimitOptions {
+            hits_per_minute: Some(3),
+            misses_per_minute: Some(1),
+            ..Default::default()
+        });
+        let client = client_at("192.0.2.1");
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.admit(&client, now).is_ok());
+            limiter.charge(&client, true);
+        }
+        assert!(limiter.admit(&client, now).is_err());
+
+        // A second client has its own buckets. Once its miss is charged, it is
+        // refused even for hits.
+        let other = client_at("192.0.2.2");
+        assert!(limiter.admit(&other, now).is_ok());
+        limiter.charge(&other, false);
+        assert_eq!(limiter.admit(&other, now), Err(Duration::from_secs(60)));
+        assert_eq!(
+            limiter.admit(&other, now + Duration::from_secs(45)),
+            Err(Duration::from_secs(15))
+        );
+        assert!(limiter.admit(&other, now + Duration::from_secs(60)).is_ok());
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = RateLimiter::new(RateLimitOptions {
+            misses_per_minute: Some(1),
+            ..Default::default()
+        });
+        let client = client_at("192.0.2.1");
+        for _ in 0..1000 {
+            assert!(limiter.admit(&client, Instant::now()).is_ok());
+            limiter.charge(&client, true);
+        }
+    }
+
+    #[test]
+    fn test_prune() {
+        let limiter = RateLimiter::new(RateLimitOptions {
+            hits_per_minute: Some(1),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        limiter.admit(&client_at("192.0.2.1"), now).unwrap();
+        let later = now + Duration::from_secs(50);
+        limiter.admit(&client_at("192.0.2.2"), later).unwrap();
+        limiter.charge(&client_at("192.0.2.2"), true);
+
+        // Only the bucket of the client which made a request recently has yet to refill.
+        limiter.prune(now + PRUNE_INTERVAL);
+        assert_eq!(limiter.clients.lock().unwrap().len(), 1);
+        limiter.prune(now + PRUNE_INTERVAL * 2);
+        assert!(limiter.clients.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_client() {
+        let limiter = RateLimiter::new(RateLimitOptions {
+            trusted_proxies: vec!["127.0.0.1".parse().unwrap(), "10.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        });
+        let forwarded_for = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(X_FORWARDED_FOR, HeaderValue::from_static(value));
+            headers
+        };
+        let headers = forwarded_for("203.0.113.9, 198.51.100.7, 10.0.0.2");
+
+        assert_eq!(limiter.client(addr("127.0.0.1"), &headers), client_at("198.51.100.7"));
+        // Forwarded addresses are ignored unless the peer is trusted.
+        assert_eq!(limiter.client(addr("192.0.2.1"), &headers), client_at("192.0.2.1"));
+        assert_eq!(limiter.client(addr("127.0.0.1"), &HeaderMap::new()), client_at("127.0.0.1"));
+
+        // Entries supplied by the client, to the left of the address the proxy
+        // received the request from, are ignored, even if they are not addresses.
+        let headers = forwarded_for("junk, 198.51.100.7");
+        assert_eq!(limiter.client(addr("127.0.0.1"), &headers), client_at("198.51.100.7"));
+        let headers = forwarded_for("10.0.0.3, 198.51.100.7");
+        assert_eq!(limiter.client(addr("127.0.0.1"), &headers), client_at("198.51.100.7"));
+
+        // An entry which is not an address identifies the client, rather than the
+        // proxy being charged for the request.
+        let headers = forwarded_for("198.51.100.7, unknown, 10.0.0.2");
+        assert_eq!(
+            limiter.client(addr("127.0.0.1"), &headers),
+            Client::Forwarded("unknown".to_string())
+        );
+
+        // If every entry is a trusted proxy, the client is the first.
+        let headers = forwarded_for("10.0.0.3, 10.0.0.2");
+        assert_eq!(limiter.client(addr("127.0.0.1"), &headers), client_at("10.0.0.3"));
+    }
+}
@@ -15,37 +15,147 @@ You should have received a copy of the GNU General Public License along with web
 If not, see <https://www.gnu.org/licenses/>.
 */
 
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
 use fluent_uri::Uri;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::link_relations::LINK_RELATIONS;
+
+// The generator of the table of registered relation types, so that the table can
+// be checked against it.
+#[cfg(test)]
+#[allow(dead_code)]
+#[path = "../examples/gen_link_relations.rs"]
+mod gen_link_relations;
+
+/* A Rel is a link relation type: either a registered relation type, such as "me",
+or an extension relation type, which is a URI. Each is normalized once, when it
+is constructed, and relation types are equal if their normalized forms are equal:
+registered relation types are compared case-insensitively and extension relation
+types are compared as normalized URIs. */
+#[derive(Clone, Debug)]
+pub enum Rel {
+    Registered {
+        // The relation type as written.
+        rel: String,
 
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
-pub struct Rel{
-    rel: String,
+        // The relation type in lower case.
+        normalized: String,
+    },
+    Extension {
+        // The URI as written.
+        rel: String,
+
+        // The URI in normal form.
+        normalized: String,
+    },
 }
 
 pub fn make_rel(v: String) -> Rel {
-    Rel{rel: v.clone()}
+    Rel::new(v)
 }
 
-impl PartialEq for Rel {
-    fn eq(&self, other: &Self) -> bool {
+impl Rel {
+    pub fn new(rel: String) -> Rel {
         // Detect extension relation types to be URIs.
-        if let Ok(self_uri_reference) = Uri::parse(self.rel.clone()) {
-            if let Ok(other_uri_reference) = Uri::parse(other.rel.clone()) {
-                if self_uri_reference.has_scheme() && other_uri_reference.has_scheme() {
-                    eprintln!("comparing normalized URI references {} and {}", self_uri_reference.normalize(), other_uri_reference.normalize());
-                    return self_uri_reference.normalize() == other_uri_reference.normalize()
-                }
+        if let Ok(uri_reference) = Uri::parse(rel.as_str()) {
+            if uri_reference.has_scheme() {
+                let normalized = uri_reference.normalize().to_string();
+                return Rel::Extension { rel, normalized };
             }
         }
+        let normalized = rel.to_ascii_lowercase();
+        Rel::Registered { rel, normalized }
+    }
+
+    // The relation type as written.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Rel::Registered { rel, .. } | Rel::Extension { rel, .. } => rel,
+        }
+    }
+
+    // The normalized form of the relation type, which determines equality.
+    pub fn normalized(&self) -> &str {
+        match self {
+            Rel::Registered { normalized, .. } | Rel::Extension { normalized, .. } => normalized,
+        }
+    }
+
+    pub fn is_extension(&self) -> bool {
+        matches!(self, Rel::Extension { .. })
+    }
+
+    // The description of the relation type in the IANA Link Relations registry, or
+    // None if it is not a registered relation type.
+    pub fn description(&self) -> Option<&'static str> {
+        match self {
+            Rel::Registered { normalized, .. } => LINK_RELATIONS
+                .binary_search_by(|(value, _)| {
+                    value
+                        .bytes()
+                        .map(|b| b.to_ascii_lowercase())
+                        .cmp(normalized.bytes())
+                })
+                .ok()
+                .map(|i| LINK_RELATIONS[i].1),
+            Rel::Extension { .. } => None,
+        }
+    }
+
+    // Determine whether the relation type is in the IANA Link Relations registry.
+    pub fn is_registered(&self) -> bool {
+        self.description().is_some()
+    }
+}
+
+impl PartialEq for Rel {
+    fn eq(&self, other: &Self) -> bool {
+        self.is_extension() == other.is_extension() && self.normalized() == other.normalized()
+    }
+}
+
+impl Eq for Rel {}
+
+// Hash the same parts as are compared for equality, so that equal relation types
+// have equal hashes.
+impl Hash for Rel {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.is_extension().hash(state);
+        self.normalized().hash(state);
+    }
+}
+
+impl fmt::Display for Rel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+// A relation type in the IANA Link Relations registry is serialized in lower case
+// and any other relation type is serialized as written.
+impl Serialize for Rel {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.is_registered() {
+            serializer.serialize_str(self.normalized())
+        } else {
+            serializer.serialize_str(self.as_str())
+        }
+    }
+}
 
-        // Compare registered relation types as case-insensitive strings.
-        self.rel.eq_ignore_ascii_case(&other.rel)
+impl<'de> Deserialize<'de> for Rel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Rel, D::Error> {
+        Ok(Rel::new(String::deserialize(deserializer)?))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn test_equality_registered_strings_equal() {
@@ -99,4 +209,130 @@ mod tests {
         assert_eq!(make_rel("example://a/%62".to_string()), make_rel("example://a/b".to_string()));
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_kind() {
+        assert!(!make_rel("me".to_string()).is_extension());
+        assert!(make_rel("http://example.com/reltype".to_string()).is_extension());
+    }
+
+    #[test]
+    fn test_serialization_preserves_original() {
+        let rel: Rel = serde_json::from_str(r#""HTTP://Example.com/./reltype""#).unwrap();
+        assert_eq!(serde_json::to_string(&rel).unwrap(), r#""HTTP://Example.com/./reltype""#);
+    }
+
+    #[test]
+    fn test_description() {
+        assert_eq!(
+            make_rel("Self".to_string()).description(),
+            Some("Conveys an identifier for the link's context.")
+        );
+        assert!(make_rel("intervalafter".to_string()).is_registered());
+        assert!(!make_rel("avtar".to_string()).is_registered());
+        assert!(!make_rel("http://webfinger.net/rel/avatar".to_string()).is_registered());
+    }
+
+    #[test]
+    fn test_registered_serialized_in_lower_case() {
+        let rels: Vec<Rel> = serde_json::from_str(r#"["SELF","Avtar"]"#).unwrap();
+        assert_eq!(serde_json::to_string(&rels).unwrap(), r#"["self","Avtar"]"#);
+    }
+
+    #[test]
+    fn test_link_relations_sorted() {
+        for pair in LINK_RELATIONS.windows(2) {
+            assert!(pair[0].0.to_ascii_lowercase() < pair[1].0.to_ascii_lowercase());
+        }
+    }
+
+    #[test]
+    fn test_generate_link_relations() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <registry xmlns="http://www.iana.org/assignments" id="link-relations">
+              <registry id="link-relations-1">
+                <record>
+                  <value>self</value>
+                  <description>Conveys an identifier
+                    for the link's context.</description>
+                  <spec><xref type="rfc" data="rfc4287"/></spec>
+                </record>
+                <record>
+                  <value>intervalAfter</value>
+                  <description>refers to a resource &amp; <xref type="uri" data="x"/>more</description>
+                </record>
+                <record>
+                  <value>about</value>
+                  <description>Refers to a resource that is the subject of the link's context.</description>
+                </record>
+              </registry>
+            </registry>"#;
+
+        let source = gen_link_relations::generate(xml);
+
+        let entries: Vec<&str> = source.lines().filter(|line| line.starts_with("    (")).collect();
+        assert_eq!(
+            entries,
+            [
+                r#"    ("about", "Refers to a resource that is the subject of the link's context."),"#,
+                r#"    ("intervalAfter", "refers to a resource & more"),"#,
+                r#"    ("self", "Conveys an identifier for the link's context."),"#,
+            ]
+        );
+    }
+
+    // Check that the table is the generator's output for the registry. This needs
+    // a copy of https://www.iana.org/assignments/link-relations/link-relations.xml
+    // saved in the root of the repository.
+    #[test]
+    #[ignore = "needs link-relations.xml downloaded from IANA"]
+    fn test_link_relations_generated() {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("link-relations.xml");
+        let xml = std::fs::read_to_string(path).unwrap();
+        assert_eq!(gen_link_relations::generate(&xml), include_str!("link_relations.rs"));
+    }
+
+    fn hash(rel: &Rel) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        rel.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    // Generate relation types which are often equal without being identical.
+    fn rel_strategy() -> impl Strategy<Value = Rel> {
+        let registered = prop::sample::select(vec!["me", "ME", "Me", "self", "SELF", "author"])
+            .prop_map(str::to_string);
+        let extension = (
+            prop::sample::select(vec!["http", "HTTP", "example"]),
+            prop::sample::select(vec!["a", "A", "example.com"]),
+            prop::sample::select(vec!["/b", "/./b", "/c/../b", "/%62", "/%7Bfoo%7D", "/%7bfoo%7d", "/{foo}"]),
+        )
+            .prop_map(|(scheme, host, path)| format!("{scheme}://{host}{path}"));
+        prop_oneof![registered, extension].prop_map(Rel::new)
+    }
+
+    proptest! {
+        #[test]
+        fn prop_equality_reflexive(a in rel_strategy()) {
+            prop_assert_eq!(&a, &a.clone());
+        }
+
+        #[test]
+        fn prop_equality_symmetric(a in rel_strategy(), b in rel_strategy()) {
+            prop_assert_eq!(a == b, b == a);
+        }
+
+        #[test]
+        fn prop_equality_transitive(a in rel_strategy(), b in rel_strategy(), c in rel_strategy()) {
+            if a == b && b == c {
+                prop_assert_eq!(&a, &c);
+            }
+        }
+
+        #[test]
+        fn prop_hash_consistent_with_equality(a in rel_strategy(), b in rel_strategy()) {
+            if a == b {
+                prop_assert_eq!(hash(&a), hash(&b));
+            }
+        }
+    }
+}
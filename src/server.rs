@@ -0,0 +1,2290 @@
+/*
+Copyright 2024 Glyn Normington
+
+This file is part of webfinger-rs.
+
+webfinger-rs is free software: you can redistribute it and/or modify it under the terms
+of the GNU General Public License as published by the Free Software Foundation, either
+version 3 of the License, or (at your option) any later version.
+
+webfinger-rs is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with webfinger-rs.
+If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{OriginalUri, Request, State},
+    http::{Method, StatusCode, Uri},
+    middleware::{self, Next},
+    response::Response,
+    routing::{any, get, MethodRouter},
+    Router,
+};
+use axum_extra::extract::Query;
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCEPT_LANGUAGE, ALLOW, AUTHORIZATION, LOCATION, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_TYPE, VARY, WWW_AUTHENTICATE,
+};
+use serde::Deserialize;
+use tokio::runtime::Handle;
+
+use crate::access::Principals;
+use crate::acct::{self, AcctUri};
+use crate::compression::{self, CompressionOptions, ResponseCache};
+use crate::cors::{self, CorsOptions};
+use crate::health;
+use crate::metrics::{self, Metrics};
+use crate::limits::{self, RequestLimits};
+use crate::problem::{self, Problem};
+use crate::ratelimit::{self, RateLimitOptions, RateLimiter};
+use crate::jrdmap::{self, Jrd, JrdMap};
+use crate::httpsig::{self, SignatureScheme};
+use crate::jws::{self, JwkSet, SigningKey};
+use crate::language;
+use crate::store::{JrdStore, JsonMapStore, StoreError};
+
+/* Options controlling how the server handles requests. */
+#[derive(Clone, Debug, Default)]
+pub struct ServerOptions {
+    // Interpret a resource without a scheme of the form user@host or @user@host
+    // as an acct URI rather than rejecting it as malformed.
+    pub lenient: bool,
+
+    // Write JRDs in canonical JSON form, with the members of every object sorted by
+    // name, rather than in the order in which RFC 7033 defines them.
+    pub canonical_json: bool,
+
+    // Reduce the titles of each link to the one in the language which best matches
+    // the request's Accept-Language header.
+    pub select_title_language: bool,
+
+    // Principals which may see JRDs and links which are not public. If there are
+    // none, every request is treated as anonymous.
+    pub principals: Principals,
+
+    // Request header in which a TLS-terminating reverse proxy identifies the
+    // client certificate presented by the client.
+    pub client_certificate_header: Option<HeaderName>,
+
+    // Limit the rate of requests from each client. The router must be served with
+    // connection information (see Router::into_make_service_with_connect_info) for
+    // this to take effect.
+    pub rate_limit: Option<RateLimitOptions>,
+
+    // Delay responses other than successful ones until at least this long after
+    // the request was received, so that their timing is uniform.
+    pub miss_response_time: Option<Duration>,
+
+    // Limits on the size of requests and the time taken to answer them.
+    pub limits: RequestLimits,
+
+    // Cross-origin resource sharing policy of the well-known routes.
+    pub cors: CorsOptions,
+
+    // How requests for paths with a trailing slash, such as /.well-known/webfinger/,
+    // are handled.
+    pub trailing_slash: TrailingSlash,
+
+    // Compress JRDs for clients which accept a supported content coding.
+    pub compression: Option<CompressionOptions>,
+
+    // Sign JRDs with this key, and publish its public key at /.well-known/jwks.json.
+    pub signing_key: Option<SigningKey>,
+
+    // How JRD responses are signed.
+    pub signature_scheme: SignatureScheme,
+
+    // Public keys of earlier signing keys, published alongside the signing key so
+    // that responses signed with them, for example by caches, can still be verified.
+    pub previous_keys: JwkSet,
+}
+
+/* TrailingSlash determines how a request for a path with an extra trailing slash
+is handled. */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum TrailingSlash {
+    // Respond with 404 (Not Found).
+    #[default]
+    NotFound,
+
+    // Redirect to the path without the trailing slash.
+    Redirect,
+
+    // Handle the request as if the path had no trailing slash.
+    Ignore,
+}
+
+// Media type of a JRD.
+const JRD_JSON: &str = "application/jrd+json";
+
+// Methods allowed on the WebFinger route.
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+
+#[derive(Clone)]
+pub(crate) struct ServerState {
+    pub(crate) store: Arc<dyn JrdStore>,
+    pub(crate) options: Arc<ServerOptions>,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) rate_limiter: Option<Arc<RateLimiter>>,
+    pub(crate) response_cache: Option<Arc<ResponseCache>>,
+}
+
+impl ServerState {
+    fn new(store: Arc<dyn JrdStore>, options: ServerOptions) -> ServerState {
+        let options = Arc::new(options);
+        let response_cache = options
+            .compression
+            .clone()
+            .map(|compression| Arc::new(ResponseCache::new(compression)));
+        // Compress responses in advance, unless there is no runtime to do so, in
+        // which case responses are only compressed as they are requested.
+        if let (Some(cache), Ok(runtime)) = (&response_cache, Handle::try_current()) {
+            runtime.spawn(compression::keep_loaded(cache.clone(), store.clone(), options.clone()));
+        }
+        ServerState {
+            store,
+            rate_limiter: options
+                .rate_limit
+                .clone()
+                .map(|rate_limit| Arc::new(RateLimiter::new(rate_limit))),
+            options,
+            metrics: Arc::new(Metrics::default()),
+            response_cache,
+        }
+    }
+
+    // The route which answers WebFinger requests.
+    fn webfinger_route(&self) -> MethodRouter<ServerState> {
+        self.well_known(
+            get(handler)
+                .options(options)
+                .fallback(method_not_allowed)
+                .layer(middleware::from_fn_with_state(self.clone(), limits::check))
+                .layer(middleware::from_fn_with_state(self.clone(), ratelimit::limit))
+                .layer(middleware::from_fn_with_state(self.clone(), sign)),
+        )
+    }
+
+    // Apply the CORS policy to a route under /.well-known. This is the outermost
+    // layer so that preflight requests are answered without further processing.
+    fn well_known(&self, route: MethodRouter<ServerState>) -> MethodRouter<ServerState> {
+        route.layer(middleware::from_fn_with_state(self.clone(), cors::apply))
+    }
+
+    // Route a path and, if the trailing slash option requires it, the same path
+    // with a trailing slash.
+    fn route(
+        &self,
+        router: Router<ServerState>,
+        path: &str,
+        method_router: MethodRouter<ServerState>,
+    ) -> Router<ServerState> {
+        let slashed = format!("{path}/");
+        match self.options.trailing_slash {
+            TrailingSlash::NotFound => router.route(path, method_router),
+            TrailingSlash::Redirect => router
+                .route(path, method_router)
+                .route(&slashed, any(remove_trailing_slash)),
+            TrailingSlash::Ignore => router
+                .route(path, method_router.clone())
+                .route(&slashed, method_router),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Params {
+    #[serde(default)]
+    resource: Vec<String>,
+
+    #[serde(default)]
+    rel: Vec<String>,
+}
+
+// Create a router which serves the JRDs of a JRD map at /.well-known/webfinger,
+// together with the /healthz, /readyz, and /metrics endpoints, using the default
+// options.
+pub fn create_router(jm: JrdMap) -> Router {
+    create_store_router(Arc::new(JsonMapStore::new(jm)), ServerOptions::default())
+}
+
+// Create a router which serves the JRDs of a store at /.well-known/webfinger,
+// together with the /healthz, /readyz, and /metrics endpoints.
+pub fn create_store_router(store: Arc<dyn JrdStore>, options: ServerOptions) -> Router {
+    let state = ServerState::new(store, options);
+
+    let router = Router::new();
+    let router = state.route(router, "/.well-known/webfinger", state.webfinger_route());
+    let router = state.route(router, "/healthz", get(health::healthz));
+    let router = state.route(router, "/readyz", get(health::readyz));
+    let router = state.route(router, "/metrics", get(metrics::metrics));
+    let router = if state.options.signing_key.is_some() {
+        state.route(router, jws::JWKS_PATH, state.well_known(get(jwk_set)))
+    } else {
+        router
+    };
+    router.fallback(not_found).with_state(state)
+}
+
+// Create a router which serves WebFinger requests at its root, for nesting at
+// /.well-known/webfinger in another application's router, for example:
+//
+//     let app = Router::new().nest("/.well-known/webfinger", webfinger_router(store, options));
+pub fn webfinger_router(store: Arc<dyn JrdStore>, options: ServerOptions) -> Router {
+    let state = ServerState::new(store, options);
+
+    Router::new().route("/", state.webfinger_route()).with_state(state)
+}
+
+// Answer a request which is not a CORS preflight request with the allowed methods.
+async fn options() -> Response {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(ALLOW, ALLOWED_METHODS)
+        .body(Body::empty())
+        .unwrap()
+}
+
+async fn method_not_allowed(method: Method, headers: HeaderMap) -> Response {
+    let mut response = Problem::status(StatusCode::METHOD_NOT_ALLOWED)
+        .detail(format!("The method {method} is not allowed; use one of {ALLOWED_METHODS}"))
+        .text("Method not allowed")
+        .respond(&headers);
+    response
+        .headers_mut()
+        .insert(ALLOW, HeaderValue::from_static(ALLOWED_METHODS));
+    response
+}
+
+// Publish the public key with which JRDs are signed.
+async fn jwk_set(State(state): State<ServerState>) -> Response {
+    let mut jwk_set = state.options.signing_key.as_ref().map(SigningKey::jwk_set).unwrap_or_default();
+    jwk_set.keys.extend(state.options.previous_keys.keys.iter().cloned());
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, jws::JWK_SET_JSON)
+        .body(Body::from(serde_json::to_string(&jwk_set).unwrap()))
+        .unwrap()
+}
+
+async fn not_found(headers: HeaderMap) -> Response {
+    Problem::status(StatusCode::NOT_FOUND).respond(&headers)
+}
+
+// Redirect to the request's path without its trailing slash, keeping the query.
+async fn remove_trailing_slash(uri: Uri) -> Response {
+    let path = uri.path().trim_end_matches('/');
+    let location = match uri.query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
+    };
+    Response::builder()
+        .status(StatusCode::PERMANENT_REDIRECT)
+        .header(LOCATION, location)
+        .body(Body::empty())
+        .unwrap()
+}
+
+async fn handler(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Query(params): Query<Params>,
+) -> Response {
+    let authenticated = if state.options.principals.is_empty() {
+        Ok(None)
+    } else {
+        state
+            .options
+            .principals
+            .authenticate(&headers, state.options.client_certificate_header.as_ref())
+    };
+    let (principal, mut response) = match authenticated {
+        Ok(principal) => {
+            let response = answer(&state, &headers, params, principal.as_deref()).await;
+            (principal, response)
+        }
+        Err(e) => {
+            let mut response = Problem::new(StatusCode::UNAUTHORIZED, "invalid-credentials", "Invalid credentials")
+                .detail(format!("The request could not be authenticated: {e}"))
+                .text("Invalid credentials")
+                .respond(&headers);
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            (None, response)
+        }
+    };
+
+    // Every response, including one reporting that a resource was not found,
+    // depends on the client's credentials when there are principals, so shared
+    // caches must distinguish them, and must not store responses to clients
+    // which authenticated.
+    if !state.options.principals.is_empty() {
+        let response_headers = response.headers_mut();
+        response_headers.append(VARY, HeaderValue::from(AUTHORIZATION));
+        if let Some(name) = &state.options.client_certificate_header {
+            response_headers.append(VARY, HeaderValue::from(name.clone()));
+        }
+    }
+    if principal.is_some() {
+        response
+            .headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("private"));
+    }
+    response
+}
+
+// Answer a WebFinger request from a client which has been authenticated as the
+// given principal, if any.
+async fn answer(
+    state: &ServerState,
+    headers: &HeaderMap,
+    params: Params,
+    principal: Option<&str>,
+) -> Response {
+    let uri = params.resource;
+
+    // "resource" parameter must be specified exactly once
+    if uri.len() != 1 {
+        let problem = if uri.is_empty() {
+            Problem::new(StatusCode::BAD_REQUEST, "missing-resource", "Missing resource parameter")
+                .detail("The \"resource\" query parameter must be provided".to_string())
+        } else {
+            Problem::new(StatusCode::BAD_REQUEST, "duplicate-resource", "Duplicate resource parameter")
+                .detail(format!(
+                    "The \"resource\" query parameter was provided {} times, but must be provided exactly once",
+                    uri.len()
+                ))
+        };
+        problem
+            .text("Exactly one \"resource\" query parameter must be provided")
+            .respond(headers)
+    } else if params.rel.len() > state.options.limits.max_rels {
+        Problem::new(StatusCode::BAD_REQUEST, "too-many-rels", "Too many rel parameters")
+            .detail(format!(
+                "The \"rel\" query parameter was provided {} times, but may be provided at most {} times",
+                params.rel.len(),
+                state.options.limits.max_rels
+            ))
+            .text("Too many \"rel\" query parameters")
+            .respond(headers)
+    } else {
+        let uri = match inferred_acct(state, &uri[0]) {
+            Some(acct) => acct,
+            None => uri[0].clone(),
+        };
+        let uri = &uri;
+        if !jrdmap::valid_uri(uri) {
+            // Malformed "resource" parameter
+            Problem::new(StatusCode::BAD_REQUEST, "malformed-resource", "Malformed resource parameter")
+                .detail(format!("The \"resource\" query parameter {uri:?} is not a URI"))
+                .text("Malformed \"resource\" query parameter")
+                .respond(headers)
+        } else if let Some(Err(e)) = acct::has_acct_scheme(uri).then(|| AcctUri::parse(uri)) {
+            // Malformed acct URI
+            Problem::new(StatusCode::BAD_REQUEST, "malformed-resource", "Malformed resource parameter")
+                .detail(format!("The \"resource\" query parameter {uri:?} is not a valid acct URI: {e}"))
+                .text(format!("Malformed acct URI in \"resource\" query parameter: {e}"))
+                .respond(headers)
+        } else {
+            // A JRD which the client may not see is reported as not found, so that
+            // its existence is not revealed.
+            let result = lookup(state.store.as_ref(), uri)
+                .await
+                .map(|jrd| jrd.and_then(|jrd| jrd.visible_to(principal)));
+            match result {
+                Ok(Some(jrd)) => {
+                    let jrd = if params.rel.is_empty() {
+                        jrd
+                    } else {
+                        jrd.filter(params.rel)
+                    };
+                    let mut builder = Response::builder().status(StatusCode::OK);
+                    let jrd = if state.options.select_title_language {
+                        builder = builder.header(VARY, "Accept-Language");
+                        match headers.get(ACCEPT_LANGUAGE).and_then(|v| v.to_str().ok()) {
+                            Some(accept_language) => {
+                                jrd.select_titles(&language::parse_accept_language(accept_language))
+                            }
+                            None => jrd,
+                        }
+                    } else {
+                        jrd
+                    };
+                    let body = render(&state.options, &jrd);
+
+                    // Sign the JRD either in a header or, if the client asks for it,
+                    // by wrapping it in a JWS.
+                    let (content_type, body) = match (&state.options.signing_key, state.options.signature_scheme) {
+                        (Some(key), SignatureScheme::Jws) => {
+                            builder = builder.header(VARY, "Accept");
+                            if problem::accepts(headers, &[jws::JOSE_JSON]) {
+                                (jws::JOSE_JSON, key.sign_wrapped(body.as_bytes()))
+                            } else {
+                                builder = builder.header(jws::SIGNATURE_HEADER, key.sign_detached(body.as_bytes()));
+                                (JRD_JSON, body)
+                            }
+                        }
+                        _ => (JRD_JSON, body),
+                    };
+                    builder = builder.header(CONTENT_TYPE, content_type);
+
+                    let body = match &state.response_cache {
+                        Some(cache) if cache.compressible(&body) => {
+                            builder = builder.header(VARY, "Accept-Encoding");
+                            match compression::negotiate(headers) {
+                                Some(encoding) => {
+                                    builder = builder.header(CONTENT_ENCODING, encoding.name());
+                                    cache.compress(&body, encoding).await
+                                }
+                                None => Bytes::from(body),
+                            }
+                        }
+                        _ => Bytes::from(body),
+                    };
+
+                    builder.body(Body::from(body)).unwrap()
+                }
+                Ok(None) => {
+                    // URI not found
+                    Problem::new(StatusCode::NOT_FOUND, "resource-not-found", "Resource not found")
+                        .detail(format!("There is no JRD for the resource {uri:?}"))
+                        .respond(headers)
+                }
+                Err(e) => {
+                    eprintln!("Failed to look up {uri}: {e}");
+                    Problem::status(StatusCode::INTERNAL_SERVER_ERROR).respond(headers)
+                }
+            }
+        }
+    }
+}
+
+// Sign a response to a WebFinger request with an HTTP message signature, if that
+// is the signature scheme. Problem responses, including those from the rate
+// limiter, are signed as well as JRDs, so that a client can tell that a resource
+// was not found by the server it asked. The response is signed as sent, including
+// any content coding. Responses without content, such as those to OPTIONS
+// requests, are not signed.
+async fn sign(
+    State(state): State<ServerState>,
+    OriginalUri(original_uri): OriginalUri,
+    request: Request,
+    next: Next,
+) -> Response {
+    let response = next.run(request).await;
+    let (Some(key), SignatureScheme::HttpMessage) = (&state.options.signing_key, state.options.signature_scheme)
+    else {
+        return response;
+    };
+    let Some(content_type) = response.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+        return response;
+    };
+    let content_type = content_type.to_string();
+
+    let (mut parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            eprintln!("Failed to read response to sign: {e}");
+            return Problem::status(StatusCode::INTERNAL_SERVER_ERROR).respond(&HeaderMap::new());
+        }
+    };
+    let content_digest = httpsig::content_digest(&body);
+    let request_target = original_uri
+        .path_and_query()
+        .map_or(original_uri.path(), |pq| pq.as_str());
+    let components = httpsig::Components {
+        status: parts.status.as_u16(),
+        content_type: &content_type,
+        content_digest: &content_digest,
+        request_target,
+    };
+    let created = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (signature_input, signature) = httpsig::sign(key, &components, created);
+    for (name, value) in [
+        (httpsig::CONTENT_DIGEST, content_digest),
+        (httpsig::SIGNATURE_INPUT, signature_input),
+        (httpsig::SIGNATURE, signature),
+    ] {
+        parts
+            .headers
+            .insert(name, HeaderValue::from_str(&value).expect("signature headers are valid"));
+    }
+    Response::from_parts(parts, Body::from(body))
+}
+
+// Write a JRD as the body of a response.
+pub(crate) fn render(options: &ServerOptions, jrd: &Jrd) -> String {
+    if options.canonical_json {
+        jrdmap::to_canonical_json(jrd)
+    } else {
+        jrdmap::to_json(jrd)
+    }
+}
+
+// In lenient mode, interpret a resource without a scheme as an acct URI, if possible.
+fn inferred_acct(state: &ServerState, resource: &str) -> Option<String> {
+    if !state.options.lenient || jrdmap::valid_uri(resource) {
+        return None;
+    }
+    let acct = acct::infer_acct(resource)?.to_string();
+    state.metrics.lenient_resources.fetch_add(1, Ordering::Relaxed);
+    eprintln!("Interpreted resource {resource:?} as {acct} in lenient mode");
+    Some(acct)
+}
+
+// Look up a resource. An acct URI which is not found as given is also looked up
+// in normal form, so that, for example, the host is matched case-insensitively.
+async fn lookup(store: &dyn JrdStore, uri: &str) -> Result<Option<Jrd>, StoreError> {
+    if let Some(jrd) = store.lookup(uri).await? {
+        return Ok(Some(jrd));
+    }
+    match AcctUri::parse(uri) {
+        Ok(acct) if acct.to_string() != uri => store.lookup(&acct.to_string()).await,
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Body,
+        extract::connect_info::MockConnectInfo,
+        http::{Request, StatusCode},
+    };
+    use hyper::header::{
+        CONTENT_LENGTH, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+        ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, RETRY_AFTER,
+    };
+    use http_body_util::BodyExt;
+    use pretty_assertions::assert_eq;
+    use serde_json::{json, Value};
+    use std::str;
+    use tokio::net::TcpListener;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn router_test() {
+        let jm = jrdmap::from_json(
+            &r#"
+            {
+                "acct:alice@example.com":{
+                    "subject": "acct:alice@example.com",
+                    "links": [
+                        {
+                            "rel": "http://webfinger.net/rel/avatar",
+                            "type": "image/jpeg",
+                            "href": "https://example.com/data/alice-avatar.jpeg"
+                        }
+                    ]
+                }
+            }"#
+            .to_string(),
+        );
+        let router = create_router(jm);
+
+        let response = router
+            .oneshot(Request::builder().uri("/.well-known/webfinger?resource=acct:alice@example.com&rel=http://webfinger.net/rel/avatar").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "application/jrd+json"
+        );
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let actual: Value = serde_json::from_str(str::from_utf8(&body[..]).unwrap()).unwrap();
+        let expected = json!(
+        {
+            "subject":"acct:alice@example.com",
+            "links": [
+                {
+                    "rel":"http://webfinger.net/rel/avatar",
+                    "type":"image/jpeg",
+                    "href":"https://example.com/data/alice-avatar.jpeg"
+                }
+            ]
+        });
+        assert_eq!(actual, expected);
+    }
+
+    #[tokio::test]
+    async fn nested_router() {
+        let jm = jrdmap::from_json(
+            &r#"
+            {
+                "acct:alice@example.com":{
+                    "subject": "acct:alice@example.com"
+                }
+            }"#
+            .to_string(),
+        );
+        let router = Router::new()
+            .route("/", get(|| async { "home" }))
+            .nest("/.well-known/webfinger", webfinger_router(Arc::new(JsonMapStore::new(jm)), ServerOptions::default()));
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/.well-known/webfinger?resource=acct:alice@example.com")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "application/jrd+json"
+        );
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let actual: Value = serde_json::from_str(str::from_utf8(&body[..]).unwrap()).unwrap();
+        assert_eq!(actual, json!({"subject": "acct:alice@example.com"}));
+    }
+
+    #[tokio::test]
+    async fn router_test_with_multiple_rels_in_query() {
+        let jm = jrdmap::from_json(
+            &r#"
+            {
+                "acct:alice@example.com":{
+                    "subject": "acct:alice@example.com",
+                    "links": [
+                        {
+                            "rel": "http://webfinger.net/rel/avatar",
+                            "type": "image/jpeg",
+                            "href": "https://example.com/data/alice-avatar.jpeg"
+                        },
+                        {
+                            "rel": "me",
+                            "href": "acct:me@example.com"
+                        },
+                        {
+                            "rel": "author",
+                            "href": "acct:author@example.com"
+                        }
+                    ]
+                }
+            }"#
+            .to_string(),
+        );
+        let router = create_router(jm);
+
+        let response = router
+            .oneshot(Request::builder().uri("/.well-known/webfinger?resource=acct:alice@example.com&rel=http://webfinger.net/rel/avatar&rel=me").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "application/jrd+json"
+        );
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let actual: Value = serde_json::from_str(str::from_utf8(&body[..]).unwrap()).unwrap();
+        let expected = json!(
+        {
+            "subject":"acct:alice@example.com",
+            "links": [
+                {
+                    "rel":"http://webfinger.net/rel/avatar",
+                    "type":"image/jpeg",
+                    "href":"https://example.com/data/alice-avatar.jpeg"
+                },
+                {
+                    "rel": "me",
+                    "href": "acct:me@example.com"
+                }
+            ]
+        });
+        assert_eq!(actual, expected);
+    }
+
+    #[tokio::test]
+    async fn router_test_with_encoded_query() {
+        let jm = jrdmap::from_json(
+            &r#"
+            {
+                "acct:alice@example.com":{
+                    "subject": "acct:alice@example.com",
+                    "links": [
+                        {
+                            "rel": "http://webfinger.net/rel/avatar",
+                            "type": "image/jpeg",
+                            "href": "https://example.com/data/alice-avatar.jpeg"
+                        }
+                    ]
+                }
+            }"#
+            .to_string(),
+        );
+        let router = create_router(jm);
+
+        let response = router
+            .oneshot(Request::builder().uri("/.well-known/webfinger?resource=acct%3Aalice%40example.com&rel=http%3a//webfinger.net/rel/avatar").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "application/jrd+json"
+        );
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let actual: Value = serde_json::from_str(str::from_utf8(&body[..]).unwrap()).unwrap();
+        let expected = json!(
+        {
+            "subject":"acct:alice@example.com",
+            "links": [
+                {
+                    "rel":"http://webfinger.net/rel/avatar",
+                    "type":"image/jpeg",
+                    "href":"https://example.com/data/alice-avatar.jpeg"
+                }
+            ]
+        });
+        assert_eq!(actual, expected);
+    }
+
+    #[tokio::test]
+    async fn not_found() {
+        let jm = jrdmap::from_json(
+            &r#"
+            {
+                "acct:other@example.com":{
+                    "subject": "acct:other@example.com"
+                }
+            }"#
+            .to_string(),
+        );
+        let router = create_router(jm);
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/.well-known/webfinger?resource=acct:alice@example.com")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn missing_resource() {
+        let jm = jrdmap::from_json(
+            &r#"
+            {
+                "acct:other@example.com":{
+                    "subject": "acct:other@example.com"
+                }
+            }"#
+            .to_string(),
+        );
+        let router = create_router(jm);
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/.well-known/webfinger")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "Exactly one \"resource\" query parameter must be provided");
+    }
+
+    #[tokio::test]
+    async fn duplicate_resource() {
+        let jm = jrdmap::from_json(
+            &r#"
+            {
+                "acct:other@example.com":{
+                    "subject": "acct:other@example.com"
+                }
+            }"#
+            .to_string(),
+        );
+        let router = create_router(jm);
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/.well-known/webfinger?resource=acct:other@example.com&resource=acct:other@example.com")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "Exactly one \"resource\" query parameter must be provided");
+    }
+
+    #[tokio::test]
+    async fn malformed_resource() {
+        let jm = jrdmap::from_json(
+            &r#"
+            {
+                "acct:other@example.com":{
+                    "subject": "acct:other@example.com"
+                }
+            }"#
+            .to_string(),
+        );
+        let router = create_router(jm);
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/.well-known/webfinger?resource=alice@example.com") // resource not a URI according to RFC 3986
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "Malformed \"resource\" query parameter");
+    }
+
+    #[tokio::test]
+    async fn malformed_acct_resource() {
+        let jm = jrdmap::from_json(
+            &r#"
+            {
+                "acct:other@example.com":{
+                    "subject": "acct:other@example.com"
+                }
+            }"#
+            .to_string(),
+        );
+        let router = create_router(jm);
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/.well-known/webfinger?resource=acct:@example.com")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "Malformed acct URI in \"resource\" query parameter: empty userpart");
+    }
+
+    #[tokio::test]
+    async fn acct_resource_normalized() {
+        let jm = jrdmap::from_json(
+            &r#"
+            {
+                "acct:alice@example.com":{
+                    "subject": "acct:alice@example.com"
+                }
+            }"#
+            .to_string(),
+        );
+        let router = create_router(jm);
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/.well-known/webfinger?resource=acct:alice@Example.COM")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn lenient_resource() {
+        let jm = jrdmap::from_json(
+            &r#"
+            {
+                "acct:alice@example.com":{
+                    "subject": "acct:alice@example.com"
+                }
+            }"#
+            .to_string(),
+        );
+        let router = create_store_router(
+            Arc::new(JsonMapStore::new(jm)),
+            ServerOptions {
+                lenient: true,
+                ..Default::default()
+            },
+        );
+
+        for resource in ["alice@example.com", "@alice@example.com", "acct:alice@example.com"] {
+            let response = router
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri(format!("/.well-known/webfinger?resource={resource}"))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK, "{resource}");
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let actual: Value = serde_json::from_str(str::from_utf8(&body[..]).unwrap()).unwrap();
+            assert_eq!(actual, json!({"subject": "acct:alice@example.com"}));
+        }
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(str::from_utf8(&body[..])
+            .unwrap()
+            .contains("\nwebfinger_lenient_resources_total 2\n"));
+    }
+
+    #[tokio::test]
+    async fn lenient_malformed_resource() {
+        let jm = jrdmap::from_json(&"{}".to_string());
+        let router = create_store_router(
+            Arc::new(JsonMapStore::new(jm)),
+            ServerOptions {
+                lenient: true,
+                ..Default::default()
+            },
+        );
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/.well-known/webfinger?resource=alice")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "Malformed \"resource\" query parameter");
+    }
+
+    #[tokio::test]
+    async fn problem_details() {
+        let jm = jrdmap::from_json(
+            &r#"
+            {
+                "acct:other@example.com":{
+                    "subject": "acct:other@example.com"
+                }
+            }"#
+            .to_string(),
+        );
+        let router = create_router(jm);
+
+        let cases = [
+            (
+                "/.well-known/webfinger",
+                json!({
+                    "type": "https://github.com/glyn/webfinger-rs/blob/main/README.md#missing-resource",
+                    "title": "Missing resource parameter",
+                    "status": 400,
+                    "detail": "The \"resource\" query parameter must be provided"
+                }),
+            ),
+            (
+                "/.well-known/webfinger?resource=acct:other@example.com&resource=acct:other@example.com",
+                json!({
+                    "type": "https://github.com/glyn/webfinger-rs/blob/main/README.md#duplicate-resource",
+                    "title": "Duplicate resource parameter",
+                    "status": 400,
+                    "detail": "The \"resource\" query parameter was provided 2 times, but must be provided exactly once"
+                }),
+            ),
+            (
+                "/.well-known/webfinger?resource=alice@example.com",
+                json!({
+                    "type": "https://github.com/glyn/webfinger-rs/blob/main/README.md#malformed-resource",
+                    "title": "Malformed resource parameter",
+                    "status": 400,
+                    "detail": "The \"resource\" query parameter \"alice@example.com\" is not a URI"
+                }),
+            ),
+            (
+                "/.well-known/webfinger?resource=acct:alice@example.com",
+                json!({
+                    "type": "https://github.com/glyn/webfinger-rs/blob/main/README.md#resource-not-found",
+                    "title": "Resource not found",
+                    "status": 404,
+                    "detail": "There is no JRD for the resource \"acct:alice@example.com\""
+                }),
+            ),
+        ];
+
+        for (uri, expected) in cases {
+            let response = router
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri(uri)
+                        .header("Accept", "application/problem+json")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.status().as_u16(), expected["status"], "{uri}");
+            assert_eq!(
+                response.headers().get(CONTENT_TYPE).unwrap(),
+                "application/problem+json"
+            );
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let actual: Value = serde_json::from_str(str::from_utf8(&body[..]).unwrap()).unwrap();
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn store_failure_problem_details() {
+        let router = create_store_router(Arc::new(FailingStore), ServerOptions::default());
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/.well-known/webfinger?resource=acct:alice@example.com")
+                    .header("Accept", "application/json")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let actual: Value = serde_json::from_str(str::from_utf8(&body[..]).unwrap()).unwrap();
+        assert_eq!(
+            actual,
+            json!({"type": "about:blank", "title": "Internal Server Error", "status": 500})
+        );
+    }
+
+    #[tokio::test]
+    async fn canonical_json() {
+        let jm = jrdmap::from_json(
+            &r#"
+            {
+                "acct:alice@example.com":{
+                    "subject": "acct:alice@example.com",
+                    "properties": {
+                        "http://z.example/": "z",
+                        "http://a.example/": "a"
+                    },
+                    "links": [
+                        {
+                            "rel": "me",
+                            "href": "acct:me@example.com"
+                        }
+                    ]
+                }
+            }"#
+            .to_string(),
+        );
+        for (canonical_json, expected) in [
+            (
+                false,
+                r#"{"subject":"acct:alice@example.com","properties":{"http://a.example/":"a","http://z.example/":"z"},"links":[{"rel":"me","href":"acct:me@example.com"}]}"#,
+            ),
+            (
+                true,
+                r#"{"links":[{"href":"acct:me@example.com","rel":"me"}],"properties":{"http://a.example/":"a","http://z.example/":"z"},"subject":"acct:alice@example.com"}"#,
+            ),
+        ] {
+            let router = create_store_router(
+                Arc::new(JsonMapStore::new(jm.clone())),
+                ServerOptions {
+                    canonical_json,
+                    ..Default::default()
+                },
+            );
+
+            let response = router
+                .oneshot(
+                    Request::builder()
+                        .uri("/.well-known/webfinger?resource=acct:alice@example.com")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn select_title_language() {
+        let jm = jrdmap::from_json(
+            &r#"
+            {
+                "acct:alice@example.com":{
+                    "subject": "acct:alice@example.com",
+                    "links": [
+                        {
+                            "rel": "me",
+                            "href": "acct:me@example.com",
+                            "titles": {
+                                "en-GB": "Me",
+                                "fr": "Moi",
+                                "und": "Me"
+                            }
+                        }
+                    ]
+                }
+            }"#
+            .to_string(),
+        );
+        let router = create_store_router(
+            Arc::new(JsonMapStore::new(jm)),
+            ServerOptions {
+                select_title_language: true,
+                ..Default::default()
+            },
+        );
+
+        for (accept_language, expected) in [
+            (Some("fr-CA, en;q=0.8"), json!({"fr": "Moi"})),
+            (Some("en-GB-oxendict"), json!({"en-GB": "Me"})),
+            (Some("de"), json!({"und": "Me"})),
+            (None, json!({"en-GB": "Me", "fr": "Moi", "und": "Me"})),
+        ] {
+            let mut request =
+                Request::builder().uri("/.well-known/webfinger?resource=acct:alice@example.com");
+            if let Some(accept_language) = accept_language {
+                request = request.header("Accept-Language", accept_language);
+            }
+            let response = router
+                .clone()
+                .oneshot(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers().get(VARY).unwrap(), "Accept-Language");
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let actual: Value = serde_json::from_str(str::from_utf8(&body[..]).unwrap()).unwrap();
+            assert_eq!(actual["links"][0]["titles"], expected, "{accept_language:?}");
+        }
+    }
+
+    fn visibility_router() -> Router {
+        let jm = jrdmap::from_json(
+            &r#"
+            {
+                "acct:alice@example.com":{
+                    "subject": "acct:alice@example.com",
+                    "links": [
+                        {"rel": "self", "href": "https://example.com/alice"},
+                        {"rel": "me", "href": "https://example.com/private", "visibility": "private"},
+                        {"rel": "author", "href": "https://example.com/partner", "visibility": ["partner"]}
+                    ]
+                },
+                "acct:carol@example.com":{
+                    "subject": "acct:carol@example.com",
+                    "visibility": ["intranet"]
+                }
+            }"#
+            .to_string(),
+        );
+        let principals = crate::access::Principals::from_str(
+            r#"
+            {
+                "partner": {"token": "s3cret"},
+                "intranet": {"client_certificate": "SHA256:ab:cd"}
+            }"#,
+            jrdmap::Format::Json,
+        )
+        .unwrap();
+        create_store_router(
+            Arc::new(JsonMapStore::new(jm)),
+            ServerOptions {
+                principals,
+                client_certificate_header: Some(HeaderName::from_static("x-client-cert")),
+                ..Default::default()
+            },
+        )
+    }
+
+    async fn get_with_header(router: Router, resource: &str, header: Option<(&str, &str)>) -> Response {
+        let mut request = Request::builder().uri(format!("/.well-known/webfinger?resource={resource}"));
+        if let Some((name, value)) = header {
+            request = request.header(name, value);
+        }
+        router.oneshot(request.body(Body::empty()).unwrap()).await.unwrap()
+    }
+
+    async fn link_rels(response: Response) -> Vec<String> {
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let actual: Value = serde_json::from_slice(&body).unwrap();
+        assert!(actual.get("visibility").is_none());
+        actual["links"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|lk| {
+                assert!(lk.get("visibility").is_none());
+                lk["rel"].as_str().unwrap().to_string()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn anonymous_client_sees_public_links() {
+        let response = get_with_header(visibility_router(), "acct:alice@example.com", None).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(CACHE_CONTROL).is_none());
+        let vary: Vec<_> = response.headers().get_all(VARY).iter().collect();
+        assert_eq!(vary, ["authorization", "x-client-cert"]);
+        assert_eq!(link_rels(response).await, ["self"]);
+    }
+
+    #[tokio::test]
+    async fn authenticated_client_sees_restricted_links() {
+        let response = get_with_header(
+            visibility_router(),
+            "acct:alice@example.com",
+            Some(("Authorization", "Bearer s3cret")),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(CACHE_CONTROL).unwrap(), "private");
+        assert_eq!(link_rels(response).await, ["self", "me", "author"]);
+
+        let response = get_with_header(
+            visibility_router(),
+            "acct:alice@example.com",
+            Some(("X-Client-Cert", "SHA256:ab:cd")),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(link_rels(response).await, ["self", "me"]);
+    }
+
+    #[tokio::test]
+    async fn hidden_resource_not_found() {
+        for header in [None, Some(("Authorization", "Bearer s3cret"))] {
+            let response = get_with_header(visibility_router(), "acct:carol@example.com", header).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{header:?}");
+            // A shared cache must not answer an authorised client with a response
+            // to an anonymous client.
+            let vary: Vec<_> = response.headers().get_all(VARY).iter().collect();
+            assert_eq!(vary, ["authorization", "x-client-cert"], "{header:?}");
+        }
+
+        let response = get_with_header(
+            visibility_router(),
+            "acct:carol@example.com",
+            Some(("X-Client-Cert", "SHA256:ab:cd")),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn invalid_credentials() {
+        let response = get_with_header(
+            visibility_router(),
+            "acct:alice@example.com",
+            Some(("Authorization", "Bearer guess")),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers().get(WWW_AUTHENTICATE).unwrap(), "Bearer");
+        let vary: Vec<_> = response.headers().get_all(VARY).iter().collect();
+        assert_eq!(vary, ["authorization", "x-client-cert"]);
+    }
+
+    #[tokio::test]
+    async fn problem_response_varies_with_credentials() {
+        let response = get_with_header(visibility_router(), "acct:@example.com", Some(("Authorization", "Bearer s3cret"))).await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let vary: Vec<_> = response.headers().get_all(VARY).iter().collect();
+        assert_eq!(vary, ["authorization", "x-client-cert"]);
+        assert_eq!(response.headers().get(CACHE_CONTROL).unwrap(), "private");
+    }
+
+    #[tokio::test]
+    async fn credentials_ignored_without_principals() {
+        let jm = jrdmap::from_json(
+            &r#"{"acct:alice@example.com":{"subject": "acct:alice@example.com"}}"#.to_string(),
+        );
+        let response = get_with_header(
+            create_router(jm),
+            "acct:alice@example.com",
+            Some(("Authorization", "Bearer guess")),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(VARY).is_none());
+    }
+
+    fn rate_limited_router(rate_limit: RateLimitOptions, peer: [u8; 4]) -> Router {
+        let jm = jrdmap::from_json(
+            &r#"{"acct:alice@example.com":{"subject": "acct:alice@example.com"}}"#.to_string(),
+        );
+        create_store_router(
+            Arc::new(JsonMapStore::new(jm)),
+            ServerOptions {
+                rate_limit: Some(rate_limit),
+                ..Default::default()
+            },
+        )
+        .layer(MockConnectInfo(std::net::SocketAddr::from((peer, 1234))))
+    }
+
+    #[tokio::test]
+    async fn rate_limit_misses() {
+        let router = rate_limited_router(
+            RateLimitOptions {
+                misses_per_minute: Some(2),
+                ..Default::default()
+            },
+            [192, 0, 2, 1],
+        );
+
+        for resource in ["acct:bob@example.com", "acct:carol@example.com"] {
+            let response = get_with_header(router.clone(), resource, None).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+
+        // Once the client has no misses left, even resources which exist are refused.
+        let response = get_with_header(router.clone(), "acct:alice@example.com", None).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "30");
+
+        let response = router
+            .oneshot(Request::builder().uri("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(str::from_utf8(&body).unwrap().contains("webfinger_rate_limited_requests_total 1\n"));
+    }
+
+    #[tokio::test]
+    async fn rate_limit_hits() {
+        let router = rate_limited_router(
+            RateLimitOptions {
+                hits_per_minute: Some(1),
+                ..Default::default()
+            },
+            [192, 0, 2, 1],
+        );
+
+        let response = get_with_header(router.clone(), "acct:alice@example.com", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = get_with_header(router.clone(), "acct:alice@example.com", None).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "60");
+    }
+
+    #[tokio::test]
+    async fn rate_limit_message() {
+        let router = rate_limited_router(
+            RateLimitOptions {
+                misses_per_minute: Some(1),
+                message: Some("Please slow down".to_string()),
+                ..Default::default()
+            },
+            [192, 0, 2, 1],
+        );
+
+        get_with_header(router.clone(), "acct:bob@example.com", None).await;
+        let response = get_with_header(router.clone(), "acct:bob@example.com", None).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(str::from_utf8(&body).unwrap(), "Please slow down");
+
+        let response =
+            get_with_header(router, "acct:bob@example.com", Some(("Accept", "application/problem+json"))).await;
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem["detail"], "Please slow down");
+        assert_eq!(problem["status"], 429);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_forwarded_clients() {
+        let router = rate_limited_router(
+            RateLimitOptions {
+                misses_per_minute: Some(1),
+                trusted_proxies: vec!["127.0.0.1".parse().unwrap()],
+                ..Default::default()
+            },
+            [127, 0, 0, 1],
+        );
+
+        // Each client behind the trusted proxy has its own budget.
+        for client in ["198.51.100.1", "198.51.100.2"] {
+            let response =
+                get_with_header(router.clone(), "acct:bob@example.com", Some(("X-Forwarded-For", client))).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{client}");
+        }
+        let response =
+            get_with_header(router, "acct:bob@example.com", Some(("X-Forwarded-For", "198.51.100.1"))).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn miss_response_time() {
+        let jm = jrdmap::from_json(
+            &r#"{"acct:alice@example.com":{"subject": "acct:alice@example.com"}}"#.to_string(),
+        );
+        let router = create_store_router(
+            Arc::new(JsonMapStore::new(jm)),
+            ServerOptions {
+                miss_response_time: Some(Duration::from_millis(100)),
+                ..Default::default()
+            },
+        );
+
+        let start = std::time::Instant::now();
+        let response = get_with_header(router.clone(), "acct:bob@example.com", None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        let start = std::time::Instant::now();
+        let response = get_with_header(router, "acct:alice@example.com", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    fn limited_router(limits: RequestLimits) -> Router {
+        let jm = jrdmap::from_json(
+            &r#"{"acct:alice@example.com":{"subject": "acct:alice@example.com"}}"#.to_string(),
+        );
+        create_store_router(
+            Arc::new(JsonMapStore::new(jm)),
+            ServerOptions {
+                limits,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn uri_too_long() {
+        let router = limited_router(RequestLimits {
+            max_uri_length: 64,
+            ..Default::default()
+        });
+
+        let response = get_with_header(router.clone(), "acct:alice@example.com", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = get_with_header(router, &format!("acct:{}@example.com", "a".repeat(64)), None).await;
+        assert_eq!(response.status(), StatusCode::URI_TOO_LONG);
+    }
+
+    #[tokio::test]
+    async fn too_many_rels() {
+        let router = limited_router(RequestLimits {
+            max_rels: 2,
+            ..Default::default()
+        });
+
+        let response = get_with_header(router.clone(), "acct:alice@example.com&rel=self&rel=me", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response =
+            get_with_header(router, "acct:alice@example.com&rel=self&rel=me&rel=author", None).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"Too many \"rel\" query parameters");
+    }
+
+    #[tokio::test]
+    async fn headers_too_large() {
+        let router = limited_router(RequestLimits {
+            max_header_size: 64,
+            ..Default::default()
+        });
+
+        let response =
+            get_with_header(router.clone(), "acct:alice@example.com", Some(("X-Small", "small"))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let large = "x".repeat(64);
+        let response = get_with_header(router, "acct:alice@example.com", Some(("X-Large", &large))).await;
+        assert_eq!(response.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+
+    struct SlowStore;
+
+    #[async_trait::async_trait]
+    impl JrdStore for SlowStore {
+        async fn lookup(&self, _resource: &str) -> Result<Option<jrdmap::Jrd>, StoreError> {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn request_timeout() {
+        let router = create_store_router(
+            Arc::new(SlowStore),
+            ServerOptions {
+                limits: RequestLimits {
+                    request_timeout: Duration::from_millis(50),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+
+        let response = get_with_header(router, "acct:alice@example.com", None).await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    fn cors_router(cors: CorsOptions) -> Router {
+        let jm = jrdmap::from_json(
+            &r#"{"acct:alice@example.com":{"subject": "acct:alice@example.com"}}"#.to_string(),
+        );
+        create_store_router(
+            Arc::new(JsonMapStore::new(jm)),
+            ServerOptions {
+                cors,
+                ..Default::default()
+            },
+        )
+    }
+
+    async fn preflight(router: Router, origin: &str) -> Response {
+        router
+            .oneshot(
+                Request::builder()
+                    .method("OPTIONS")
+                    .uri("/.well-known/webfinger?resource=acct:alice@example.com")
+                    .header("Origin", origin)
+                    .header("Access-Control-Request-Method", "GET")
+                    .header("Access-Control-Request-Headers", "authorization")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn cors_default() {
+        let router = cors_router(CorsOptions::default());
+
+        // Error responses are also readable by any origin.
+        let response = get_with_header(router.clone(), "acct:bob@example.com", Some(("Origin", "https://app.example.com"))).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "*");
+        assert!(response.headers().get(VARY).is_none());
+
+        let response = preflight(router, "https://app.example.com").await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "*");
+        assert_eq!(response.headers().get(ACCESS_CONTROL_ALLOW_METHODS).unwrap(), "GET");
+        assert!(response.headers().get(ACCESS_CONTROL_ALLOW_HEADERS).is_none());
+        assert!(response.headers().get(ACCESS_CONTROL_MAX_AGE).is_none());
+    }
+
+    #[tokio::test]
+    async fn cors_configured() {
+        let router = cors_router(CorsOptions {
+            allowed_origins: vec!["https://app.example.com".to_string()],
+            expose_headers: vec![HeaderName::from_static("etag")],
+            allow_headers: vec![AUTHORIZATION],
+            max_age: Some(Duration::from_secs(600)),
+        });
+
+        let response = get_with_header(router.clone(), "acct:alice@example.com", Some(("Origin", "https://app.example.com"))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://app.example.com");
+        assert_eq!(response.headers().get(ACCESS_CONTROL_EXPOSE_HEADERS).unwrap(), "etag");
+        assert_eq!(response.headers().get(VARY).unwrap(), "Origin");
+
+        let response = get_with_header(router.clone(), "acct:alice@example.com", Some(("Origin", "https://evil.example.com"))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+        assert!(response.headers().get(ACCESS_CONTROL_EXPOSE_HEADERS).is_none());
+
+        let response = preflight(router.clone(), "https://app.example.com").await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://app.example.com");
+        assert_eq!(response.headers().get(ACCESS_CONTROL_ALLOW_HEADERS).unwrap(), "authorization");
+        assert_eq!(response.headers().get(ACCESS_CONTROL_MAX_AGE).unwrap(), "600");
+
+        let response = preflight(router, "https://evil.example.com").await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(response.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+        assert!(response.headers().get(ACCESS_CONTROL_ALLOW_METHODS).is_none());
+    }
+
+    async fn send(router: Router, method: &str, uri: &str) -> Response {
+        router
+            .oneshot(Request::builder().method(method).uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn head_request() {
+        let jm = jrdmap::from_json(
+            &r#"{"acct:alice@example.com":{"subject": "acct:alice@example.com"}}"#.to_string(),
+        );
+        let router = create_router(jm);
+        let uri = "/.well-known/webfinger?resource=acct:alice@example.com";
+
+        let get_response = send(router.clone(), "GET", uri).await;
+        let head_response = send(router, "HEAD", uri).await;
+
+        assert_eq!(head_response.status(), StatusCode::OK);
+        assert_eq!(head_response.headers(), get_response.headers());
+        assert_eq!(head_response.headers().get(CONTENT_LENGTH).unwrap(), "36");
+        let body = head_response.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn method_not_allowed() {
+        let router = create_router(JrdMap::new());
+
+        let response = send(router, "POST", "/.well-known/webfinger?resource=acct:alice@example.com").await;
+
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers().get(ALLOW).unwrap(), "GET, HEAD, OPTIONS");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"Method not allowed");
+    }
+
+    #[tokio::test]
+    async fn options_request() {
+        let router = create_router(JrdMap::new());
+
+        let response = send(router, "OPTIONS", "/.well-known/webfinger").await;
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers().get(ALLOW).unwrap(), "GET, HEAD, OPTIONS");
+    }
+
+    #[tokio::test]
+    async fn unknown_path() {
+        let router = create_router(JrdMap::new());
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/.well-known/host-meta")
+                    .header("Accept", "application/problem+json")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let actual: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(actual, json!({"type": "about:blank", "title": "Not Found", "status": 404}));
+    }
+
+    fn trailing_slash_router(trailing_slash: TrailingSlash) -> Router {
+        let jm = jrdmap::from_json(
+            &r#"{"acct:alice@example.com":{"subject": "acct:alice@example.com"}}"#.to_string(),
+        );
+        create_store_router(
+            Arc::new(JsonMapStore::new(jm)),
+            ServerOptions {
+                trailing_slash,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn trailing_slash() {
+        let uri = "/.well-known/webfinger/?resource=acct:alice@example.com";
+
+        let response = send(trailing_slash_router(TrailingSlash::NotFound), "GET", uri).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = send(trailing_slash_router(TrailingSlash::Redirect), "GET", uri).await;
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            response.headers().get(LOCATION).unwrap(),
+            "/.well-known/webfinger?resource=acct:alice@example.com"
+        );
+
+        let response = send(trailing_slash_router(TrailingSlash::Ignore), "GET", uri).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = send(trailing_slash_router(TrailingSlash::Ignore), "GET", "/healthz/").await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn invalid_rel() {
+        let jm = jrdmap::from_json(
+            &r#"
+            {
+                "acct:other@example.com":{
+                    "subject": "acct:other@example.com"
+                }
+            }"#
+            .to_string(),
+        );
+        let router = create_router(jm);
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/.well-known/webfinger?resource=acct:alice@example.com&rel=")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.is_empty());
+    }
+
+    struct FailingStore;
+
+    #[async_trait::async_trait]
+    impl JrdStore for FailingStore {
+        async fn lookup(&self, _resource: &str) -> Result<Option<jrdmap::Jrd>, StoreError> {
+            Err(StoreError("unavailable".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn store_failure() {
+        let router = create_store_router(Arc::new(FailingStore), ServerOptions::default());
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/.well-known/webfinger?resource=acct:alice@example.com")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn readyz_store_failure() {
+        let router = create_store_router(Arc::new(FailingStore), ServerOptions::default());
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/readyz")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let actual: Value = serde_json::from_str(str::from_utf8(&body[..]).unwrap()).unwrap();
+        assert_eq!(actual, json!({"status": "unavailable", "error": "unavailable"}));
+    }
+
+    struct EmptyStore;
+
+    #[async_trait::async_trait]
+    impl JrdStore for EmptyStore {
+        async fn lookup(&self, _resource: &str) -> Result<Option<jrdmap::Jrd>, StoreError> {
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn readyz_without_listing() {
+        let router = create_store_router(Arc::new(EmptyStore), ServerOptions::default());
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/readyz")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let actual: Value = serde_json::from_str(str::from_utf8(&body[..]).unwrap()).unwrap();
+        let expected = json!(
+        {
+            "status": "ready",
+            "map": {
+                "hash": null,
+                "resources": null
+            }
+        });
+        assert_eq!(actual, expected);
+    }
+
+    #[tokio::test]
+    async fn healthz() {
+        let jm = jrdmap::from_json(&"{}".to_string());
+        let router = create_router(jm);
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/healthz")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "OK");
+    }
+
+    #[tokio::test]
+    async fn readyz() {
+        let jm = jrdmap::from_json(
+            &r#"
+            {
+                "acct:alice@example.com":{
+                    "subject": "acct:alice@example.com"
+                },
+                "acct:other@example.com":{
+                    "subject": "acct:other@example.com"
+                }
+            }"#
+            .to_string(),
+        );
+        let hash = jrdmap::digest(&jm);
+        let router = create_router(jm);
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/readyz")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let actual: Value = serde_json::from_str(str::from_utf8(&body[..]).unwrap()).unwrap();
+        let expected = json!(
+        {
+            "status": "ready",
+            "map": {
+                "hash": hash,
+                "resources": 2
+            }
+        });
+        assert_eq!(actual, expected);
+    }
+
+    #[tokio::test]
+    async fn integration_test() {
+        let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let jm = jrdmap::from_json(
+                &r#"
+                {
+                    "acct:alice@example.com":{
+                        "subject": "acct:alice@example.com",
+                        "links": [
+                            {
+                                "rel":"http://webfinger.net/rel/avatar",
+                                "type":"image/jpeg",
+                                "href":"https://example.com/data/alice-avatar.jpeg"
+                            }
+                        ]
+                    }
+                }"#
+                .to_string(),
+            );
+            axum::serve(listener, create_router(jm)).await.unwrap();
+        });
+
+        let client =
+            hyper_util::client::legacy::Client::builder(hyper_util::rt::TokioExecutor::new())
+                .build_http();
+
+        let response = client
+            .request(
+                Request::builder()
+                    .uri(format!(
+                        "http://{addr}/.well-known/webfinger?resource=acct:alice@example.com"
+                    ))
+                    .header("Host", "localhost")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "application/jrd+json"
+        );
+        assert_eq!(
+            response.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+            "*"
+        );
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let actual: Value = serde_json::from_str(str::from_utf8(&body[..]).unwrap()).unwrap();
+        let expected = json!({"subject":"acct:alice@example.com",
+            "links": [
+                {
+                    "rel":"http://webfinger.net/rel/avatar",
+                    "type":"image/jpeg",
+                    "href":"https://example.com/data/alice-avatar.jpeg"
+                }
+            ]
+        });
+        assert_eq!(actual, expected);
+    }
+
+    fn compression_router(min_size: usize) -> Router {
+        let jm = jrdmap::from_json(&format!(
+            r#"{{"acct:alice@example.com":{{"subject": "acct:alice@example.com", "links": [{{"rel": "me", "titles": {{"und": "{}"}}}}]}}}}"#,
+            "Alice ".repeat(100)
+        ));
+        create_store_router(
+            Arc::new(JsonMapStore::new(jm)),
+            ServerOptions {
+                compression: Some(CompressionOptions {
+                    min_size,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn compressed_response() {
+        let router = compression_router(512);
+        let uncompressed = get_with_header(router.clone(), "acct:alice@example.com", None).await;
+        assert_eq!(uncompressed.headers().get(VARY).unwrap(), "Accept-Encoding");
+        assert!(uncompressed.headers().get(CONTENT_ENCODING).is_none());
+        let expected = uncompressed.into_body().collect().await.unwrap().to_bytes();
+
+        for (accept_encoding, encoding) in [
+            ("gzip, deflate, br, zstd", compression::Encoding::Brotli),
+            ("gzip, zstd", compression::Encoding::Zstd),
+            ("gzip", compression::Encoding::Gzip),
+        ] {
+            let response = get_with_header(
+                router.clone(),
+                "acct:alice@example.com",
+                Some(("Accept-Encoding", accept_encoding)),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers().get(CONTENT_ENCODING).unwrap(), encoding.name());
+            assert_eq!(response.headers().get(VARY).unwrap(), "Accept-Encoding");
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert!(body.len() < expected.len());
+            assert_eq!(encoding.decompress(&body).unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn small_response_not_compressed() {
+        let router = compression_router(4096);
+        let response = get_with_header(router, "acct:alice@example.com", Some(("Accept-Encoding", "gzip"))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(CONTENT_ENCODING).is_none());
+        assert!(response.headers().get(VARY).is_none());
+    }
+
+    #[tokio::test]
+    async fn compressed_head_request() {
+        let router = compression_router(512);
+        let request = |method| {
+            Request::builder()
+                .method(method)
+                .uri("/.well-known/webfinger?resource=acct:alice@example.com")
+                .header("Accept-Encoding", "gzip")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let get_response = router.clone().oneshot(request("GET")).await.unwrap();
+        let head_response = router.oneshot(request("HEAD")).await.unwrap();
+
+        assert_eq!(head_response.headers(), get_response.headers());
+        assert_eq!(head_response.headers().get(CONTENT_ENCODING).unwrap(), "gzip");
+        let body = get_response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(
+            head_response.headers().get(CONTENT_LENGTH).unwrap(),
+            body.len().to_string().as_str()
+        );
+    }
+
+    fn signed_router(signing_key: &SigningKey) -> Router {
+        let jm = jrdmap::from_json(&r#"{"acct:alice@example.com":{"subject": "acct:alice@example.com"}}"#.to_string());
+        create_store_router(
+            Arc::new(JsonMapStore::new(jm)),
+            ServerOptions {
+                signing_key: Some(signing_key.clone()),
+                ..Default::default()
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn signed_response() {
+        let key = jws::generate_key(jws::Algorithm::EdDSA, None);
+        let router = signed_router(&key);
+
+        let response = get_with_header(router, "acct:alice@example.com", None).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), "application/jrd+json");
+        assert_eq!(response.headers().get(VARY).unwrap(), "Accept");
+        let signature = response.headers().get(jws::SIGNATURE_HEADER).unwrap().to_str().unwrap().to_string();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(jws::verify_detached(&signature, &body, &key.jwk_set()).unwrap(), key.kid());
+    }
+
+    #[tokio::test]
+    async fn jose_wrapped_response() {
+        let key = jws::generate_key(jws::Algorithm::ES256, None);
+        let router = signed_router(&key);
+
+        let response = get_with_header(
+            router.clone(),
+            "acct:alice@example.com",
+            Some(("Accept", "application/jose+json, application/jrd+json;q=0.5")),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), "application/jose+json");
+        assert!(response.headers().get(jws::SIGNATURE_HEADER).is_none());
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let (kid, payload) = jws::verify_wrapped(str::from_utf8(&body).unwrap(), &key.jwk_set()).unwrap();
+        assert_eq!(kid, key.kid());
+        assert_eq!(str::from_utf8(&payload).unwrap(), r#"{"subject":"acct:alice@example.com"}"#);
+
+        let response = get_with_header(
+            router,
+            "acct:alice@example.com",
+            Some(("Accept", "application/jose+json;q=0, application/jrd+json")),
+        )
+        .await;
+        assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), "application/jrd+json");
+    }
+
+    #[tokio::test]
+    async fn jwk_set_published() {
+        let key = jws::generate_key(jws::Algorithm::EdDSA, Some("2024-06".to_string()));
+
+        let response = send(signed_router(&key), "GET", "/.well-known/jwks.json").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), "application/jwk-set+json");
+        assert_eq!(response.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "*");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let jwk_set: jws::JwkSet = str::from_utf8(&body).unwrap().parse().unwrap();
+        assert_eq!(jwk_set, key.jwk_set());
+        assert_eq!(jwk_set.keys[0].kid.as_deref(), Some("2024-06"));
+    }
+
+    #[tokio::test]
+    async fn jwk_set_not_published_without_key() {
+        let jm = jrdmap::from_json(&r#"{"acct:alice@example.com":{"subject": "acct:alice@example.com"}}"#.to_string());
+        let response = send(create_router(jm), "GET", "/.well-known/jwks.json").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn http_message_signed_response() {
+        let key = jws::generate_key(jws::Algorithm::ES256, None);
+        let jm = jrdmap::from_json(&format!(
+            r#"{{"acct:alice@example.com":{{"subject": "acct:alice@example.com", "aliases": ["{}"]}}}}"#,
+            "https://example.com/alice".repeat(30)
+        ));
+        let router = create_store_router(
+            Arc::new(JsonMapStore::new(jm)),
+            ServerOptions {
+                signing_key: Some(key.clone()),
+                signature_scheme: SignatureScheme::HttpMessage,
+                compression: Some(CompressionOptions::default()),
+                ..Default::default()
+            },
+        );
+        let target = "/.well-known/webfinger?resource=acct%3Aalice%40example.com";
+
+        for accept_encoding in ["identity", "gzip"] {
+            let response = router
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri(target)
+                        .header("Accept-Encoding", accept_encoding)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(response.headers().get(jws::SIGNATURE_HEADER).is_none());
+            assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), "application/jrd+json");
+            verify_message_signature(response, target, &key).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn http_message_signed_problem() {
+        let key = jws::generate_key(jws::Algorithm::EdDSA, None);
+        let jm = jrdmap::from_json(&r#"{"acct:alice@example.com":{"subject": "acct:alice@example.com"}}"#.to_string());
+        let router = create_store_router(
+            Arc::new(JsonMapStore::new(jm)),
+            ServerOptions {
+                signing_key: Some(key.clone()),
+                signature_scheme: SignatureScheme::HttpMessage,
+                rate_limit: Some(RateLimitOptions {
+                    misses_per_minute: Some(1),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        )
+        .layer(MockConnectInfo(std::net::SocketAddr::from(([192, 0, 2, 1], 1234))));
+        let target = "/.well-known/webfinger?resource=acct%3Abob%40example.com";
+
+        let response = send(router.clone(), "GET", target).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), "text/plain; charset=utf-8");
+        verify_message_signature(response, target, &key).await;
+
+        // Responses from the rate limiter are signed too.
+        let response = send(router, "GET", target).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        verify_message_signature(response, target, &key).await;
+    }
+
+    // Verify the HTTP message signature of a response to a request with the given target.
+    async fn verify_message_signature(response: Response, target: &str, key: &SigningKey) {
+        let header = |name| response.headers().get(name).unwrap().to_str().unwrap().to_string();
+        let (content_type, content_digest, signature_input, signature) = (
+            header(CONTENT_TYPE.as_str()),
+            header(httpsig::CONTENT_DIGEST),
+            header(httpsig::SIGNATURE_INPUT),
+            header(httpsig::SIGNATURE),
+        );
+        let status = response.status().as_u16();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        // The digest covers the content as sent.
+        httpsig::verify_content_digest(&content_digest, &body).unwrap();
+        let components = httpsig::Components {
+            status,
+            content_type: &content_type,
+            content_digest: &content_digest,
+            request_target: target,
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert_eq!(
+            httpsig::verify(&components, &signature_input, &signature, &key.jwk_set(), now).unwrap(),
+            key.kid()
+        );
+    }
+
+    #[tokio::test]
+    async fn jwk_set_includes_previous_keys() {
+        let key = jws::generate_key(jws::Algorithm::EdDSA, Some("2024".to_string()));
+        let previous = jws::generate_key(jws::Algorithm::EdDSA, Some("2023".to_string()));
+        let jm = jrdmap::from_json(&r#"{"acct:alice@example.com":{"subject": "acct:alice@example.com"}}"#.to_string());
+        let router = create_store_router(
+            Arc::new(JsonMapStore::new(jm)),
+            ServerOptions {
+                signing_key: Some(key),
+                previous_keys: previous.jwk_set(),
+                ..Default::default()
+            },
+        );
+
+        let response = send(router, "GET", "/.well-known/jwks.json").await;
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let jwk_set: JwkSet = str::from_utf8(&body).unwrap().parse().unwrap();
+        let kids: Vec<_> = jwk_set.keys.iter().map(|jwk| jwk.kid.as_deref().unwrap()).collect();
+        assert_eq!(kids, vec!["2024", "2023"]);
+    }
+}
@@ -0,0 +1,553 @@
+/*
+Copyright 2024 Glyn Normington
+
+This file is part of webfinger-rs.
+
+webfinger-rs is free software: you can redistribute it and/or modify it under the terms
+of the GNU General Public License as published by the Free Software Foundation, either
+version 3 of the License, or (at your option) any later version.
+
+webfinger-rs is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with webfinger-rs.
+If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// A JrdStore backed by a SQLite database, for sites with more resources than are
+// convenient to keep in a single JSON file. JRDs are stored in normalized tables
+// with indexes on resource URI and alias. The database is opened in WAL mode so
+// that the server can continue to read while another process, such as
+// `webfinger-rs sqlite import`, writes to it.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+
+use crate::jrdmap::{Jrd, JrdMap, ResourceLink, Visibility};
+use crate::rel::make_rel;
+use crate::store::{JrdStore, StoreError};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS resources (
+        id INTEGER PRIMARY KEY,
+        uri TEXT NOT NULL UNIQUE,
+        subject TEXT NOT NULL,
+        visibility TEXT
+    );
+    CREATE TABLE IF NOT EXISTS aliases (
+        resource_id INTEGER NOT NULL REFERENCES resources(id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        alias TEXT NOT NULL,
+        PRIMARY KEY (resource_id, position)
+    );
+    CREATE INDEX IF NOT EXISTS aliases_alias ON aliases(alias);
+    CREATE TABLE IF NOT EXISTS resource_properties (
+        resource_id INTEGER NOT NULL REFERENCES resources(id) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (resource_id, name)
+    );
+    CREATE TABLE IF NOT EXISTS links (
+        id INTEGER PRIMARY KEY,
+        resource_id INTEGER NOT NULL REFERENCES resources(id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        rel TEXT NOT NULL,
+        type TEXT,
+        href TEXT,
+        visibility TEXT
+    );
+    CREATE INDEX IF NOT EXISTS links_resource ON links(resource_id, position);
+    CREATE TABLE IF NOT EXISTS link_titles (
+        link_id INTEGER NOT NULL REFERENCES links(id) ON DELETE CASCADE,
+        language TEXT NOT NULL,
+        title TEXT NOT NULL,
+        PRIMARY KEY (link_id, language)
+    );
+    CREATE TABLE IF NOT EXISTS link_properties (
+        link_id INTEGER NOT NULL REFERENCES links(id) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (link_id, name)
+    );
+";
+
+#[derive(Clone)]
+pub struct SqliteStore {
+    path: PathBuf,
+
+    // Idle connections, reused across lookups so that concurrent lookups each
+    // have their own connection.
+    connections: Arc<Mutex<Vec<Connection>>>,
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> StoreError {
+        StoreError(format!("SQLite error: {e}"))
+    }
+}
+
+impl SqliteStore {
+    // Open the database at the given path, creating it and its tables if necessary.
+    pub fn open(path: &Path) -> Result<SqliteStore, StoreError> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(SCHEMA)?;
+        migrate(&conn)?;
+        configure(&conn)?;
+        Ok(SqliteStore {
+            path: path.to_path_buf(),
+            connections: Arc::new(Mutex::new(vec![conn])),
+        })
+    }
+
+    // Insert the entries of a JRD map, replacing any existing entries for the same
+    // resources. All the entries are written in a single transaction.
+    pub fn import(&self, jm: &JrdMap) -> Result<(), StoreError> {
+        self.with_connection(|conn| {
+            let tx = conn.transaction()?;
+            for (uri, jrd) in jm {
+                insert(&tx, uri, jrd)?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    // Read every entry into a JRD map.
+    pub fn export(&self) -> Result<JrdMap, StoreError> {
+        self.with_connection(|conn| {
+            let tx = conn.transaction()?;
+            let ids: Vec<(i64, String)> = tx
+                .prepare("SELECT id, uri FROM resources")?
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<_, _>>()?;
+            let mut jm = JrdMap::new();
+            for (id, uri) in ids {
+                jm.insert(uri, read_jrd(&tx, id)?);
+            }
+            Ok(jm)
+        })
+    }
+
+    fn with_connection<T, F>(&self, f: F) -> Result<T, StoreError>
+    where
+        F: FnOnce(&mut Connection) -> Result<T, StoreError>,
+    {
+        let pooled = self.connections.lock().unwrap().pop();
+        let mut conn = match pooled {
+            Some(conn) => conn,
+            None => {
+                let conn = Connection::open(&self.path)?;
+                configure(&conn)?;
+                conn
+            }
+        };
+        let result = f(&mut conn);
+        self.connections.lock().unwrap().push(conn);
+        result
+    }
+
+    // Run a query on a blocking thread so as not to hold up the async runtime.
+    async fn query<T, F>(&self, f: F) -> Result<T, StoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, StoreError> + Send + 'static,
+    {
+        let store = self.clone();
+        tokio::task::spawn_blocking(move || store.with_connection(f))
+            .await
+            .map_err(|e| StoreError(format!("SQLite query failed: {e}")))?
+    }
+}
+
+fn configure(conn: &Connection) -> Result<(), StoreError> {
+    conn.pragma_update(None, "foreign_keys", "ON")?;
+    // Wait for a writer to finish rather than failing immediately.
+    conn.busy_timeout(Duration::from_secs(5))?;
+    Ok(())
+}
+
+// Bring the tables of a database created by an earlier version up to date.
+fn migrate(conn: &Connection) -> Result<(), StoreError> {
+    for table in ["resources", "links"] {
+        let columns: Vec<String> = conn
+            .prepare(&format!("PRAGMA table_info({table})"))?
+            .query_map([], |row| row.get(1))?
+            .collect::<Result<_, _>>()?;
+        if !columns.iter().any(|c| c == "visibility") {
+            conn.execute_batch(&format!("ALTER TABLE {table} ADD COLUMN visibility TEXT"))?;
+        }
+    }
+    Ok(())
+}
+
+// Visibility is stored as JSON, with NULL meaning public.
+fn visibility_to_sql(visibility: &Visibility) -> Option<String> {
+    (!visibility.is_public()).then(|| serde_json::to_string(visibility).unwrap())
+}
+
+fn visibility_from_sql(value: Option<String>) -> Result<Visibility, StoreError> {
+    match value {
+        None => Ok(Visibility::Public),
+        Some(json) => serde_json::from_str(&json)
+            .map_err(|e| StoreError(format!("Invalid visibility {json:?}: {e}"))),
+    }
+}
+
+fn insert(tx: &Transaction, uri: &str, jrd: &Jrd) -> Result<(), StoreError> {
+    tx.execute("DELETE FROM resources WHERE uri = ?1", params![uri])?;
+    tx.execute(
+        "INSERT INTO resources (uri, subject, visibility) VALUES (?1, ?2, ?3)",
+        params![uri, jrd.subject, visibility_to_sql(&jrd.visibility)],
+    )?;
+    let resource_id = tx.last_insert_rowid();
+
+    for (position, alias) in jrd.aliases.iter().flatten().enumerate() {
+        tx.execute(
+            "INSERT INTO aliases (resource_id, position, alias) VALUES (?1, ?2, ?3)",
+            params![resource_id, position, alias],
+        )?;
+    }
+    for (name, value) in jrd.properties.iter().flatten() {
+        tx.execute(
+            "INSERT INTO resource_properties (resource_id, name, value) VALUES (?1, ?2, ?3)",
+            params![resource_id, name, value],
+        )?;
+    }
+    for (position, link) in jrd.links.iter().flatten().enumerate() {
+        tx.execute(
+            "INSERT INTO links (resource_id, position, rel, type, href, visibility) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                resource_id,
+                position,
+                link.rel.as_str(),
+                link.type_,
+                link.href,
+                visibility_to_sql(&link.visibility)
+            ],
+        )?;
+        let link_id = tx.last_insert_rowid();
+        for (language, title) in link.titles.iter().flatten() {
+            tx.execute(
+                "INSERT INTO link_titles (link_id, language, title) VALUES (?1, ?2, ?3)",
+                params![link_id, language, title],
+            )?;
+        }
+        for (name, value) in link.properties.iter().flatten() {
+            tx.execute(
+                "INSERT INTO link_properties (link_id, name, value) VALUES (?1, ?2, ?3)",
+                params![link_id, name, value],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// Find the resource with the given URI or, failing that, with the given alias.
+fn find(conn: &Connection, resource: &str) -> Result<Option<i64>, StoreError> {
+    let id = conn
+        .query_row(
+            "SELECT id FROM resources WHERE uri = ?1",
+            params![resource],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if id.is_some() {
+        return Ok(id);
+    }
+    Ok(conn
+        .query_row(
+            "SELECT resource_id FROM aliases WHERE alias = ?1 ORDER BY resource_id LIMIT 1",
+            params![resource],
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+// Read a JRD. Empty aliases, properties, links, and titles are omitted.
+fn read_jrd(conn: &Connection, id: i64) -> Result<Jrd, StoreError> {
+    let (subject, visibility): (String, Option<String>) = conn.query_row(
+        "SELECT subject, visibility FROM resources WHERE id = ?1",
+        params![id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+
+    let aliases: Vec<String> = conn
+        .prepare_cached("SELECT alias FROM aliases WHERE resource_id = ?1 ORDER BY position")?
+        .query_map(params![id], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+
+    let properties = read_pairs(
+        conn,
+        "SELECT name, value FROM resource_properties WHERE resource_id = ?1",
+        id,
+    )?;
+
+    type LinkRow = (i64, String, Option<String>, Option<String>, Option<String>);
+    let link_rows: Vec<LinkRow> = conn
+        .prepare_cached(
+            "SELECT id, rel, type, href, visibility FROM links WHERE resource_id = ?1 ORDER BY position",
+        )?
+        .query_map(params![id], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+        })?
+        .collect::<Result<_, _>>()?;
+
+    let mut links = Vec::new();
+    for (link_id, rel, type_, href, visibility) in link_rows {
+        links.push(ResourceLink {
+            rel: make_rel(rel),
+            type_,
+            href,
+            titles: read_pairs(
+                conn,
+                "SELECT language, title FROM link_titles WHERE link_id = ?1",
+                link_id,
+            )?,
+            properties: read_pairs(
+                conn,
+                "SELECT name, value FROM link_properties WHERE link_id = ?1",
+                link_id,
+            )?,
+            visibility: visibility_from_sql(visibility)?,
+        });
+    }
+
+    Ok(Jrd {
+        subject,
+        aliases: (!aliases.is_empty()).then_some(aliases),
+        properties,
+        links: (!links.is_empty()).then_some(links),
+        visibility: visibility_from_sql(visibility)?,
+    })
+}
+
+fn read_pairs(
+    conn: &Connection,
+    sql: &str,
+    id: i64,
+) -> Result<Option<BTreeMap<String, String>>, StoreError> {
+    let pairs: BTreeMap<String, String> = conn
+        .prepare_cached(sql)?
+        .query_map(params![id], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+    Ok((!pairs.is_empty()).then_some(pairs))
+}
+
+#[async_trait]
+impl JrdStore for SqliteStore {
+    async fn lookup(&self, resource: &str) -> Result<Option<Jrd>, StoreError> {
+        let resource = resource.to_string();
+        self.query(move |conn| {
+            let tx = conn.transaction()?;
+            match find(&tx, &resource)? {
+                Some(id) => Ok(Some(read_jrd(&tx, id)?)),
+                None => Ok(None),
+            }
+        })
+        .await
+    }
+
+    async fn list(&self) -> Result<Option<Vec<String>>, StoreError> {
+        self.query(|conn| {
+            let resources: Vec<String> = conn
+                .prepare_cached("SELECT uri FROM resources ORDER BY uri")?
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            Ok(Some(resources))
+        })
+        .await
+    }
+
+    async fn count(&self) -> Result<Option<usize>, StoreError> {
+        self.query(|conn| {
+            let count: i64 = conn
+                .prepare_cached("SELECT COUNT(*) FROM resources")?
+                .query_row([], |row| row.get(0))?;
+            Ok(Some(count as usize))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jrdmap;
+    use pretty_assertions::assert_eq;
+    use serde_json::{json, Value};
+
+    const MAP: &str = r#"
+        {
+            "acct:alice@example.com":{
+                "subject": "acct:alice@example.com",
+                "aliases": ["https://example.com/alice", "acct:someone@example.com"],
+                "properties": {"http://example.com/ns/role": "admin"},
+                "links": [
+                    {
+                        "rel": "http://webfinger.net/rel/avatar",
+                        "type": "image/jpeg",
+                        "href": "https://example.com/data/alice-avatar.jpeg",
+                        "titles": {"en": "Avatar", "und": "Avatar"},
+                        "properties": {"http://example.com/ns/size": "large"}
+                    },
+                    {
+                        "rel": "self",
+                        "type": "application/activity+json",
+                        "href": "https://example.com/users/alice"
+                    }
+                ]
+            },
+            "acct:bob@example.com":{
+                "subject": "acct:bob@example.com"
+            }
+        }"#;
+
+    fn open_store(dir: &tempfile::TempDir) -> SqliteStore {
+        SqliteStore::open(&dir.path().join("jrd.sqlite")).unwrap()
+    }
+
+    fn to_value(jm: &JrdMap) -> Value {
+        serde_json::to_value(jm).unwrap()
+    }
+
+    #[test]
+    fn test_import_export_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open_store(&dir);
+        let jm = jrdmap::from_json(&MAP.to_string());
+
+        store.import(&jm).unwrap();
+
+        assert_eq!(to_value(&store.export().unwrap()), to_value(&jm));
+    }
+
+    #[test]
+    fn test_import_replaces_existing_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open_store(&dir);
+        store.import(&jrdmap::from_json(&MAP.to_string())).unwrap();
+
+        store
+            .import(&jrdmap::from_json(
+                &r#"{"acct:bob@example.com":{"subject":"acct:robert@example.com"}}"#.to_string(),
+            ))
+            .unwrap();
+
+        let exported = to_value(&store.export().unwrap());
+        assert_eq!(exported["acct:bob@example.com"], json!({"subject": "acct:robert@example.com"}));
+        assert_eq!(exported["acct:alice@example.com"]["subject"], "acct:alice@example.com");
+    }
+
+    #[test]
+    fn test_import_export_round_trip_with_visibility() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open_store(&dir);
+        let jm = jrdmap::from_json(
+            &r#"{
+                "acct:carol@example.com": {
+                    "subject": "acct:carol@example.com",
+                    "links": [
+                        {"rel": "self", "href": "https://example.com/carol"},
+                        {"rel": "me", "href": "https://example.com/c", "visibility": ["partner"]}
+                    ],
+                    "visibility": "private"
+                }
+            }"#
+            .to_string(),
+        );
+
+        store.import(&jm).unwrap();
+
+        assert_eq!(to_value(&store.export().unwrap()), to_value(&jm));
+    }
+
+    #[test]
+    fn test_open_adds_visibility_to_existing_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("jrd.sqlite");
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE resources (id INTEGER PRIMARY KEY, uri TEXT NOT NULL UNIQUE, subject TEXT NOT NULL);
+                 CREATE TABLE links (id INTEGER PRIMARY KEY, resource_id INTEGER NOT NULL, position INTEGER NOT NULL,
+                     rel TEXT NOT NULL, type TEXT, href TEXT);
+                 INSERT INTO resources (uri, subject) VALUES ('acct:bob@example.com', 'acct:bob@example.com');",
+            )
+            .unwrap();
+
+        let store = SqliteStore::open(&path).unwrap();
+
+        assert_eq!(
+            to_value(&store.export().unwrap()),
+            json!({"acct:bob@example.com": {"subject": "acct:bob@example.com"}})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lookup_by_resource() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open_store(&dir);
+        let jm = jrdmap::from_json(&MAP.to_string());
+        store.import(&jm).unwrap();
+
+        let jrd = store.lookup("acct:alice@example.com").await.unwrap().unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&jrd).unwrap(),
+            serde_json::to_value(&jm["acct:alice@example.com"]).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lookup_by_alias() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open_store(&dir);
+        store.import(&jrdmap::from_json(&MAP.to_string())).unwrap();
+
+        let jrd = store.lookup("acct:someone@example.com").await.unwrap().unwrap();
+
+        assert_eq!(jrd.subject, "acct:alice@example.com");
+    }
+
+    #[tokio::test]
+    async fn test_lookup_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open_store(&dir);
+        store.import(&jrdmap::from_json(&MAP.to_string())).unwrap();
+
+        assert!(store.lookup("acct:carol@example.com").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open_store(&dir);
+        store.import(&jrdmap::from_json(&MAP.to_string())).unwrap();
+
+        assert_eq!(
+            store.list().await.unwrap(),
+            Some(vec!["acct:alice@example.com".to_string(), "acct:bob@example.com".to_string()])
+        );
+        assert_eq!(store.count().await.unwrap(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_reads_see_writes_from_another_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let reader = open_store(&dir);
+        assert!(reader.lookup("acct:bob@example.com").await.unwrap().is_none());
+
+        // Simulate a separate admin process writing to the same database.
+        let writer = open_store(&dir);
+        writer.import(&jrdmap::from_json(&MAP.to_string())).unwrap();
+
+        assert!(reader.lookup("acct:bob@example.com").await.unwrap().is_some());
+    }
+}
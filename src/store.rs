@@ -0,0 +1,383 @@
+/*
+Copyright 2024 Glyn Normington
+
+This file is part of webfinger-rs.
+
+webfinger-rs is free software: you can redistribute it and/or modify it under the terms
+of the GNU General Public License as published by the Free Software Foundation, either
+version 3 of the License, or (at your option) any later version.
+
+webfinger-rs is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with webfinger-rs.
+If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+
+use crate::jrdmap::{self, Jrd, JrdMap};
+
+#[derive(Debug)]
+pub struct StoreError(pub String);
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+/* A JrdStore is a source of JSON Resource Descriptors, keyed by resource URI. */
+#[async_trait]
+pub trait JrdStore: Send + Sync {
+    // Look up the JRD associated with a resource URI.
+    async fn lookup(&self, resource: &str) -> Result<Option<Jrd>, StoreError>;
+
+    // List the resource URIs in the store, or None if the store does not support
+    // listing.
+    async fn list(&self) -> Result<Option<Vec<String>>, StoreError> {
+        Ok(None)
+    }
+
+    // Count the resources in the store, or None if the store does not support
+    // listing. By default, the resources are listed and counted.
+    async fn count(&self) -> Result<Option<usize>, StoreError> {
+        Ok(self.list().await?.map(|resources| resources.len()))
+    }
+
+    // Digest identifying the current content of the store, or None if the store
+    // cannot compute one. This is called by the readiness probe, so should be cheap.
+    async fn digest(&self) -> Result<Option<String>, StoreError> {
+        Ok(None)
+    }
+
+    // Subscribe to notifications of changes to the store, or None if the store
+    // does not support notifications. A notification is sent after each change.
+    fn subscribe(&self) -> Option<watch::Receiver<()>> {
+        None
+    }
+
+    // Check that the store can be queried, for the readiness probe. By default, a
+    // resource which is not expected to exist is looked up.
+    async fn check(&self) -> Result<(), StoreError> {
+        self.lookup(PROBE_RESOURCE).await.map(|_| ())
+    }
+}
+
+// The resource looked up to check that a store can be queried.
+const PROBE_RESOURCE: &str = "acct:readiness-probe@invalid";
+
+/* A JsonMapStore holds a JrdMap, such as one loaded from a JSON file, in memory. */
+pub struct JsonMapStore {
+    webfinger_jrdmap: RwLock<JrdMap>,
+
+    // The digest of the map, computed whenever the map is replaced.
+    digest: RwLock<String>,
+
+    changes: watch::Sender<()>,
+}
+
+impl JsonMapStore {
+    pub fn new(jm: JrdMap) -> JsonMapStore {
+        JsonMapStore {
+            digest: RwLock::new(jrdmap::digest(&jm)),
+            webfinger_jrdmap: RwLock::new(jm),
+            changes: watch::Sender::new(()),
+        }
+    }
+
+    // Take a copy of the current map.
+    pub fn snapshot(&self) -> JrdMap {
+        self.webfinger_jrdmap.read().unwrap().clone()
+    }
+
+    // Replace the map and notify subscribers.
+    pub fn replace(&self, jm: JrdMap) {
+        let digest = jrdmap::digest(&jm);
+        let mut map = self.webfinger_jrdmap.write().unwrap();
+        *map = jm;
+        *self.digest.write().unwrap() = digest;
+        drop(map);
+        self.changes.send_replace(());
+    }
+}
+
+#[async_trait]
+impl JrdStore for JsonMapStore {
+    async fn lookup(&self, resource: &str) -> Result<Option<Jrd>, StoreError> {
+        Ok(self.webfinger_jrdmap.read().unwrap().get(resource).cloned())
+    }
+
+    async fn list(&self) -> Result<Option<Vec<String>>, StoreError> {
+        let mut resources: Vec<String> = self
+            .webfinger_jrdmap
+            .read()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        resources.sort();
+        Ok(Some(resources))
+    }
+
+    async fn count(&self) -> Result<Option<usize>, StoreError> {
+        Ok(Some(self.webfinger_jrdmap.read().unwrap().len()))
+    }
+
+    async fn digest(&self) -> Result<Option<String>, StoreError> {
+        Ok(Some(self.digest.read().unwrap().clone()))
+    }
+
+    fn subscribe(&self) -> Option<watch::Receiver<()>> {
+        Some(self.changes.subscribe())
+    }
+}
+
+/* A CompositeStore consults several stores in priority order: a resource is
+looked up in each store in turn and the first JRD found is returned. */
+pub struct CompositeStore {
+    stores: Vec<Arc<dyn JrdStore>>,
+
+    // Notified of changes to any of the stores, or None if none of them support
+    // notifications.
+    changes: Option<Arc<watch::Sender<()>>>,
+
+    // The number of resources, if they have been counted.
+    count: Mutex<Option<Count>>,
+}
+
+struct Count {
+    resources: Option<usize>,
+
+    // Shows whether any of the stores has changed since the resources were counted.
+    changes: Option<watch::Receiver<()>>,
+}
+
+impl CompositeStore {
+    // Create a store consulting the given stores in priority order. If any of them
+    // support notifications, this must be called within a Tokio runtime, since a
+    // task is spawned to forward the notifications of each such store.
+    pub fn new(stores: Vec<Arc<dyn JrdStore>>) -> CompositeStore {
+        let receivers: Vec<watch::Receiver<()>> = stores.iter().filter_map(|s| s.subscribe()).collect();
+        let changes = (!receivers.is_empty()).then(|| {
+            let sender = Arc::new(watch::Sender::new(()));
+            // Each task ends when its store, and so the store's sender, is dropped.
+            for mut store_receiver in receivers {
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    while store_receiver.changed().await.is_ok() {
+                        sender.send_replace(());
+                    }
+                });
+            }
+            sender
+        });
+        CompositeStore {
+            stores,
+            changes,
+            count: Mutex::new(None),
+        }
+    }
+}
+
+#[async_trait]
+impl JrdStore for CompositeStore {
+    async fn lookup(&self, resource: &str) -> Result<Option<Jrd>, StoreError> {
+        for store in &self.stores {
+            if let Some(jrd) = store.lookup(resource).await? {
+                return Ok(Some(jrd));
+            }
+        }
+        Ok(None)
+    }
+
+    // List the union of the resources of the stores which support listing.
+    async fn list(&self) -> Result<Option<Vec<String>>, StoreError> {
+        let mut resources: Option<BTreeSet<String>> = None;
+        for store in &self.stores {
+            if let Some(store_resources) = store.list().await? {
+                resources.get_or_insert_with(BTreeSet::new).extend(store_resources);
+            }
+        }
+        Ok(resources.map(|r| r.into_iter().collect()))
+    }
+
+    // Count the union of the resources of the stores which support listing. Since
+    // this requires every store to be listed, the count is kept until one of the
+    // stores notifies a change. Stores which do not support notifications, such
+    // as SqliteStore, are therefore counted once.
+    async fn count(&self) -> Result<Option<usize>, StoreError> {
+        if let Some(count) = &*self.count.lock().unwrap() {
+            if !count.changes.as_ref().is_some_and(|changes| changes.has_changed().unwrap_or(true)) {
+                return Ok(count.resources);
+            }
+        }
+        // Subscribe before listing, so that a change made while listing is noticed.
+        let changes = self.subscribe();
+        let resources = self.list().await?.map(|resources| resources.len());
+        *self.count.lock().unwrap() = Some(Count { resources, changes });
+        Ok(resources)
+    }
+
+    // Combine the digests of the stores, provided every store has a digest.
+    async fn digest(&self) -> Result<Option<String>, StoreError> {
+        let mut hasher = Sha256::new();
+        for store in &self.stores {
+            match store.digest().await? {
+                Some(digest) => hasher.update(digest.as_bytes()),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(format!("sha256:{:x}", hasher.finalize())))
+    }
+
+    // Notify subscribers of changes to any of the stores.
+    fn subscribe(&self) -> Option<watch::Receiver<()>> {
+        self.changes.as_ref().map(|sender| sender.subscribe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json_store(s: &str) -> Arc<JsonMapStore> {
+        Arc::new(JsonMapStore::new(jrdmap::from_json(&s.to_string())))
+    }
+
+    #[tokio::test]
+    async fn test_json_map_store_lookup() {
+        let store = json_store(r#"{"acct:a@example.com":{"subject":"acct:a@example.com"}}"#);
+
+        let jrd = store.lookup("acct:a@example.com").await.unwrap().unwrap();
+        assert_eq!(jrd.subject, "acct:a@example.com");
+        assert!(store.lookup("acct:b@example.com").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_json_map_store_list() {
+        let store = json_store(
+            r#"{"acct:b@example.com":{"subject":"acct:b@example.com"},"acct:a@example.com":{"subject":"acct:a@example.com"}}"#,
+        );
+
+        assert_eq!(
+            store.list().await.unwrap(),
+            Some(vec!["acct:a@example.com".to_string(), "acct:b@example.com".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_json_map_store_notifies_on_replace() {
+        let store = json_store("{}");
+        let changes = store.subscribe().unwrap();
+
+        store.replace(jrdmap::from_json(&r#"{"acct:a@example.com":{"subject":"acct:a@example.com"}}"#.to_string()));
+
+        assert!(changes.has_changed().unwrap());
+        assert!(store.lookup("acct:a@example.com").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_json_map_store_digest() {
+        let store = json_store("{}");
+        let jm = jrdmap::from_json(&r#"{"acct:a@example.com":{"subject":"acct:a@example.com"}}"#.to_string());
+        let digest = jrdmap::digest(&jm);
+
+        store.replace(jm);
+
+        assert_eq!(store.digest().await.unwrap(), Some(digest));
+        assert_eq!(store.count().await.unwrap(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_composite_store_priority() {
+        let first = json_store(r#"{"acct:a@example.com":{"subject":"acct:first@example.com"}}"#);
+        let second = json_store(
+            r#"{"acct:a@example.com":{"subject":"acct:second@example.com"},"acct:b@example.com":{"subject":"acct:b@example.com"}}"#,
+        );
+        let store = CompositeStore::new(vec![first, second]);
+
+        let jrd = store.lookup("acct:a@example.com").await.unwrap().unwrap();
+        assert_eq!(jrd.subject, "acct:first@example.com");
+        let jrd = store.lookup("acct:b@example.com").await.unwrap().unwrap();
+        assert_eq!(jrd.subject, "acct:b@example.com");
+        assert!(store.lookup("acct:c@example.com").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_composite_store_list() {
+        let first = json_store(r#"{"acct:a@example.com":{"subject":"acct:a@example.com"}}"#);
+        let second = json_store(
+            r#"{"acct:a@example.com":{"subject":"acct:a@example.com"},"acct:b@example.com":{"subject":"acct:b@example.com"}}"#,
+        );
+        let store = CompositeStore::new(vec![first, second]);
+
+        assert_eq!(
+            store.list().await.unwrap(),
+            Some(vec!["acct:a@example.com".to_string(), "acct:b@example.com".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_composite_store_count() {
+        let first = json_store(r#"{"acct:a@example.com":{"subject":"acct:a@example.com"}}"#);
+        let second = json_store(r#"{"acct:a@example.com":{"subject":"acct:a@example.com"}}"#);
+        let store = CompositeStore::new(vec![first, second.clone()]);
+        assert_eq!(store.count().await.unwrap(), Some(1));
+
+        second.replace(jrdmap::from_json(
+            &r#"{"acct:b@example.com":{"subject":"acct:b@example.com"}}"#.to_string(),
+        ));
+        // The forwarding task may not have run yet, so wait until the count changes.
+        for _ in 0..50 {
+            if store.count().await.unwrap() == Some(2) {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("count was not updated");
+    }
+
+    #[tokio::test]
+    async fn test_composite_store_notifies_on_change() {
+        let first = json_store("{}");
+        let second = json_store("{}");
+        let store = CompositeStore::new(vec![first, second.clone()]);
+        let mut changes = store.subscribe().unwrap();
+
+        second.replace(JrdMap::new());
+
+        tokio::time::timeout(std::time::Duration::from_secs(5), changes.changed())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_composite_store_shares_notifications() {
+        let first = json_store("{}");
+        let store = CompositeStore::new(vec![first.clone()]);
+        drop(store.subscribe().unwrap());
+        let mut changes = store.subscribe().unwrap();
+
+        // Dropping one subscription does not affect the others, and each
+        // subscription starts from the current state.
+        assert!(!changes.has_changed().unwrap());
+        first.replace(JrdMap::new());
+
+        tokio::time::timeout(std::time::Duration::from_secs(5), changes.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(store.subscribe().is_some_and(|changes| !changes.has_changed().unwrap()));
+    }
+}